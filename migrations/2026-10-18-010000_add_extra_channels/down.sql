@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE rem_data
+DROP COLUMN co2,
+DROP COLUMN nox_index,
+DROP COLUMN illuminance,
+DROP COLUMN sound_level;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE rem_data
+ADD COLUMN co2 REAL,
+ADD COLUMN nox_index REAL,
+ADD COLUMN illuminance REAL,
+ADD COLUMN sound_level REAL;
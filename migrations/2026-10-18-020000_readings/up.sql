@@ -0,0 +1,17 @@
+-- Your SQL goes here
+DROP TABLE IF EXISTS readings;
+CREATE TABLE readings (
+    id BIGSERIAL PRIMARY KEY,
+    data_id VARCHAR(36) NOT NULL,
+    device_id VARCHAR NOT NULL,
+
+    metric VARCHAR NOT NULL,
+    value DOUBLE PRECISION NOT NULL,
+    unit VARCHAR,
+
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+
+    UNIQUE (data_id, metric)
+);
+
+CREATE INDEX readings_metric_device_id_created_at_idx ON readings (metric, device_id, created_at);
@@ -0,0 +1,20 @@
+-- Your SQL goes here
+DROP TABLE IF EXISTS calibrations;
+CREATE TABLE calibrations (
+    device_id VARCHAR NOT NULL,
+    field VARCHAR NOT NULL,
+
+    "offset" REAL NOT NULL DEFAULT 0,
+    scale REAL NOT NULL DEFAULT 1,
+
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW(),
+
+    PRIMARY KEY (device_id, field)
+);
+
+SELECT diesel_manage_updated_at('calibrations');
+
+-- Uncalibrated channel values as reported by the device, keyed by payload field name. Rows written
+-- before calibrations existed have no raw values, their columns are the raw values.
+ALTER TABLE rem_data
+ADD COLUMN raw JSONB;
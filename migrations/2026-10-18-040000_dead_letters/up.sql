@@ -0,0 +1,10 @@
+-- Your SQL goes here
+DROP TABLE IF EXISTS dead_letters;
+CREATE TABLE dead_letters (
+    id BIGSERIAL PRIMARY KEY,
+    topic VARCHAR NOT NULL,
+    payload BYTEA NOT NULL,
+    error VARCHAR NOT NULL,
+
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
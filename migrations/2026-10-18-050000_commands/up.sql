@@ -0,0 +1,22 @@
+-- Your SQL goes here
+DROP TABLE IF EXISTS commands;
+CREATE TABLE commands (
+    id VARCHAR(36) PRIMARY KEY,
+    device_id VARCHAR NOT NULL,
+
+    command VARCHAR NOT NULL,
+    params JSONB,
+
+    status VARCHAR NOT NULL DEFAULT 'pending'
+        CHECK (status IN ('pending', 'sent', 'accepted', 'completed', 'failed', 'rejected')),
+
+    -- Last acknowledgement of the device, or the reason publishing failed
+    response JSONB,
+
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX commands_device_id_created_at_idx ON commands (device_id, created_at);
+
+SELECT diesel_manage_updated_at('commands');
@@ -0,0 +1,17 @@
+-- Your SQL goes here
+DROP TABLE IF EXISTS device_configs;
+CREATE TABLE device_configs (
+    device_id VARCHAR PRIMARY KEY,
+
+    -- Configuration set through the API, published to the device as a retained message
+    desired JSONB,
+    desired_at TIMESTAMP,
+
+    -- Configuration the device last reported in its status
+    reported JSONB,
+    reported_at TIMESTAMP,
+
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+SELECT diesel_manage_updated_at('device_configs');
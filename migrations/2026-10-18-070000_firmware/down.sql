@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE rem_status
+DROP COLUMN firmware_version;
+
+DROP TABLE firmware_updates;
+DROP TABLE devices;
+DROP TABLE firmware_images;
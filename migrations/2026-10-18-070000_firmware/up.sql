@@ -0,0 +1,45 @@
+-- Your SQL goes here
+DROP TABLE IF EXISTS firmware_updates;
+DROP TABLE IF EXISTS firmware_images;
+DROP TABLE IF EXISTS devices;
+
+-- Firmware images uploaded through the API, the image itself is stored on disk
+CREATE TABLE firmware_images (
+    version VARCHAR PRIMARY KEY,
+    size BIGINT NOT NULL,
+    sha256 VARCHAR(64) NOT NULL,
+
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+-- Devices known to the listener, with the group used to roll out firmware and the firmware
+-- version the device reported last
+CREATE TABLE devices (
+    device_id VARCHAR PRIMARY KEY,
+    group_name VARCHAR,
+    firmware_version VARCHAR,
+
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX devices_group_name_idx ON devices (group_name);
+
+SELECT diesel_manage_updated_at('devices');
+
+-- The firmware update assigned to a device, a new assignment replaces the previous one
+CREATE TABLE firmware_updates (
+    device_id VARCHAR PRIMARY KEY,
+    target_version VARCHAR NOT NULL REFERENCES firmware_images (version) ON DELETE CASCADE,
+
+    status VARCHAR NOT NULL DEFAULT 'assigned',
+    progress SMALLINT,
+    message VARCHAR,
+
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+SELECT diesel_manage_updated_at('firmware_updates');
+
+ALTER TABLE rem_status
+ADD COLUMN firmware_version VARCHAR;
@@ -0,0 +1,16 @@
+-- Your SQL goes here
+DROP TABLE IF EXISTS api_keys;
+
+-- API keys used to authenticate with the API. Only the SHA-256 hash of a key is stored, the key
+-- itself is returned once when it is created
+CREATE TABLE api_keys (
+    id VARCHAR PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    -- Start of the key, so it can be recognized without storing it
+    prefix VARCHAR NOT NULL,
+    key_hash VARCHAR(64) NOT NULL UNIQUE,
+    scopes VARCHAR[] NOT NULL,
+
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    revoked_at TIMESTAMP
+);
@@ -0,0 +1,29 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE api_keys
+DROP COLUMN tenant_id;
+
+ALTER TABLE firmware_updates
+DROP COLUMN tenant_id;
+
+ALTER TABLE device_configs
+DROP COLUMN tenant_id;
+
+ALTER TABLE commands
+DROP COLUMN tenant_id;
+
+ALTER TABLE calibrations
+DROP COLUMN tenant_id;
+
+ALTER TABLE readings
+DROP COLUMN tenant_id;
+
+ALTER TABLE rem_status
+DROP COLUMN tenant_id;
+
+ALTER TABLE rem_data
+DROP COLUMN tenant_id;
+
+ALTER TABLE devices
+DROP COLUMN tenant_id;
+
+DROP TABLE tenants;
@@ -0,0 +1,57 @@
+-- Your SQL goes here
+DROP TABLE IF EXISTS tenants;
+
+-- Customers sharing the listener. Everything that existed before tenants belongs to the default
+-- tenant, which is also the tenant of the operator.
+CREATE TABLE tenants (
+    id VARCHAR PRIMARY KEY,
+    name VARCHAR NOT NULL,
+
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+INSERT INTO tenants (id, name) VALUES ('default', 'Default');
+
+-- The device registry decides which tenant the messages of a device belong to
+ALTER TABLE devices
+ADD COLUMN tenant_id VARCHAR NOT NULL DEFAULT 'default' REFERENCES tenants (id);
+
+CREATE INDEX devices_tenant_id_idx ON devices (tenant_id);
+
+-- Every device without an entry in the registry so far belongs to the default tenant
+INSERT INTO devices (device_id)
+SELECT device_id FROM rem_data
+UNION SELECT device_id FROM rem_status
+UNION SELECT device_id FROM calibrations
+UNION SELECT device_id FROM commands
+UNION SELECT device_id FROM device_configs
+UNION SELECT device_id FROM firmware_updates
+ON CONFLICT DO NOTHING;
+
+ALTER TABLE rem_data
+ADD COLUMN tenant_id VARCHAR NOT NULL DEFAULT 'default' REFERENCES tenants (id);
+
+CREATE INDEX rem_data_tenant_id_device_id_idx ON rem_data (tenant_id, device_id);
+
+ALTER TABLE rem_status
+ADD COLUMN tenant_id VARCHAR NOT NULL DEFAULT 'default' REFERENCES tenants (id);
+
+CREATE INDEX rem_status_tenant_id_device_id_idx ON rem_status (tenant_id, device_id);
+
+ALTER TABLE readings
+ADD COLUMN tenant_id VARCHAR NOT NULL DEFAULT 'default' REFERENCES tenants (id);
+
+ALTER TABLE calibrations
+ADD COLUMN tenant_id VARCHAR NOT NULL DEFAULT 'default' REFERENCES tenants (id);
+
+ALTER TABLE commands
+ADD COLUMN tenant_id VARCHAR NOT NULL DEFAULT 'default' REFERENCES tenants (id);
+
+ALTER TABLE device_configs
+ADD COLUMN tenant_id VARCHAR NOT NULL DEFAULT 'default' REFERENCES tenants (id);
+
+ALTER TABLE firmware_updates
+ADD COLUMN tenant_id VARCHAR NOT NULL DEFAULT 'default' REFERENCES tenants (id);
+
+ALTER TABLE api_keys
+ADD COLUMN tenant_id VARCHAR NOT NULL DEFAULT 'default' REFERENCES tenants (id);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE rejected_messages;
+DROP TABLE device_keys;
@@ -0,0 +1,24 @@
+-- Your SQL goes here
+DROP TABLE IF EXISTS device_keys;
+CREATE TABLE device_keys (
+    device_id VARCHAR PRIMARY KEY REFERENCES devices (device_id) ON DELETE CASCADE,
+    algorithm VARCHAR NOT NULL,
+    key BYTEA NOT NULL,
+
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+DROP TABLE IF EXISTS rejected_messages;
+CREATE TABLE rejected_messages (
+    id BIGSERIAL PRIMARY KEY,
+    tenant_id VARCHAR NOT NULL DEFAULT 'default' REFERENCES tenants (id),
+    device_id VARCHAR NOT NULL,
+    topic VARCHAR NOT NULL,
+    reason VARCHAR NOT NULL,
+    error VARCHAR NOT NULL,
+    payload BYTEA NOT NULL,
+
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX rejected_messages_tenant_id_created_at_idx ON rejected_messages (tenant_id, created_at);
@@ -0,0 +1,12 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE readings
+DROP CONSTRAINT readings_tenant_id_data_id_metric_key,
+ADD CONSTRAINT readings_data_id_metric_key UNIQUE (data_id, metric);
+
+ALTER TABLE rem_status
+DROP CONSTRAINT rem_status_pkey,
+ADD PRIMARY KEY (id);
+
+ALTER TABLE rem_data
+DROP CONSTRAINT rem_data_pkey,
+ADD PRIMARY KEY (id);
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+-- Messages are identified by the devices, so the same ID may be used by devices of different
+-- tenants. Keying them by tenant keeps one tenant from shadowing the messages of another.
+ALTER TABLE rem_data
+DROP CONSTRAINT rem_data_pkey,
+ADD PRIMARY KEY (tenant_id, id);
+
+ALTER TABLE rem_status
+DROP CONSTRAINT rem_status_pkey,
+ADD PRIMARY KEY (tenant_id, id);
+
+ALTER TABLE readings
+DROP CONSTRAINT readings_data_id_metric_key,
+ADD CONSTRAINT readings_tenant_id_data_id_metric_key UNIQUE (tenant_id, data_id, metric);
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE device_keys DROP COLUMN last_sent_at;
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE device_keys ADD COLUMN last_sent_at TIMESTAMP;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX rem_status_tenant_id_created_at_idx;
+DROP INDEX rem_data_tenant_id_created_at_idx;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+CREATE INDEX rem_data_tenant_id_created_at_idx ON rem_data (tenant_id, created_at);
+CREATE INDEX rem_status_tenant_id_created_at_idx ON rem_status (tenant_id, created_at);
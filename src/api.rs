@@ -6,152 +6,2077 @@ use std::{
 };
 
 use crate::{
-    model::{RemData, RemStatus},
-    repo::RemRepo,
+    auth::{
+        generate_key, hash_key, key_from_headers, key_prefix, require_scope, Authenticator,
+        API_KEY_HEADER,
+    },
+    calibration::{RecalibrationJob, RecalibrationJobs},
+    connection::{ConnectionMonitor, ConnectionStatus},
+    error::{ApiError, ApiJson, ApiPath, ApiQuery, ErrorCode},
+    firmware::{checksum, download_url, is_valid_version, FirmwareStore},
+    health::{HealthChecker, HealthStatus, Liveness, Readiness},
+    ingest::{Ingestor, Outcome},
+    model::{
+        ApiKey, ApiScope, Calibration, Command, CommandStatus, Device, DeviceCommand, DeviceConfig,
+        DeviceConfigState, DeviceKey, FirmwareImage, FirmwareUpdate, FirmwareUpdateStatus, Reading,
+        RejectedMessage, RemData, RemDataAggregate, RemStatus, SignatureAlgorithm, Tenant,
+    },
+    mqtt::{command_message, config_message, ota_message, MQTTClientError, MqttPublisher},
+    prometheus,
+    ratelimit::{limit_by_ip, limit_by_key, RateLimitStats, RateLimits},
+    repo::{AggregateBucket, AggregateFilter, ReadingsFilter, RemRepo, RemRepoError},
     settings::Settings,
+    signing::validate_key,
+    telemetry::{record_device, trace_http},
+    topic::{is_valid_topic_level, REM_DATA_TOPIC, REM_STATUS_TOPIC},
 };
-use axum::{extract::State, http::StatusCode, response::IntoResponse, routing::get, Json, Router};
-use diesel::{sql_query, PgConnection, RunQueryDsl};
-use paho_mqtt::AsyncClient;
-use serde::Serialize;
+use axum::{
+    body::Bytes,
+    extract::{rejection::BytesRejection, DefaultBodyLimit, State},
+    http::{header, HeaderMap, StatusCode, Uri},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Extension, Json, Router,
+};
+use chrono::NaiveDateTime;
+use paho_mqtt::{Message, QOS_1};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use tokio::{sync::Mutex, time::sleep};
-use tracing::{error, info};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
 
-// use utoipa::ToSchema;
-// use utoipa_axum::router::OpenApiRouter;
-// use utoipa_swagger_ui::SwaggerUi;
+use utoipa::{
+    openapi::security::{
+        ApiKey as ApiKeyScheme, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme,
+    },
+    IntoParams, Modify, OpenApi, ToSchema,
+};
+use utoipa_swagger_ui::SwaggerUi;
 
 #[derive(Clone)]
 struct AppState {
-    mqtt_client: Arc<Mutex<AsyncClient>>,
-    db: Arc<Mutex<PgConnection>>,
+    config: Arc<Settings>,
+    firmware: Arc<FirmwareStore>,
+    publisher: MqttPublisher,
+    ingestor: Arc<Ingestor>,
+    connection: Arc<ConnectionMonitor>,
+    rate_limits: Arc<RateLimits>,
+    health: Arc<HealthChecker>,
+    recalibrations: Arc<RecalibrationJobs>,
     repo: Arc<Mutex<RemRepo>>,
+    auth: Authenticator,
 }
 
-async fn default_handler() -> impl IntoResponse {
-    (StatusCode::NOT_FOUND, "Not Found")
+impl AppState {
+    /// The tenant to prefix the topics of messages to the devices of `api_key` with, if any
+    fn topic_tenant<'a>(&self, api_key: &'a ApiKey) -> Option<&'a str> {
+        self.config
+            .mqtt_tenant_topics
+            .then_some(api_key.tenant_id.as_str())
+    }
 }
 
-#[derive(Debug, Serialize)]
-struct ApiError {
-    message: String,
+async fn default_handler(uri: Uri) -> ApiError {
+    ApiError::not_found(format!("No route for {}", uri.path()))
 }
 
-/// Healthcheck handler
-///
-/// This handler checks the health of the application by verifying that the MQTT client is connected
-/// to the broker and that the database can be queried.
-// #[utoipa::path(post, path = "/healthcheck", responses((status = OK, body = String)))]
-async fn healthcheck_handler(State(app_state): State<AppState>) -> (StatusCode, &'static str) {
-    let mqtt_client_lock = app_state.mqtt_client.lock().await;
-    let mut db_lock = app_state.db.lock().await;
-
-    let mut err_msg = "";
+/// Error of the APIs that only the keys of the operator of the listener can use
+fn operator_only() -> ApiError {
+    ApiError::new(
+        ErrorCode::Forbidden,
+        "This API is only available to admin keys of the default tenant",
+    )
+}
 
-    // Check that the MQTT client is connected
-    if !mqtt_client_lock.is_connected() {
-        err_msg = "MQTT client is not connected to the broker"
+/// Liveness
+///
+/// Returns 200 as long as the listener is running and able to serve requests. The dependencies
+/// aren't checked, so orchestrators don't restart the listener while the broker or the database
+/// is down.
+#[utoipa::path(
+    get,
+    path = "/v1/health/live",
+    tag = "health",
+    security(()),
+    responses(
+        (status = OK, body = Liveness),
+    )
+)]
+async fn live_handler(State(app_state): State<AppState>) -> Json<Liveness> {
+    Json(app_state.health.live())
+}
 
-    // Verify that we can query the database
-    } else if sql_query("SELECT 1").execute(&mut *db_lock).is_err() {
-        err_msg = "Database couldn't be connected too"
+/// Readiness
+///
+/// Checks every component the listener depends on: the connection to the MQTT broker, the latency
+/// of the database, the time since the last message was stored and the number of messages waiting
+/// in the inbox. Returns 503 when a component is down, degraded components don't fail the check.
+#[utoipa::path(
+    get,
+    path = "/v1/health/ready",
+    tag = "health",
+    security(()),
+    responses(
+        (status = OK, body = Readiness),
+        (status = SERVICE_UNAVAILABLE, body = Readiness),
+    )
+)]
+async fn ready_handler(State(app_state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let readiness = app_state.health.ready().await;
+    if readiness.status == HealthStatus::Down {
+        warn!("Readiness check failed: {:?}", readiness.components);
+        return (StatusCode::SERVICE_UNAVAILABLE, Json(readiness));
     }
+    (StatusCode::OK, Json(readiness))
+}
 
-    // If we get an error message, we return it as an internal error
-    if !err_msg.is_empty() {
-        error!(err_msg);
-        return (StatusCode::INTERNAL_SERVER_ERROR, err_msg);
+/// MQTT Connection
+///
+/// Returns the state of the connection to the MQTT broker, when it entered that state, the number of
+/// failed attempts while (re)connecting and the number of times the connection was reestablished.
+#[utoipa::path(
+    get,
+    path = "/v1/mqtt/connection",
+    tag = "health",
+    responses(
+        (status = OK, body = ConnectionStatus),
+    )
+)]
+async fn mqtt_connection_handler(State(app_state): State<AppState>) -> Json<ConnectionStatus> {
+    Json(app_state.connection.status())
+}
+
+/// Metrics
+///
+/// Returns the metrics of the listener in the Prometheus text format: the handled messages by topic
+/// and outcome, the database latency, the state of the MQTT connection, the API requests by route
+/// and the latest value of every sensor. This API requires the `METRICS_TOKEN` or an API key with
+/// the admin scope of the default tenant, either one sent as a bearer token
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "health",
+    responses(
+        (status = OK, body = String, content_type = "text/plain"),
+        (status = UNAUTHORIZED, description = "Missing or invalid token", body = ApiError),
+        (status = FORBIDDEN, description = "Not an admin key of the default tenant", body = ApiError),
+    )
+)]
+async fn metrics_handler(State(app_state): State<AppState>, headers: HeaderMap) -> Response {
+    let Some(key) = key_from_headers(&headers) else {
+        return ApiError::new(ErrorCode::Unauthorized, "Missing metrics token").into_response();
+    };
+    if app_state.config.metrics_token.as_deref() != Some(key) {
+        match app_state.auth.authenticate(key).await {
+            Ok(Some(api_key)) if api_key.is_operator() => {}
+            Ok(Some(_)) => return operator_only().into_response(),
+            Ok(None) => {
+                return ApiError::new(ErrorCode::Unauthorized, "Invalid metrics token")
+                    .into_response()
+            }
+            Err(response) => return response,
+        }
     }
 
-    // Success if db query returns a result and the MQTT client is connected.
-    info!("Healthcheck passed");
-    (StatusCode::OK, "Ok")
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        prometheus::render(),
+    )
+        .into_response()
 }
 
 /// VersionResponse
 ///
 /// Contains information about the current running server version
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema, Debug, Clone)]
 pub struct VersionResponse {
     version: String,
     commit: String,
 }
 
+impl VersionResponse {
+    /// Version information of the running binary
+    pub fn current() -> Self {
+        VersionResponse {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            // Read versioning information from rustc environment variables at compile time
+            // This variable is added as `cargo:rustc-env=VERGEN_GIT_SHA=blah` in the build.rs
+            commit: std::env!("VERGEN_GIT_SHA").to_string(),
+        }
+    }
+}
+
 /// Version
 ///
 /// Returns the version information of the application including a sematic version on a commit hash.
-// #[utoipa::path(get, path = "/version", responses((status = OK, body = VersionResponse)))]
+#[utoipa::path(
+    get,
+    path = "/v1/version",
+    tag = "health",
+    security(()),
+    responses(
+        (status = OK, body = VersionResponse),
+    )
+)]
 pub async fn version_handler() -> Json<VersionResponse> {
-    Json(VersionResponse {
-        version: env!("CARGO_PKG_VERSION").to_string(),
-        // Read versioning information from rustc environment variables at compile time
-        // This variable is added as `cargo:rustc-env=VERGEN_GIT_SHA=blah` in the build.rs
-        commit: std::env!("VERGEN_GIT_SHA").to_string(),
-    })
+    Json(VersionResponse::current())
+}
+
+/// Default number of items returned by the list data and list status APIs
+const DEFAULT_LIST_LIMIT: i64 = 1000;
+
+/// Query parameters of the list data and list status APIs
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ListQuery {
+    #[serde(rename = "deviceId")]
+    device_id: Option<String>,
+    /// Number of items to return, at most 5000
+    limit: Option<i64>,
 }
 
 /// List Data
 ///
-/// Returns a page of REM data stored in the database. This API is unauthenticated
-// #[utoipa::path(get, path = "/v1/rem/data/list", responses(
-//     (status = OK, body = Vec<RemData>),
-//     (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError )
-// ))]
+/// Returns the most recent REM data stored in the database, newest first, optionally only the data
+/// of one device. This API requires the read-data scope
+#[utoipa::path(
+    get,
+    path = "/v1/rem/data/list",
+    tag = "data",
+    params(ListQuery),
+    responses(
+        (status = OK, body = Vec<RemData>),
+        (status = BAD_REQUEST, description = "Invalid request", body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
 async fn list_data(
     State(app_state): State<AppState>,
-) -> Result<Json<Vec<RemData>>, Json<ApiError>> {
+    Extension(api_key): Extension<ApiKey>,
+    ApiQuery(query): ApiQuery<ListQuery>,
+) -> Result<Json<Vec<RemData>>, ApiError> {
+    let limit = list_limit(query.limit, DEFAULT_LIST_LIMIT)?;
+
     let repo = app_state.repo.lock().await;
-    repo.list_data().await.map(Json).map_err(|err| {
-        error!("Failed to {:?}", err.to_string());
-        Json(ApiError {
-            message: err.to_string(),
-        })
-    })
+    repo.list_data(&api_key.tenant_id, query.device_id.as_deref(), limit)
+        .await
+        .map(Json)
+        .map_err(ApiError::from)
 }
 
 /// List Status
 ///
-/// Returns a page of REM status stored in the database. This API is unauthenticated
-// #[utoipa::path(get, path = "/v1/rem/status/list", responses(
-//     (status = OK, body = Vec<RemStatus>),
-//     (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError )
-// ))]
+/// Returns the most recent REM status stored in the database, newest first, optionally only the
+/// status of one device. This API requires the read-data scope
+#[utoipa::path(
+    get,
+    path = "/v1/rem/status/list",
+    tag = "data",
+    params(ListQuery),
+    responses(
+        (status = OK, body = Vec<RemStatus>),
+        (status = BAD_REQUEST, description = "Invalid request", body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
 async fn list_status(
     State(app_state): State<AppState>,
-) -> Result<Json<Vec<RemStatus>>, Json<ApiError>> {
+    Extension(api_key): Extension<ApiKey>,
+    ApiQuery(query): ApiQuery<ListQuery>,
+) -> Result<Json<Vec<RemStatus>>, ApiError> {
+    let limit = list_limit(query.limit, DEFAULT_LIST_LIMIT)?;
+
+    let repo = app_state.repo.lock().await;
+    repo.list_status(&api_key.tenant_id, query.device_id.as_deref(), limit)
+        .await
+        .map(Json)
+        .map_err(ApiError::from)
+}
+
+/// Largest number of items accepted by the batch ingestion APIs
+const MAX_INGEST_BATCH: usize = 1000;
+
+/// What happened to an item posted to the ingestion API
+#[derive(Serialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum IngestStatus {
+    /// The item was stored
+    Stored,
+    /// The item was stored before, this isn't an error
+    Duplicate,
+    /// The item isn't valid and won't ever be stored
+    Invalid,
+    /// The signature of the item is missing or invalid, or the item may have been replayed
+    Rejected,
+    /// The device is over its rate limit, the item was dropped
+    RateLimited,
+    /// The item couldn't be stored, it can be retried
+    Failed,
+    /// The database can't be reached, the item can be retried
+    Unavailable,
+}
+
+/// Result of a single item posted to the ingestion API
+#[derive(Serialize, ToSchema, Debug)]
+struct IngestResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    status: IngestStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+impl IntoResponse for IngestResult {
+    /// The result of an item posted on its own, an [`ApiError`] unless the item was stored
+    fn into_response(self) -> Response {
+        let code = match self.status {
+            IngestStatus::Stored => return (StatusCode::CREATED, Json(self)).into_response(),
+            IngestStatus::Duplicate => return (StatusCode::OK, Json(self)).into_response(),
+            IngestStatus::Invalid => ErrorCode::InvalidRequest,
+            IngestStatus::Rejected => ErrorCode::Forbidden,
+            IngestStatus::RateLimited => ErrorCode::RateLimited,
+            IngestStatus::Failed => ErrorCode::Internal,
+            IngestStatus::Unavailable => ErrorCode::Unavailable,
+        };
+        ApiError::new(code, self.message.unwrap_or_default()).into_response()
+    }
+}
+
+/// Pass an item of a tenant to the handler of `topic`, exactly like a message received over MQTT.
+async fn ingest_item(ingestor: &Ingestor, tenant: &str, topic: &str, item: &Value) -> IngestResult {
+    let id = item.get("id").and_then(Value::as_str).map(str::to_string);
+    let msg = Message::new(topic, item.to_string(), QOS_1);
+
+    let (status, message) = match ingestor.submit(tenant, &msg).await {
+        Ok(Outcome::Duplicate) => (IngestStatus::Duplicate, None),
+        Ok(_) => (IngestStatus::Stored, None),
+        Err(MQTTClientError::InvalidMessage) => (
+            IngestStatus::Invalid,
+            Some("Payload doesn't match the message format".to_string()),
+        ),
+        Err(err @ MQTTClientError::RateLimited(_)) => {
+            (IngestStatus::RateLimited, Some(err.to_string()))
+        }
+        Err(err @ MQTTClientError::Signature(_)) => (IngestStatus::Rejected, Some(err.to_string())),
+        Err(err @ MQTTClientError::Repo(RemRepoError::UnknownDevice(_))) => {
+            (IngestStatus::Invalid, Some(err.to_string()))
+        }
+        Err(MQTTClientError::Repo(err)) if err.is_transient() => {
+            error!("Failed to {:?}", err.to_string());
+            (IngestStatus::Unavailable, Some(err.to_string()))
+        }
+        Err(err @ MQTTClientError::Repo(_)) => {
+            error!("Failed to {:?}", err.to_string());
+            (IngestStatus::Failed, Some(err.to_string()))
+        }
+        Err(err) => (IngestStatus::Invalid, Some(err.to_string())),
+    };
+
+    IngestResult {
+        id,
+        status,
+        message,
+    }
+}
+
+async fn ingest_one(app_state: AppState, tenant: &str, topic: &str, item: Value) -> Response {
+    ingest_item(&app_state.ingestor, tenant, topic, &item)
+        .await
+        .into_response()
+}
+
+async fn ingest_batch(
+    app_state: AppState,
+    tenant: &str,
+    topic: &str,
+    items: Vec<Value>,
+) -> Response {
+    if items.len() > MAX_INGEST_BATCH {
+        let message = format!("Batches are limited to {} items", MAX_INGEST_BATCH);
+        return ApiError::invalid(message).into_response();
+    }
+
+    let mut results = Vec::with_capacity(items.len());
+    for item in &items {
+        results.push(ingest_item(&app_state.ingestor, tenant, topic, item).await);
+    }
+    Json(results).into_response()
+}
+
+/// Ingest Data
+///
+/// Stores REM data posted over HTTP, for sites that can't reach the MQTT server. The body is the
+/// same JSON devices publish on `rem/data` and goes through the same validation. A duplicate is
+/// reported with a 200 rather than as an error, items that aren't stored with an error. Requires
+/// the write-data scope
+#[utoipa::path(
+    post,
+    path = "/v1/rem/data",
+    tag = "data",
+    request_body = RemData,
+    responses(
+        (status = CREATED, body = IngestResult),
+        (status = OK, description = "Duplicate", body = IngestResult),
+        (status = BAD_REQUEST, description = "Invalid item", body = ApiError),
+        (status = UNAUTHORIZED, body = ApiError),
+        (status = FORBIDDEN, description = "Missing or invalid signature", body = ApiError),
+        (status = TOO_MANY_REQUESTS, description = "Device over its rate limit", body = ApiError),
+        (status = SERVICE_UNAVAILABLE, description = "Database unreachable", body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn ingest_data(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiJson(item): ApiJson<Value>,
+) -> Response {
+    ingest_one(app_state, &api_key.tenant_id, REM_DATA_TOPIC, item).await
+}
+
+/// Ingest Data Batch
+///
+/// Stores a list of REM data posted over HTTP and returns the result of every item in the same
+/// order. One invalid item doesn't affect the others. Requires the write-data scope
+#[utoipa::path(
+    post,
+    path = "/v1/rem/data/batch",
+    tag = "data",
+    request_body = Vec<RemData>,
+    responses(
+        (status = OK, body = Vec<IngestResult>),
+        (status = UNAUTHORIZED, body = ApiError),
+    )
+)]
+async fn ingest_data_batch(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiJson(items): ApiJson<Vec<Value>>,
+) -> Response {
+    ingest_batch(app_state, &api_key.tenant_id, REM_DATA_TOPIC, items).await
+}
+
+/// Ingest Status
+///
+/// Stores a REM status posted over HTTP, with the same JSON devices publish on `rem/status`. A
+/// duplicate is reported with a 200 rather than as an error, items that aren't stored with an
+/// error. Requires the write-data scope
+#[utoipa::path(
+    post,
+    path = "/v1/rem/status",
+    tag = "data",
+    request_body = RemStatus,
+    responses(
+        (status = CREATED, body = IngestResult),
+        (status = OK, description = "Duplicate", body = IngestResult),
+        (status = BAD_REQUEST, description = "Invalid item", body = ApiError),
+        (status = UNAUTHORIZED, body = ApiError),
+        (status = FORBIDDEN, description = "Missing or invalid signature", body = ApiError),
+        (status = TOO_MANY_REQUESTS, description = "Device over its rate limit", body = ApiError),
+        (status = SERVICE_UNAVAILABLE, description = "Database unreachable", body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn ingest_status(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiJson(item): ApiJson<Value>,
+) -> Response {
+    ingest_one(app_state, &api_key.tenant_id, REM_STATUS_TOPIC, item).await
+}
+
+/// Ingest Status Batch
+///
+/// Stores a list of REM status posted over HTTP and returns the result of every item in the same
+/// order. Requires the write-data scope
+#[utoipa::path(
+    post,
+    path = "/v1/rem/status/batch",
+    tag = "data",
+    request_body = Vec<RemStatus>,
+    responses(
+        (status = OK, body = Vec<IngestResult>),
+        (status = UNAUTHORIZED, body = ApiError),
+    )
+)]
+async fn ingest_status_batch(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiJson(items): ApiJson<Vec<Value>>,
+) -> Response {
+    ingest_batch(app_state, &api_key.tenant_id, REM_STATUS_TOPIC, items).await
+}
+
+/// Default number of readings returned by the readings API
+const DEFAULT_READINGS_LIMIT: i64 = 1000;
+
+/// Largest `limit` of the list APIs, larger limits are capped to it
+const MAX_LIST_LIMIT: i64 = 5000;
+
+/// The `limit` query parameter of a list API, or `default` when it is left out
+fn list_limit(limit: Option<i64>, default: i64) -> Result<i64, ApiError> {
+    match limit {
+        None => Ok(default),
+        Some(limit) if limit < 1 => Err(ApiError::invalid(format!(
+            "Invalid limit: {}, it must be at least 1",
+            limit
+        ))),
+        Some(limit) => Ok(limit.min(MAX_LIST_LIMIT)),
+    }
+}
+
+/// Query parameters of the aggregate data API
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AggregateQuery {
+    #[serde(default)]
+    #[param(inline)]
+    bucket: AggregateBucket,
+    #[serde(rename = "deviceId")]
+    device_id: Option<String>,
+    since: Option<NaiveDateTime>,
+    /// Number of items to return, at most 5000
+    limit: Option<i64>,
+}
+
+/// Aggregate Data
+///
+/// Returns the average of every REM data channel per device and time bucket (`minute`, `hour`,
+/// `day`, `week` or `month`), along with the derived metrics of those averages. This API requires
+/// the read-data scope
+#[utoipa::path(
+    get,
+    path = "/v1/rem/data/aggregate",
+    tag = "data",
+    params(AggregateQuery),
+    responses(
+        (status = OK, body = Vec<RemDataAggregate>),
+        (status = BAD_REQUEST, description = "Invalid request", body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn aggregate_data(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiQuery(query): ApiQuery<AggregateQuery>,
+) -> Result<Json<Vec<RemDataAggregate>>, ApiError> {
+    let filter = AggregateFilter {
+        bucket: query.bucket,
+        device_id: query.device_id,
+        since: query.since,
+        limit: list_limit(query.limit, DEFAULT_READINGS_LIMIT)?,
+    };
+
+    let repo = app_state.repo.lock().await;
+    repo.aggregate_data(&api_key.tenant_id, filter)
+        .await
+        .map(Json)
+        .map_err(ApiError::from)
+}
+
+/// Query parameters of the readings API
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ReadingsQuery {
+    metric: String,
+    #[serde(rename = "deviceId")]
+    device_id: Option<String>,
+    since: Option<NaiveDateTime>,
+    /// Number of items to return, at most 5000
+    limit: Option<i64>,
+}
+
+/// List Readings
+///
+/// Returns the most recent values of a single metric, newest first. The metric can be one of the
+/// known REM data channels (like `temperature` or `co2`) or any extra field a device reported.
+/// This API requires the read-data scope
+#[utoipa::path(
+    get,
+    path = "/v1/rem/readings",
+    tag = "data",
+    params(ReadingsQuery),
+    responses(
+        (status = OK, body = Vec<Reading>),
+        (status = BAD_REQUEST, description = "Invalid request", body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn list_readings(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiQuery(query): ApiQuery<ReadingsQuery>,
+) -> Result<Json<Vec<Reading>>, ApiError> {
+    let filter = ReadingsFilter {
+        metric: query.metric,
+        device_id: query.device_id,
+        since: query.since,
+        limit: list_limit(query.limit, DEFAULT_READINGS_LIMIT)?,
+    };
+
+    let repo = app_state.repo.lock().await;
+    repo.list_readings(&api_key.tenant_id, filter)
+        .await
+        .map(Json)
+        .map_err(ApiError::from)
+}
+
+/// Body of the calibration API, `calibrated = raw * scale + offset`
+#[derive(Debug, Deserialize, ToSchema)]
+struct CalibrationRequest {
+    #[serde(default)]
+    offset: f32,
+    #[serde(default = "default_scale")]
+    scale: f32,
+}
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+/// List Calibrations
+///
+/// Returns the calibration of every channel of a device. This API requires the read-data scope
+#[utoipa::path(
+    get,
+    path = "/v1/rem/calibration/{device_id}",
+    tag = "calibration",
+    params(("device_id" = String, Path, description = "ID of the device")),
+    responses(
+        (status = OK, body = Vec<Calibration>),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn list_calibrations(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiPath(device_id): ApiPath<String>,
+) -> Result<Json<Vec<Calibration>>, ApiError> {
+    record_device(&device_id);
+    let repo = app_state.repo.lock().await;
+    repo.list_calibrations(&api_key.tenant_id, &device_id)
+        .await
+        .map(Json)
+        .map_err(ApiError::from)
+}
+
+/// Set Calibration
+///
+/// Creates or replaces the calibration of a channel of a device. The calibration applies to newly
+/// ingested data, use the recompute API to apply it to the stored data. This API requires the
+/// admin scope
+#[utoipa::path(
+    put,
+    path = "/v1/rem/calibration/{device_id}/{field}",
+    tag = "calibration",
+    params(("device_id" = String, Path, description = "ID of the device"), ("field" = String, Path, description = "Name of the channel, like `temperature`")),
+    request_body = CalibrationRequest,
+    responses(
+        (status = OK, body = Calibration),
+        (status = BAD_REQUEST, description = "Invalid request", body = ApiError),
+        (status = NOT_FOUND, description = "Unknown device", body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn set_calibration(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiPath((device_id, field)): ApiPath<(String, String)>,
+    ApiJson(req): ApiJson<CalibrationRequest>,
+) -> Result<Json<Calibration>, ApiError> {
+    record_device(&device_id);
+    if !RemData::CHANNELS.contains(&field.as_str()) {
+        return Err(ApiError::invalid(format!("Unknown channel: {}", field)));
+    }
+
+    let calibration = Calibration {
+        device_id,
+        field,
+        offset: req.offset,
+        scale: req.scale,
+    };
+
+    let repo = app_state.repo.lock().await;
+    repo.upsert_calibration(&api_key.tenant_id, calibration.clone())
+        .await
+        .map(|_| Json(calibration))
+        .map_err(ApiError::from)
+}
+
+/// Delete Calibration
+///
+/// Removes the calibration of a channel of a device. This API requires the admin scope
+#[utoipa::path(
+    delete,
+    path = "/v1/rem/calibration/{device_id}/{field}",
+    tag = "calibration",
+    params(("device_id" = String, Path, description = "ID of the device"), ("field" = String, Path, description = "Name of the channel, like `temperature`")),
+    responses(
+        (status = NO_CONTENT),
+        (status = NOT_FOUND, body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn delete_calibration(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiPath((device_id, field)): ApiPath<(String, String)>,
+) -> Result<StatusCode, ApiError> {
+    record_device(&device_id);
+    let repo = app_state.repo.lock().await;
+    let deleted = repo
+        .delete_calibration(&api_key.tenant_id, &device_id, &field)
+        .await?;
+    if !deleted {
+        let message = format!("Device {} has no calibration of {}", device_id, field);
+        return Err(ApiError::not_found(message));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Recompute Calibration
+///
+/// Starts a background job that reapplies the current calibrations of a device to all its stored
+/// data, using the raw values recorded at ingestion. Poll the job with the recompute status API to
+/// know when it is done. A device is only recalibrated by one job at a time. This API requires the
+/// admin scope
+#[utoipa::path(
+    post,
+    path = "/v1/rem/calibration/{device_id}/recompute",
+    tag = "calibration",
+    params(("device_id" = String, Path, description = "ID of the device")),
+    responses(
+        (status = ACCEPTED, body = RecalibrationJob),
+        (status = CONFLICT, description = "Already being recalibrated", body = ApiError),
+    )
+)]
+async fn recompute_calibration(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiPath(device_id): ApiPath<String>,
+) -> Result<(StatusCode, Json<RecalibrationJob>), ApiError> {
+    record_device(&device_id);
+    match app_state
+        .recalibrations
+        .start(api_key.tenant_id, device_id.clone())
+    {
+        Ok(job) => Ok((StatusCode::ACCEPTED, Json(job))),
+        Err(running) => Err(ApiError::new(
+            ErrorCode::Conflict,
+            format!("Device {device_id} is being recalibrated by job {running}"),
+        )),
+    }
+}
+
+/// Recompute Status
+///
+/// Returns the progress of a recalibration job, and whether it completed or failed. Jobs are
+/// forgotten when the listener restarts. This API requires the read-data scope
+#[utoipa::path(
+    get,
+    path = "/v1/rem/calibration/{device_id}/recompute/{job_id}",
+    tag = "calibration",
+    params(("device_id" = String, Path, description = "ID of the device"), ("job_id" = String, Path, description = "ID of the job")),
+    responses(
+        (status = OK, body = RecalibrationJob),
+        (status = NOT_FOUND, body = ApiError),
+    )
+)]
+async fn recompute_status(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiPath((device_id, job_id)): ApiPath<(String, String)>,
+) -> Result<Json<RecalibrationJob>, ApiError> {
+    record_device(&device_id);
+    app_state
+        .recalibrations
+        .get(&api_key.tenant_id, &device_id, &job_id)
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("Unknown recalibration job: {}", job_id)))
+}
+
+/// Default number of commands returned by the commands API
+const DEFAULT_COMMANDS_LIMIT: i64 = 100;
+
+/// Query parameters of the commands API
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CommandsQuery {
+    /// Number of items to return, at most 5000
+    limit: Option<i64>,
+}
+
+/// Send Command
+///
+/// Publishes a command to a device on `rem/{device_id}/cmd`, for example
+/// `{"command": "setReportingInterval", "params": {"seconds": 60}}`, `{"command": "reboot"}` or
+/// `{"command": "selfClean"}`. The command is stored first, and its status is updated from the
+/// acknowledgements of the device. This API requires the device-command scope
+#[utoipa::path(
+    post,
+    path = "/v1/rem/command/{device_id}",
+    tag = "commands",
+    params(("device_id" = String, Path, description = "ID of the device")),
+    request_body = DeviceCommand,
+    responses(
+        (status = ACCEPTED, body = Command),
+        (status = BAD_REQUEST, description = "Invalid request", body = ApiError),
+        (status = NOT_FOUND, description = "Unknown device", body = ApiError),
+        (status = SERVICE_UNAVAILABLE, description = "MQTT server unreachable", body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn send_command(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiPath(device_id): ApiPath<String>,
+    ApiJson(req): ApiJson<DeviceCommand>,
+) -> Result<(StatusCode, Json<Command>), ApiError> {
+    record_device(&device_id);
+    if !is_valid_topic_level(&device_id) {
+        return Err(ApiError::invalid(format!(
+            "Invalid device ID: {}",
+            device_id
+        )));
+    }
+
+    // Publishing would only fail after the command was stored
+    if !app_state.connection.is_connected() {
+        return Err(ApiError::unavailable(
+            "MQTT client is not connected to the broker",
+        ));
+    }
+
+    let mut command = Command::new(device_id, &req);
+
+    app_state
+        .repo
+        .lock()
+        .await
+        .insert_command(&api_key.tenant_id, &command)
+        .await?;
+
+    let published = app_state
+        .publisher
+        .publish(command_message(app_state.topic_tenant(&api_key), &command))
+        .await;
+    let (status, response, publish_err) = match published {
+        Ok(_) => (CommandStatus::Sent, None, None),
+        Err(e) => {
+            error!("Failed to publish command {}: {}", command.id, e);
+            let response = json!({ "error": e.to_string() });
+            (CommandStatus::Failed, Some(response), Some(e))
+        }
+    };
+
+    app_state
+        .repo
+        .lock()
+        .await
+        .set_command_status(&api_key.tenant_id, &command.id, status, response.clone())
+        .await?;
+
+    if let Some(e) = publish_err {
+        return Err(ApiError::unavailable(format!(
+            "Failed to publish command: {}",
+            e
+        )));
+    }
+
+    command.status = status;
+    command.response = response;
+    Ok((StatusCode::ACCEPTED, Json(command)))
+}
+
+/// List Commands
+///
+/// Returns the most recent commands sent to a device along with their status, newest first.
+/// This API requires the read-data scope
+#[utoipa::path(
+    get,
+    path = "/v1/rem/command/{device_id}",
+    tag = "commands",
+    params(("device_id" = String, Path, description = "ID of the device"), CommandsQuery),
+    responses(
+        (status = OK, body = Vec<Command>),
+        (status = BAD_REQUEST, description = "Invalid request", body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn list_commands(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiPath(device_id): ApiPath<String>,
+    ApiQuery(query): ApiQuery<CommandsQuery>,
+) -> Result<Json<Vec<Command>>, ApiError> {
+    record_device(&device_id);
+    let limit = list_limit(query.limit, DEFAULT_COMMANDS_LIMIT)?;
+
+    let repo = app_state.repo.lock().await;
+    repo.list_commands(&api_key.tenant_id, &device_id, limit)
+        .await
+        .map(Json)
+        .map_err(ApiError::from)
+}
+
+/// Get Command
+///
+/// Returns a single command sent to a device along with its status. This API requires the
+/// read-data scope
+#[utoipa::path(
+    get,
+    path = "/v1/rem/command/{device_id}/{command_id}",
+    tag = "commands",
+    params(("device_id" = String, Path, description = "ID of the device"), ("command_id" = String, Path, description = "ID of the command")),
+    responses(
+        (status = OK, body = Command),
+        (status = NOT_FOUND, body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn get_command(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiPath((device_id, command_id)): ApiPath<(String, String)>,
+) -> Result<Json<Command>, ApiError> {
+    record_device(&device_id);
+    let repo = app_state.repo.lock().await;
+    repo.get_command(&api_key.tenant_id, &device_id, &command_id)
+        .await?
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("Unknown command: {}", command_id)))
+}
+
+/// Query parameters of the device configuration API
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ConfigsQuery {
+    /// Only return the devices whose reported configuration differs from the desired one
+    #[serde(default)]
+    drifted: bool,
+}
+
+/// List Device Configurations
+///
+/// Returns the desired and reported configuration of every known device, along with the settings
+/// that drifted. With `drifted=true` only the devices that are out of sync are returned. This API
+/// requires the read-data scope
+#[utoipa::path(
+    get,
+    path = "/v1/rem/config",
+    tag = "config",
+    params(ConfigsQuery),
+    responses(
+        (status = OK, body = Vec<DeviceConfigState>),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn list_configs(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiQuery(query): ApiQuery<ConfigsQuery>,
+) -> Result<Json<Vec<DeviceConfigState>>, ApiError> {
+    let repo = app_state.repo.lock().await;
+    repo.list_config_states(&api_key.tenant_id)
+        .await
+        .map(|states| {
+            Json(
+                states
+                    .into_iter()
+                    .filter(|state| !query.drifted || !state.in_sync)
+                    .collect(),
+            )
+        })
+        .map_err(ApiError::from)
+}
+
+/// Get Device Configuration
+///
+/// Returns the desired and reported configuration of a device, along with the settings that
+/// drifted. This API requires the read-data scope
+#[utoipa::path(
+    get,
+    path = "/v1/rem/config/{device_id}",
+    tag = "config",
+    params(("device_id" = String, Path, description = "ID of the device")),
+    responses(
+        (status = OK, body = DeviceConfigState),
+        (status = NOT_FOUND, body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn get_config(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiPath(device_id): ApiPath<String>,
+) -> Result<Json<DeviceConfigState>, ApiError> {
+    record_device(&device_id);
+    let repo = app_state.repo.lock().await;
+    repo.get_config_state(&api_key.tenant_id, &device_id)
+        .await?
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("Unknown device: {}", device_id)))
+}
+
+/// Set Device Configuration
+///
+/// Replaces the desired configuration of a device and publishes it as a retained message on
+/// `rem/{device_id}/config`, for example `{"reportingInterval": 60, "ledBrightness": 20}`. Settings
+/// that are left out stay at the device's default. This API requires the device-command scope
+#[utoipa::path(
+    put,
+    path = "/v1/rem/config/{device_id}",
+    tag = "config",
+    params(("device_id" = String, Path, description = "ID of the device")),
+    request_body = DeviceConfig,
+    responses(
+        (status = OK, body = DeviceConfigState),
+        (status = BAD_REQUEST, description = "Invalid request", body = ApiError),
+        (status = NOT_FOUND, description = "Unknown device", body = ApiError),
+        (status = SERVICE_UNAVAILABLE, description = "MQTT server unreachable", body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn set_config(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiPath(device_id): ApiPath<String>,
+    ApiJson(config): ApiJson<DeviceConfig>,
+) -> Result<Json<DeviceConfigState>, ApiError> {
+    record_device(&device_id);
+    if !is_valid_topic_level(&device_id) {
+        return Err(ApiError::invalid(format!(
+            "Invalid device ID: {}",
+            device_id
+        )));
+    }
+    if let Err(message) = config.validate() {
+        return Err(ApiError::invalid(message));
+    }
+
+    // The device only gets the configuration through the retained message
+    if !app_state.connection.is_connected() {
+        return Err(ApiError::unavailable(
+            "MQTT client is not connected to the broker",
+        ));
+    }
+
+    let state = app_state
+        .repo
+        .lock()
+        .await
+        .set_desired_config(&api_key.tenant_id, &device_id, &config)
+        .await
+        .map_err(ApiError::from)?;
+
+    let published = app_state
+        .publisher
+        .publish(config_message(
+            app_state.topic_tenant(&api_key),
+            &device_id,
+            &config,
+        ))
+        .await;
+    if let Err(e) = published {
+        error!(
+            "Failed to publish the configuration of {}: {}",
+            device_id, e
+        );
+        return Err(ApiError::unavailable(format!(
+            "Configuration was stored but couldn't be published: {}",
+            e
+        )));
+    }
+
+    Ok(Json(state))
+}
+
+/// Largest firmware image accepted by the upload API
+const MAX_FIRMWARE_SIZE: usize = 16 * 1024 * 1024;
+
+/// List Firmware
+///
+/// Returns the uploaded firmware images, newest first. This API requires the read-data scope
+#[utoipa::path(
+    get,
+    path = "/v1/rem/firmware",
+    tag = "firmware",
+    responses(
+        (status = OK, body = Vec<FirmwareImage>),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn list_firmware(
+    State(app_state): State<AppState>,
+) -> Result<Json<Vec<FirmwareImage>>, ApiError> {
+    let repo = app_state.repo.lock().await;
+    repo.list_firmware_images()
+        .await
+        .map(Json)
+        .map_err(ApiError::from)
+}
+
+/// Upload Firmware
+///
+/// Stores a firmware image, sent as the raw request body, under a version. The checksum is
+/// computed on upload. Versions can't be replaced once uploaded. Images are shared by all tenants,
+/// so this API requires the admin scope of the default tenant
+#[utoipa::path(
+    post,
+    path = "/v1/rem/firmware/{version}",
+    tag = "firmware",
+    params(("version" = String, Path, description = "Firmware version")),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = CREATED, body = FirmwareImage),
+        (status = BAD_REQUEST, description = "Invalid request", body = ApiError),
+        (status = FORBIDDEN, description = "Not an admin key of the default tenant", body = ApiError),
+        (status = CONFLICT, description = "Already exists", body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn upload_firmware(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiPath(version): ApiPath<String>,
+    image: Result<Bytes, BytesRejection>,
+) -> Result<(StatusCode, Json<FirmwareImage>), ApiError> {
+    let image = image?;
+    if !api_key.is_operator() {
+        return Err(operator_only());
+    }
+    if !is_valid_version(&version) {
+        return Err(ApiError::invalid(format!(
+            "Invalid firmware version: {}",
+            version
+        )));
+    }
+    if image.is_empty() {
+        return Err(ApiError::invalid("Firmware image is empty"));
+    }
+
+    // The row is inserted first so that of concurrent uploads of a version only one gets past
+    // the unique constraint, and an image devices may already be downloading is never replaced
+    let firmware = FirmwareImage {
+        sha256: checksum(&image),
+        size: image.len() as i64,
+        version,
+        created_at: chrono::Utc::now().naive_utc(),
+    };
+    let repo = app_state.repo.clone();
+    repo.lock().await.insert_firmware_image(&firmware).await?;
+
+    if let Err(e) = app_state.firmware.save(&firmware.version, &image).await {
+        error!("Failed to write firmware {}: {}", firmware.version, e);
+        if let Err(e) = repo
+            .lock()
+            .await
+            .delete_firmware_image(&firmware.version)
+            .await
+        {
+            error!(
+                "Failed to remove firmware {} after its upload failed: {}",
+                firmware.version, e
+            );
+        }
+        return Err(ApiError::internal(format!(
+            "Failed to store the firmware image: {}",
+            e
+        )));
+    }
+
+    info!(
+        "Stored firmware {} ({} bytes, sha256 {})",
+        firmware.version, firmware.size, firmware.sha256
+    );
+    Ok((StatusCode::CREATED, Json(firmware)))
+}
+
+/// Download Firmware
+///
+/// Returns the image of a firmware version, this is the URL devices are sent in update
+/// notifications. This API is public, devices download from it without an API key
+#[utoipa::path(
+    get,
+    path = "/v1/rem/firmware/{version}/download",
+    tag = "firmware",
+    params(("version" = String, Path, description = "Firmware version")),
+    security(()),
+    responses(
+        (status = OK, body = Vec<u8>, content_type = "application/octet-stream"),
+        (status = NOT_FOUND, body = ApiError),
+    )
+)]
+async fn download_firmware(
+    State(app_state): State<AppState>,
+    ApiPath(version): ApiPath<String>,
+) -> Result<Response, ApiError> {
+    let unknown = || ApiError::not_found(format!("Unknown firmware version: {}", version));
+    if !is_valid_version(&version) {
+        return Err(unknown());
+    }
+
+    match app_state.firmware.read(&version).await {
+        Ok(image) => {
+            Ok(([(header::CONTENT_TYPE, "application/octet-stream")], image).into_response())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(unknown()),
+        Err(e) => {
+            error!("Failed to read firmware {}: {}", version, e);
+            Err(ApiError::internal("Failed to read the firmware image"))
+        }
+    }
+}
+
+/// Body of the firmware assignment API
+#[derive(Debug, Deserialize, ToSchema)]
+struct AssignFirmwareRequest {
+    #[serde(rename = "deviceIds", default)]
+    device_ids: Vec<String>,
+
+    /// Every device currently in this group is assigned the firmware as well
+    group: Option<String>,
+}
+
+/// Assign Firmware
+///
+/// Makes a firmware version the target of devices, given by ID or by group, and notifies them on
+/// `rem/{device_id}/ota` with the download URL and checksum of the image. Devices that join the
+/// group later aren't updated automatically. This API requires the device-command scope
+#[utoipa::path(
+    post,
+    path = "/v1/rem/firmware/{version}/assign",
+    tag = "firmware",
+    params(("version" = String, Path, description = "Firmware version")),
+    request_body = AssignFirmwareRequest,
+    responses(
+        (status = OK, body = Vec<FirmwareUpdate>),
+        (status = BAD_REQUEST, description = "Invalid request", body = ApiError),
+        (status = NOT_FOUND, body = ApiError),
+        (status = SERVICE_UNAVAILABLE, description = "MQTT server unreachable", body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn assign_firmware(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiPath(version): ApiPath<String>,
+    ApiJson(req): ApiJson<AssignFirmwareRequest>,
+) -> Result<Json<Vec<FirmwareUpdate>>, ApiError> {
+    let image = app_state
+        .repo
+        .lock()
+        .await
+        .get_firmware_image(&version)
+        .await?
+        .ok_or_else(|| ApiError::not_found(format!("Unknown firmware version: {}", version)))?;
+
+    let mut device_ids = req.device_ids;
+    if let Some(group) = &req.group {
+        let members = app_state
+            .repo
+            .lock()
+            .await
+            .list_devices(&api_key.tenant_id, Some(group))
+            .await?;
+        device_ids.extend(members.into_iter().map(|d| d.device_id));
+    }
+    device_ids.sort();
+    device_ids.dedup();
+
+    if device_ids.is_empty() {
+        return Err(ApiError::invalid("No devices to assign the firmware to"));
+    }
+    if let Some(device_id) = device_ids.iter().find(|id| !is_valid_topic_level(id)) {
+        return Err(ApiError::invalid(format!(
+            "Invalid device ID: {}",
+            device_id
+        )));
+    }
+
+    // The devices only learn about the update through the notification
+    if !app_state.connection.is_connected() {
+        return Err(ApiError::unavailable(
+            "MQTT client is not connected to the broker",
+        ));
+    }
+
+    let mut updates = app_state
+        .repo
+        .lock()
+        .await
+        .assign_firmware(&api_key.tenant_id, &version, &device_ids)
+        .await?;
+
+    let url = download_url(&app_state.config, &version);
+    for update in updates.iter_mut() {
+        let published = app_state
+            .publisher
+            .publish(ota_message(
+                app_state.topic_tenant(&api_key),
+                &update.device_id,
+                &image,
+                &url,
+            ))
+            .await;
+        if let Err(e) = published {
+            // The update stays assigned, assigning it again retries the notification
+            error!(
+                "Failed to notify {} of firmware {}: {}",
+                update.device_id, version, e
+            );
+            continue;
+        }
+
+        app_state
+            .repo
+            .lock()
+            .await
+            .set_firmware_update_status(
+                &api_key.tenant_id,
+                &update.device_id,
+                FirmwareUpdateStatus::Notified,
+            )
+            .await?;
+        update.status = FirmwareUpdateStatus::Notified;
+    }
+
+    Ok(Json(updates))
+}
+
+/// Query parameters of the firmware updates API
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct FirmwareUpdatesQuery {
+    version: Option<String>,
+}
+
+/// List Firmware Updates
+///
+/// Returns the firmware update assigned to every device and its progress, optionally only the
+/// updates to a single version. This API requires the read-data scope
+#[utoipa::path(
+    get,
+    path = "/v1/rem/firmware/updates",
+    tag = "firmware",
+    params(FirmwareUpdatesQuery),
+    responses(
+        (status = OK, body = Vec<FirmwareUpdate>),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn list_firmware_updates(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiQuery(query): ApiQuery<FirmwareUpdatesQuery>,
+) -> Result<Json<Vec<FirmwareUpdate>>, ApiError> {
+    let repo = app_state.repo.lock().await;
+    repo.list_firmware_updates(&api_key.tenant_id, query.version.as_deref())
+        .await
+        .map(Json)
+        .map_err(ApiError::from)
+}
+
+/// Query parameters of the devices API
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DevicesQuery {
+    group: Option<String>,
+}
+
+/// List Devices
+///
+/// Returns the known devices with their group and reported firmware version, optionally only the
+/// devices in a group. This API requires the read-data scope
+#[utoipa::path(
+    get,
+    path = "/v1/rem/devices",
+    tag = "devices",
+    params(DevicesQuery),
+    responses(
+        (status = OK, body = Vec<Device>),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn list_devices(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiQuery(query): ApiQuery<DevicesQuery>,
+) -> Result<Json<Vec<Device>>, ApiError> {
+    let repo = app_state.repo.lock().await;
+    repo.list_devices(&api_key.tenant_id, query.group.as_deref())
+        .await
+        .map(Json)
+        .map_err(ApiError::from)
+}
+
+/// Body of the device group API
+#[derive(Debug, Deserialize, ToSchema)]
+struct DeviceGroupRequest {
+    /// The group of the device, `null` removes it from its group
+    group: Option<String>,
+}
+
+/// Set Device Group
+///
+/// Puts a device in a group used to roll out firmware. This API requires the admin scope
+#[utoipa::path(
+    put,
+    path = "/v1/rem/devices/{device_id}/group",
+    tag = "devices",
+    params(("device_id" = String, Path, description = "ID of the device")),
+    request_body = DeviceGroupRequest,
+    responses(
+        (status = OK, body = Device),
+        (status = NOT_FOUND, body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn set_device_group(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiPath(device_id): ApiPath<String>,
+    ApiJson(req): ApiJson<DeviceGroupRequest>,
+) -> Result<Json<Device>, ApiError> {
+    record_device(&device_id);
+    let repo = app_state.repo.lock().await;
+    repo.set_device_group(&api_key.tenant_id, &device_id, req.group.as_deref())
+        .await
+        .map(Json)
+        .map_err(ApiError::from)
+}
+
+/// Body of the device key API
+#[derive(Debug, Deserialize, ToSchema)]
+struct DeviceKeyRequest {
+    algorithm: SignatureAlgorithm,
+
+    /// Hex encoded shared secret for `hmac-sha256`, or public key for `ed25519`
+    key: String,
+}
+
+/// Set Device Key
+///
+/// Sets the key the messages of a device are verified with, replacing its previous key. Once a
+/// device has a key its messages are only stored when they are signed with it, see the `signing`
+/// module for how to sign them. The key isn't returned by any API. This API requires the admin
+/// scope
+#[utoipa::path(
+    put,
+    path = "/v1/rem/devices/{device_id}/key",
+    tag = "devices",
+    params(("device_id" = String, Path, description = "ID of the device")),
+    request_body = DeviceKeyRequest,
+    responses(
+        (status = OK, body = DeviceKey),
+        (status = BAD_REQUEST, description = "Invalid request", body = ApiError),
+        (status = NOT_FOUND, body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn set_device_key(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiPath(device_id): ApiPath<String>,
+    ApiJson(req): ApiJson<DeviceKeyRequest>,
+) -> Result<Json<DeviceKey>, ApiError> {
+    record_device(&device_id);
+    let Ok(key) = hex::decode(&req.key) else {
+        return Err(ApiError::invalid("Key isn't hex encoded"));
+    };
+    if let Err(message) = validate_key(req.algorithm, &key) {
+        return Err(ApiError::invalid(message));
+    }
+
     let repo = app_state.repo.lock().await;
-    repo.list_status().await.map(Json).map_err(|err| {
-        error!("Failed to {:?}", err.to_string());
-        Json(ApiError {
-            message: err.to_string(),
+    repo.set_device_key(&api_key.tenant_id, &device_id, req.algorithm, &key)
+        .await
+        .map(|device_key| {
+            info!(
+                "Set {} key of device {}",
+                device_key.algorithm.as_str(),
+                device_id
+            );
+            Json(device_key)
         })
-    })
+        .map_err(ApiError::from)
 }
 
+/// Delete Device Key
+///
+/// Removes the key of a device, its messages are stored without verification again unless signed
+/// messages are required. This API requires the admin scope
+#[utoipa::path(
+    delete,
+    path = "/v1/rem/devices/{device_id}/key",
+    tag = "devices",
+    params(("device_id" = String, Path, description = "ID of the device")),
+    responses(
+        (status = NO_CONTENT),
+        (status = NOT_FOUND, body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn delete_device_key(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiPath(device_id): ApiPath<String>,
+) -> Result<StatusCode, ApiError> {
+    record_device(&device_id);
+    let repo = app_state.repo.lock().await;
+    let deleted = repo
+        .delete_device_key(&api_key.tenant_id, &device_id)
+        .await?;
+    if !deleted {
+        return Err(ApiError::not_found(format!(
+            "Device {} has no key",
+            device_id
+        )));
+    }
+    info!("Deleted the key of device {}", device_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Default number of messages returned by the rejected messages API
+const DEFAULT_REJECTED_LIMIT: i64 = 100;
+
+/// Query parameters of the rejected messages API
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct RejectedQuery {
+    #[serde(rename = "deviceId")]
+    device_id: Option<String>,
+    /// Number of items to return, at most 5000
+    limit: Option<i64>,
+}
+
+/// List Rejected Messages
+///
+/// Returns the most recent messages that weren't stored because they weren't signed, their
+/// signature didn't match the key of the device or they may have been replayed, newest first. This
+/// API requires the read-data scope
+#[utoipa::path(
+    get,
+    path = "/v1/rem/rejected",
+    tag = "devices",
+    params(RejectedQuery),
+    responses(
+        (status = OK, body = Vec<RejectedMessage>),
+        (status = BAD_REQUEST, description = "Invalid request", body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn list_rejected_messages(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiQuery(query): ApiQuery<RejectedQuery>,
+) -> Result<Json<Vec<RejectedMessage>>, ApiError> {
+    let limit = list_limit(query.limit, DEFAULT_REJECTED_LIMIT)?;
+
+    let repo = app_state.repo.lock().await;
+    repo.list_rejected_messages(&api_key.tenant_id, query.device_id.as_deref(), limit)
+        .await
+        .map(Json)
+        .map_err(ApiError::from)
+}
+
+/// Body of the create API key request
+#[derive(Debug, Deserialize, ToSchema)]
+struct CreateApiKeyRequest {
+    name: String,
+    scopes: Vec<ApiScope>,
+
+    /// Tenant the key is bound to, only admin keys of the default tenant can pick another tenant
+    #[serde(rename = "tenantId")]
+    tenant_id: Option<String>,
+}
+
+/// A new API key along with the key itself, which isn't shown again
+#[derive(Debug, Serialize, ToSchema)]
+struct CreatedApiKey {
+    #[serde(flatten)]
+    api_key: ApiKey,
+    key: String,
+}
+
+/// Create API Key
+///
+/// Creates an API key with the given scopes, one or more of `read-data`, `write-data`,
+/// `device-command` and `admin`. The key is bound to the tenant of the caller, or to `tenantId` when
+/// called with an admin key of the default tenant. The key is only returned in this response, store
+/// it right away. This API requires the admin scope
+#[utoipa::path(
+    post,
+    path = "/v1/admin/keys",
+    tag = "admin",
+    request_body = CreateApiKeyRequest,
+    responses(
+        (status = CREATED, body = CreatedApiKey),
+        (status = BAD_REQUEST, description = "Invalid request", body = ApiError),
+        (status = FORBIDDEN, description = "Not an admin key of the default tenant", body = ApiError),
+        (status = CONFLICT, description = "Already exists", body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn create_api_key(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiJson(req): ApiJson<CreateApiKeyRequest>,
+) -> Result<(StatusCode, Json<CreatedApiKey>), ApiError> {
+    if req.name.trim().is_empty() {
+        return Err(ApiError::invalid("API key name is empty"));
+    }
+    if req.scopes.is_empty() {
+        return Err(ApiError::invalid("API key needs at least one scope"));
+    }
+
+    let tenant_id = match req.tenant_id {
+        Some(tenant_id) if tenant_id != api_key.tenant_id => {
+            if !api_key.is_operator() {
+                return Err(operator_only());
+            }
+            tenant_id
+        }
+        _ => api_key.tenant_id,
+    };
+
+    let key = generate_key();
+    let api_key = ApiKey {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: req.name,
+        tenant_id,
+        prefix: key_prefix(&key),
+        scopes: req.scopes,
+        created_at: chrono::Utc::now().naive_utc(),
+        revoked_at: None,
+    };
+
+    app_state
+        .repo
+        .lock()
+        .await
+        .insert_api_key(&api_key, &hash_key(&key))
+        .await
+        .map_err(ApiError::from)?;
+
+    info!("Created API key {} '{}'", api_key.id, api_key.name);
+    Ok((StatusCode::CREATED, Json(CreatedApiKey { api_key, key })))
+}
+
+/// List API Keys
+///
+/// Returns all API keys of the tenant of the caller including the revoked ones, without the keys
+/// themselves. This API requires the admin scope
+#[utoipa::path(
+    get,
+    path = "/v1/admin/keys",
+    tag = "admin",
+    responses(
+        (status = OK, body = Vec<ApiKey>),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn list_api_keys(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+) -> Result<Json<Vec<ApiKey>>, ApiError> {
+    let repo = app_state.repo.lock().await;
+    repo.list_api_keys(&api_key.tenant_id)
+        .await
+        .map(Json)
+        .map_err(ApiError::from)
+}
+
+/// Revoke API Key
+///
+/// Revokes an API key of the tenant of the caller, requests with it are rejected from now on. This
+/// API requires the admin scope
+#[utoipa::path(
+    delete,
+    path = "/v1/admin/keys/{id}",
+    tag = "admin",
+    params(("id" = String, Path, description = "ID of the API key")),
+    responses(
+        (status = NO_CONTENT),
+        (status = NOT_FOUND, body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn revoke_api_key(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiPath(id): ApiPath<String>,
+) -> Result<StatusCode, ApiError> {
+    let repo = app_state.repo.lock().await;
+    let revoked = repo.revoke_api_key(&api_key.tenant_id, &id).await?;
+    if !revoked {
+        return Err(ApiError::not_found(format!("Unknown API key: {}", id)));
+    }
+    info!("Revoked API key {}", id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Rate Limits
+///
+/// Returns the number of device messages dropped or sampled over the ingestion rate limits, per
+/// device and in total, and the number of requests rejected over the API rate limits. This API
+/// requires the admin scope of the default tenant
+#[utoipa::path(
+    get,
+    path = "/v1/admin/rate-limits",
+    tag = "admin",
+    responses(
+        (status = OK, body = RateLimitStats),
+        (status = FORBIDDEN, description = "Not an admin key of the default tenant", body = ApiError),
+    )
+)]
+async fn rate_limit_stats(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+) -> Result<Json<RateLimitStats>, ApiError> {
+    if !api_key.is_operator() {
+        return Err(operator_only());
+    }
+
+    Ok(Json(app_state.rate_limits.stats()))
+}
+
+/// Body of the create tenant request
+#[derive(Debug, Deserialize, ToSchema)]
+struct CreateTenantRequest {
+    id: String,
+    name: String,
+}
+
+/// List Tenants
+///
+/// Returns all tenants. This API requires the admin scope of the default tenant
+#[utoipa::path(
+    get,
+    path = "/v1/admin/tenants",
+    tag = "admin",
+    responses(
+        (status = OK, body = Vec<Tenant>),
+        (status = FORBIDDEN, description = "Not an admin key of the default tenant", body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn list_tenants(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+) -> Result<Json<Vec<Tenant>>, ApiError> {
+    if !api_key.is_operator() {
+        return Err(operator_only());
+    }
+
+    let repo = app_state.repo.lock().await;
+    repo.list_tenants().await.map(Json).map_err(ApiError::from)
+}
+
+/// Create Tenant
+///
+/// Creates a tenant that devices and API keys can be bound to. The ID is used as the first level
+/// of the device topics when `MQTT_TENANT_TOPICS` is enabled, so it can't contain `/`, `+` or `#`.
+/// Create an admin key for the tenant afterwards with the create API key API. This API requires the
+/// admin scope of the default tenant
+#[utoipa::path(
+    post,
+    path = "/v1/admin/tenants",
+    tag = "admin",
+    request_body = CreateTenantRequest,
+    responses(
+        (status = CREATED, body = Tenant),
+        (status = BAD_REQUEST, description = "Invalid request", body = ApiError),
+        (status = FORBIDDEN, description = "Not an admin key of the default tenant", body = ApiError),
+        (status = CONFLICT, description = "Already exists", body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn create_tenant(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiJson(req): ApiJson<CreateTenantRequest>,
+) -> Result<(StatusCode, Json<Tenant>), ApiError> {
+    if !api_key.is_operator() {
+        return Err(operator_only());
+    }
+    if !is_valid_topic_level(&req.id) {
+        return Err(ApiError::invalid(format!("Invalid tenant ID: {}", req.id)));
+    }
+
+    let repo = app_state.repo.lock().await;
+    let tenant = repo
+        .insert_tenant(&req.id, &req.name)
+        .await
+        .map_err(ApiError::from)?;
+
+    info!("Created tenant {} '{}'", tenant.id, tenant.name);
+    Ok((StatusCode::CREATED, Json(tenant)))
+}
+
+/// Body of the register device request
+#[derive(Debug, Deserialize, ToSchema)]
+struct RegisterDeviceRequest {
+    #[serde(rename = "deviceId")]
+    device_id: String,
+    #[serde(rename = "tenantId")]
+    tenant_id: String,
+}
+
+/// Register Device
+///
+/// Binds a device to a tenant before it sent any message, so its configuration, calibrations,
+/// key or firmware can be set up front. Devices are otherwise registered to the tenant of the
+/// first message they send, the APIs that write to a device answer 404 until then. This API
+/// requires the admin scope of the default tenant
+#[utoipa::path(
+    post,
+    path = "/v1/admin/devices",
+    tag = "admin",
+    request_body = RegisterDeviceRequest,
+    responses(
+        (status = CREATED, body = Device),
+        (status = BAD_REQUEST, description = "Invalid request", body = ApiError),
+        (status = FORBIDDEN, description = "Not an admin key of the default tenant", body = ApiError),
+        (status = CONFLICT, description = "Already registered or unknown tenant", body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "Internal error", body = ApiError),
+    )
+)]
+async fn register_device(
+    State(app_state): State<AppState>,
+    Extension(api_key): Extension<ApiKey>,
+    ApiJson(req): ApiJson<RegisterDeviceRequest>,
+) -> Result<(StatusCode, Json<Device>), ApiError> {
+    if !api_key.is_operator() {
+        return Err(operator_only());
+    }
+    record_device(&req.device_id);
+    if !is_valid_topic_level(&req.device_id) {
+        return Err(ApiError::invalid(format!(
+            "Invalid device ID: {}",
+            req.device_id
+        )));
+    }
+
+    let repo = app_state.repo.lock().await;
+    let device = repo
+        .register_device(&req.tenant_id, &req.device_id)
+        .await
+        .map_err(ApiError::from)?;
+
+    info!(
+        "Registered device {} to tenant {}",
+        req.device_id, req.tenant_id
+    );
+    Ok((StatusCode::CREATED, Json(device)))
+}
+
+/// Adds the ways to pass an API key to the OpenAPI document
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKeyScheme::Header(ApiKeyValue::new(API_KEY_HEADER))),
+        );
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+/// OpenAPI document of every route, served at `/v1/openapi.json`. Routes that need an API key
+/// accept it in either the `X-API-Key` header or as a bearer token.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        version_handler,
+        live_handler,
+        ready_handler,
+        metrics_handler,
+        mqtt_connection_handler,
+        list_data,
+        list_status,
+        aggregate_data,
+        list_readings,
+        ingest_data,
+        ingest_data_batch,
+        ingest_status,
+        ingest_status_batch,
+        list_calibrations,
+        set_calibration,
+        delete_calibration,
+        recompute_calibration,
+        recompute_status,
+        send_command,
+        list_commands,
+        get_command,
+        list_configs,
+        get_config,
+        set_config,
+        list_firmware,
+        upload_firmware,
+        download_firmware,
+        assign_firmware,
+        list_firmware_updates,
+        list_devices,
+        set_device_group,
+        set_device_key,
+        delete_device_key,
+        list_rejected_messages,
+        create_api_key,
+        list_api_keys,
+        revoke_api_key,
+        rate_limit_stats,
+        list_tenants,
+        create_tenant,
+        register_device,
+    ),
+    components(schemas(RemData, RemStatus, ApiError, VersionResponse)),
+    modifiers(&SecuritySchemes),
+    security(("api_key" = []), ("bearer" = [])),
+    tags(
+        (name = "health", description = "State of the listener and its dependencies"),
+        (name = "data", description = "Data and status of the REM devices"),
+        (name = "calibration", description = "Calibration of the device channels"),
+        (name = "commands", description = "Commands sent to the devices"),
+        (name = "config", description = "Desired and reported configuration of the devices"),
+        (name = "firmware", description = "Firmware images and updates"),
+        (name = "devices", description = "Device groups, keys and rejected messages"),
+        (name = "admin", description = "API keys, tenants, device registration and rate limits"),
+    )
+)]
+struct ApiDoc;
+
 /// Server process
 ///
 /// This function creates the axum server and binds it to a TCP socket. This function
-/// is async and blocking.
+/// is async and blocking. When `shutdown` is cancelled the server stops accepting new
+/// connections, waits for the in-flight requests to finish and returns.
+#[allow(clippy::too_many_arguments)]
 pub async fn server_proc(
     config: Arc<Settings>,
-    mqtt_client: Arc<Mutex<AsyncClient>>,
-    db: Arc<Mutex<PgConnection>>,
+    firmware: Arc<FirmwareStore>,
+    publisher: MqttPublisher,
+    ingestor: Arc<Ingestor>,
+    connection: Arc<ConnectionMonitor>,
+    rate_limits: Arc<RateLimits>,
     repo: Arc<Mutex<RemRepo>>,
+    shutdown: CancellationToken,
 ) -> Result<(), anyhow::Error> {
     let addr = SocketAddr::new(IpAddr::V4(config.host), config.port);
     info!("Listening on {}", addr);
 
-    let app = Router::new()
+    let health = HealthChecker::new(config.clone(), connection.clone(), ingestor.clone());
+    let auth = Authenticator::new(repo.clone(), config.admin_api_key.as_deref());
+    let app_state = AppState {
+        config: config.clone(),
+        firmware,
+        publisher,
+        ingestor,
+        connection,
+        rate_limits: rate_limits.clone(),
+        health: Arc::new(health),
+        recalibrations: Arc::new(RecalibrationJobs::new(repo.clone())),
+        repo: repo.clone(),
+        auth: auth.clone(),
+    };
+
+    // Every group of routes requires an API key with its scope, the key is rate limited once it
+    // is known to be valid
+    let require =
+        |scope: ApiScope| middleware::from_fn_with_state((auth.clone(), scope), require_scope);
+    let limit = || middleware::from_fn_with_state(rate_limits.clone(), limit_by_key);
+
+    let public = Router::new()
         .route("/v1/version", get(version_handler))
-        .route("/v1/healthcheck", get(healthcheck_handler))
+        .route("/v1/health/live", get(live_handler))
+        .route("/v1/health/ready", get(ready_handler))
+        .route("/metrics", get(metrics_handler))
+        .route(
+            "/v1/rem/firmware/{version}/download",
+            get(download_firmware),
+        )
+        .merge(SwaggerUi::new("/v1/docs").url("/v1/openapi.json", ApiDoc::openapi()));
+
+    let read_data = Router::new()
+        .route("/v1/mqtt/connection", get(mqtt_connection_handler))
         .route("/v1/rem/data/list", get(list_data))
+        .route("/v1/rem/data/aggregate", get(aggregate_data))
         .route("/v1/rem/status/list", get(list_status))
+        .route("/v1/rem/readings", get(list_readings))
+        .route("/v1/rem/calibration/{device_id}", get(list_calibrations))
+        .route(
+            "/v1/rem/calibration/{device_id}/recompute/{job_id}",
+            get(recompute_status),
+        )
+        .route("/v1/rem/command/{device_id}", get(list_commands))
+        .route("/v1/rem/command/{device_id}/{command_id}", get(get_command))
+        .route("/v1/rem/config", get(list_configs))
+        .route("/v1/rem/config/{device_id}", get(get_config))
+        .route("/v1/rem/firmware", get(list_firmware))
+        .route("/v1/rem/firmware/updates", get(list_firmware_updates))
+        .route("/v1/rem/devices", get(list_devices))
+        .route("/v1/rem/rejected", get(list_rejected_messages))
+        .route_layer(limit())
+        .route_layer(require(ApiScope::ReadData));
+
+    let write_data = Router::new()
+        .route("/v1/rem/data", post(ingest_data))
+        .route("/v1/rem/data/batch", post(ingest_data_batch))
+        .route("/v1/rem/status", post(ingest_status))
+        .route("/v1/rem/status/batch", post(ingest_status_batch))
+        .route_layer(limit())
+        .route_layer(require(ApiScope::WriteData));
+
+    let device_command = Router::new()
+        .route("/v1/rem/command/{device_id}", post(send_command))
+        .route("/v1/rem/config/{device_id}", put(set_config))
+        .route("/v1/rem/firmware/{version}/assign", post(assign_firmware))
+        .route_layer(limit())
+        .route_layer(require(ApiScope::DeviceCommand));
+
+    let admin = Router::new()
+        .route(
+            "/v1/rem/calibration/{device_id}/{field}",
+            put(set_calibration).delete(delete_calibration),
+        )
+        .route(
+            "/v1/rem/calibration/{device_id}/recompute",
+            post(recompute_calibration),
+        )
+        .route(
+            "/v1/rem/firmware/{version}",
+            post(upload_firmware).layer(DefaultBodyLimit::max(MAX_FIRMWARE_SIZE)),
+        )
+        .route("/v1/rem/devices/{device_id}/group", put(set_device_group))
+        .route(
+            "/v1/rem/devices/{device_id}/key",
+            put(set_device_key).delete(delete_device_key),
+        )
+        .route("/v1/admin/keys", get(list_api_keys).post(create_api_key))
+        .route("/v1/admin/keys/{id}", delete(revoke_api_key))
+        .route("/v1/admin/tenants", get(list_tenants).post(create_tenant))
+        .route("/v1/admin/devices", post(register_device))
+        .route("/v1/admin/rate-limits", get(rate_limit_stats))
+        .route_layer(limit())
+        .route_layer(require(ApiScope::Admin));
+
+    let app = Router::new()
+        .merge(public)
+        .merge(read_data)
+        .merge(write_data)
+        .merge(device_command)
+        .merge(admin)
         .fallback(default_handler)
-        .with_state(AppState {
-            mqtt_client,
-            db,
-            repo,
-        });
+        .layer(middleware::from_fn_with_state(rate_limits, limit_by_ip))
+        .layer(middleware::from_fn(prometheus::track_http))
+        .layer(middleware::from_fn(trace_http))
+        .with_state(app_state);
 
     loop {
         // TCP listener fails to be instantiated when we already are binding to that address or we run out of memory
@@ -160,7 +2085,20 @@ pub async fn server_proc(
         let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
 
         // App can be finiky some times, so its better to keep retrying on setting it up rather then panicic.
-        if let Err(e) = axum::serve(listener, app.clone()).await {
+        // The client address is needed for the per-IP rate limit
+        let app = app
+            .clone()
+            .into_make_service_with_connect_info::<SocketAddr>();
+        let res = axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown.clone().cancelled_owned())
+            .await;
+
+        if shutdown.is_cancelled() {
+            info!("API server stopped");
+            return Ok(());
+        }
+
+        if let Err(e) = res {
             error!(
                 "Failed to setup axum server with error, retrying after 1 second: {}",
                 e
@@ -0,0 +1,133 @@
+//! API key authentication.
+//!
+//! Every route except the public ones requires an API key with a scope, sent either as a bearer
+//! token or in the `X-API-Key` header. Keys are random and only their SHA-256 hash is stored, so a
+//! leaked database doesn't leak usable keys. The `ADMIN_API_KEY` from the settings is accepted
+//! with the admin scope, so the first keys can be created through the API.
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::{
+    error::{ApiError, ErrorCode},
+    model::{ApiKey, ApiScope, DEFAULT_TENANT},
+    repo::RemRepo,
+};
+
+/// Start of every generated key, makes them easy to spot in configuration and secret scanners
+const API_KEY_PREFIX: &str = "rem_";
+
+/// Length of the start of a key that is stored to recognize it by
+const API_KEY_PREFIX_LEN: usize = 12;
+
+/// Header API keys can be sent in, instead of the authorization header
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Generate a new random API key
+pub fn generate_key() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format!("{}{}", API_KEY_PREFIX, hex::encode(bytes))
+}
+
+/// Hex encoded SHA-256 hash of a key, the form keys are stored and looked up in
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// Start of a key that is shown in listings
+pub fn key_prefix(key: &str) -> String {
+    key.chars().take(API_KEY_PREFIX_LEN).collect()
+}
+
+/// The API key of a request, from either the authorization or the API key header.
+pub(crate) fn key_from_headers(headers: &HeaderMap) -> Option<&str> {
+    if let Some(key) = headers
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+    {
+        return Some(key);
+    }
+
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Looks up the API keys of requests
+#[derive(Clone)]
+pub struct Authenticator {
+    repo: Arc<Mutex<RemRepo>>,
+    admin_key_hash: Option<String>,
+}
+
+impl Authenticator {
+    pub fn new(repo: Arc<Mutex<RemRepo>>, admin_key: Option<&str>) -> Self {
+        Authenticator {
+            repo,
+            admin_key_hash: admin_key.map(hash_key),
+        }
+    }
+
+    /// The API key matching `key`, if it exists and isn't revoked
+    pub async fn authenticate(&self, key: &str) -> Result<Option<ApiKey>, Response> {
+        let hash = hash_key(key);
+        if self.admin_key_hash.as_deref() == Some(hash.as_str()) {
+            return Ok(Some(ApiKey {
+                id: "admin".to_string(),
+                name: "ADMIN_API_KEY".to_string(),
+                tenant_id: DEFAULT_TENANT.to_string(),
+                prefix: key_prefix(key),
+                scopes: vec![ApiScope::Admin],
+                created_at: chrono::Utc::now().naive_utc(),
+                revoked_at: None,
+            }));
+        }
+
+        self.repo
+            .lock()
+            .await
+            .find_api_key(&hash)
+            .await
+            .map_err(|err| ApiError::from(err).into_response())
+    }
+}
+
+/// Middleware that rejects requests without an API key that grants the scope. The key is added
+/// to the request extensions for the handlers.
+pub async fn require_scope(
+    State((auth, scope)): State<(Authenticator, ApiScope)>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let Some(key) = key_from_headers(req.headers()) else {
+        return ApiError::new(ErrorCode::Unauthorized, "Missing API key").into_response();
+    };
+
+    let api_key = match auth.authenticate(key).await {
+        Ok(Some(api_key)) => api_key,
+        Ok(None) => {
+            warn!("Rejected request to {} with an invalid API key", req.uri());
+            return ApiError::new(ErrorCode::Unauthorized, "Invalid API key").into_response();
+        }
+        Err(response) => return response,
+    };
+
+    if !api_key.allows(scope) {
+        let message = format!("API key doesn't have the {} scope", scope.as_str());
+        return ApiError::new(ErrorCode::Forbidden, message).into_response();
+    }
+
+    req.extensions_mut().insert(api_key);
+    next.run(req).await
+}
@@ -0,0 +1,1203 @@
+//! Embedded MQTT broker for single-box deployments.
+//!
+//! With `MQTT_MODE=embedded` the listener doesn't connect to an external broker, the devices
+//! connect to the listener directly instead. Messages on the topics of the registered handlers are
+//! journaled in the inbox and handed to the ingestor without going through a network client, and
+//! the acknowledgement to the device is only sent once the message is journaled.
+//!
+//! The broker is deliberately small. It supports MQTT 5 clients, QoS 0 and 1, retained messages,
+//! wildcard subscriptions and last wills. Every connection starts a clean session, QoS 1 messages
+//! to clients aren't redelivered, and shared subscriptions aren't supported. Use an external broker
+//! when any of that is needed.
+//!
+//! Every client is a device, and the client ID is its device ID. A client may publish its data and
+//! status and to its own `rem/{client_id}/...` topics, and only subscribe to its own topics and the
+//! status of the listener. Everything else is refused as not authorized.
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use paho_mqtt::{Message, MessageBuilder, Properties, PropertyCode, QOS_0, QOS_1};
+use rumqttc::v5::mqttbytes::{
+    self,
+    v5::{
+        ConnAck, ConnAckProperties, ConnectReturnCode, Disconnect, DisconnectReasonCode, Filter,
+        LastWill, Login, Packet, PingResp, PubAck, PubAckReason, Publish, PublishProperties,
+        SubAck, SubscribeReasonCode, UnsubAck, UnsubAckReason,
+    },
+    valid_filter, QoS,
+};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    select,
+    sync::{
+        mpsc::{self, Sender, UnboundedReceiver, UnboundedSender},
+        Mutex as AsyncMutex,
+    },
+    task::spawn_blocking,
+    time::{interval, timeout, MissedTickBehavior},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    connection::{ConnectionMonitor, ConnectionState},
+    firmware::download_url,
+    inbox::{Inbox, InboxEntry},
+    ingest::{Ingestor, STALLED_RETRY_INTERVAL},
+    model::FirmwareUpdateStatus,
+    mqtt::{
+        config_message, listener_status_message, ota_message, ListenerState, MESSAGE_QUEUE_SIZE,
+    },
+    repo::{RemRepo, RemRepoError},
+    settings::Settings,
+    topic::{
+        device_id_from_topic, is_valid_topic_level, replica_status_topic, topic_matches,
+        REM_DATA_TOPIC, REM_LISTENER_STATUS_TOPIC, REM_STATUS_TOPIC,
+    },
+};
+
+/// Largest packet accepted from a client
+const MAX_PACKET_SIZE: usize = 1024 * 1024;
+
+/// Time a client has to send its CONNECT packet after opening the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Keep alive used for clients that disable it, so dead connections are cleaned up eventually
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(300);
+
+#[derive(Error, Debug)]
+pub enum BrokerError {
+    #[error("Connection error: {}", .0)]
+    Io(#[from] std::io::Error),
+    #[error("Protocol error: {}", .0)]
+    Protocol(#[from] mqttbytes::Error),
+    #[error("Connection closed")]
+    Closed,
+    #[error("Timed out waiting for the client")]
+    Timeout,
+}
+
+/// Receives the messages on the topics the listener handles itself. Messages are journaled in the
+/// inbox on a blocking thread, then the entries are queued for the ingestor.
+#[derive(Clone)]
+pub struct LocalHandler {
+    pub inbox: Arc<Inbox>,
+    pub tx: Sender<InboxEntry>,
+}
+
+/// A subscription of a connected client
+struct Subscription {
+    filter: String,
+    qos: QoS,
+    retain_as_published: bool,
+}
+
+/// A connected client
+struct Session {
+    client_id: String,
+    tx: UnboundedSender<Packet>,
+    subscriptions: Vec<Subscription>,
+    next_pkid: u16,
+}
+
+impl Session {
+    fn next_pkid(&mut self) -> u16 {
+        self.next_pkid = self.next_pkid.checked_add(1).unwrap_or(1);
+        self.next_pkid
+    }
+}
+
+#[derive(Default)]
+struct State {
+    sessions: HashMap<u64, Session>,
+    retained: BTreeMap<String, Publish>,
+}
+
+/// In-process MQTT broker.
+pub struct Broker {
+    state: Mutex<State>,
+    next_session: AtomicU64,
+
+    /// Topic filters of the messages handed to the local handler
+    local_filters: Vec<String>,
+    local: Mutex<Option<LocalHandler>>,
+
+    /// Credentials clients have to connect with, if any
+    credentials: Option<Login>,
+
+    /// Whether the first level of the device topics is the tenant of the device
+    tenant_topics: bool,
+}
+
+impl Broker {
+    /// Create a broker that hands the messages matching `local_filters` to the local handler.
+    pub fn new(local_filters: Vec<String>, credentials: Option<Login>) -> Self {
+        Broker {
+            state: Mutex::new(State::default()),
+            next_session: AtomicU64::new(1),
+            local_filters,
+            local: Mutex::new(None),
+            credentials,
+            tenant_topics: false,
+        }
+    }
+
+    /// Expect the ID of the tenant as the first level of the device topics.
+    pub fn tenant_topics(mut self, enabled: bool) -> Self {
+        self.tenant_topics = enabled;
+        self
+    }
+
+    /// The topic or topic filter without the tenant level, if the device topics have one. A
+    /// wildcard tenant level isn't accepted.
+    fn device_topic<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        match self.tenant_topics {
+            true => topic
+                .split_once('/')
+                .filter(|(tenant, _)| is_valid_topic_level(tenant))
+                .map(|(_, topic)| topic),
+            false => Some(topic),
+        }
+    }
+
+    /// Whether a client may publish to a topic. Devices publish their data and status to the
+    /// topics shared by all devices, anything else has to be one of their own topics.
+    fn may_publish(&self, client_id: &str, topic: &str) -> bool {
+        self.device_topic(topic).is_some_and(|topic| {
+            topic == REM_DATA_TOPIC || topic == REM_STATUS_TOPIC || is_own_topic(client_id, topic)
+        })
+    }
+
+    /// Whether a client may subscribe to a topic filter. Devices only get their own topics and
+    /// the status of the listener.
+    fn may_subscribe(&self, client_id: &str, filter: &str) -> bool {
+        if filter == REM_LISTENER_STATUS_TOPIC || filter == replica_status_topic("+") {
+            return true;
+        }
+        self.device_topic(filter)
+            .is_some_and(|filter| is_own_topic(client_id, filter))
+    }
+
+    /// Set the handler for the messages on the local topic filters. A message is only acknowledged
+    /// once it is journaled and queued, while the queue is full its client isn't read from.
+    pub fn set_local_handler(&self, handler: LocalHandler) {
+        *lock(&self.local) = Some(handler);
+    }
+
+    /// Publish a message of the listener itself to the connected clients.
+    pub fn publish_message(&self, msg: &Message) {
+        self.route(publish_from_message(msg));
+    }
+
+    /// Publish a message of a client. Messages on the local topic filters are handed to the local
+    /// handler first, this returns once they are journaled so the client can be acknowledged.
+    async fn publish_from_client(&self, publish: Publish) {
+        let topic = String::from_utf8_lossy(&publish.topic).to_string();
+
+        // The local handler only gets the messages of the devices, not the ones we publish
+        let handler = match self.local_filters.iter().any(|f| topic_matches(f, &topic)) {
+            true => lock(&self.local).clone(),
+            false => None,
+        };
+        if let Some(LocalHandler { inbox, tx }) = handler {
+            // Journaling syncs the file, which mustn't block the runtime
+            let msg = message_from_publish(&topic, &publish);
+            match spawn_blocking(move || inbox.entry(msg)).await {
+                // The receiver only goes away when the broker routine stopped, the message is in
+                // the inbox by then and replayed on the next start
+                Ok(entry) => {
+                    let _ = tx.send(entry).await;
+                }
+                Err(e) => error!("Failed to journal the message on '{}': {}", topic, e),
+            }
+        }
+
+        self.route(publish);
+    }
+
+    /// Deliver a message to the matching subscriptions, and update the retained message of the
+    /// topic.
+    fn route(&self, publish: Publish) {
+        let topic = String::from_utf8_lossy(&publish.topic).to_string();
+
+        let mut state = lock(&self.state);
+        if publish.retain {
+            if publish.payload.is_empty() {
+                state.retained.remove(&topic);
+            } else {
+                state.retained.insert(topic.clone(), publish.clone());
+            }
+        }
+
+        for session in state.sessions.values_mut() {
+            let Some(sub) = session
+                .subscriptions
+                .iter()
+                .filter(|s| topic_matches(&s.filter, &topic))
+                .max_by_key(|s| s.qos as u8)
+            else {
+                continue;
+            };
+
+            let mut out = publish.clone();
+            out.qos = min_qos(publish.qos, sub.qos);
+            out.retain = publish.retain && sub.retain_as_published;
+            out.dup = false;
+            out.pkid = if out.qos == QoS::AtMostOnce {
+                0
+            } else {
+                session.next_pkid()
+            };
+            let _ = session.tx.send(Packet::Publish(out));
+        }
+    }
+
+    /// Register a connected client, disconnecting any other connection with the same client ID.
+    fn add_session(&self, client_id: &str, tx: UnboundedSender<Packet>) -> u64 {
+        let id = self.next_session.fetch_add(1, Ordering::Relaxed);
+        let mut state = lock(&self.state);
+
+        state.sessions.retain(|_, session| {
+            if session.client_id == client_id {
+                info!(
+                    "Client '{}' connected again, closing its old connection",
+                    client_id
+                );
+                let _ = session.tx.send(Packet::Disconnect(Disconnect::new(
+                    DisconnectReasonCode::SessionTakenOver,
+                )));
+                false
+            } else {
+                true
+            }
+        });
+
+        state.sessions.insert(
+            id,
+            Session {
+                client_id: client_id.to_string(),
+                tx,
+                subscriptions: Vec::new(),
+                next_pkid: 0,
+            },
+        );
+        id
+    }
+
+    fn remove_session(&self, id: u64) {
+        lock(&self.state).sessions.remove(&id);
+    }
+
+    /// Add the subscriptions of a client and acknowledge them, then send it the matching retained
+    /// messages.
+    fn subscribe(&self, id: u64, pkid: u16, filters: Vec<Filter>) {
+        let mut state = lock(&self.state);
+        let State { sessions, retained } = &mut *state;
+        let Some(session) = sessions.get_mut(&id) else {
+            return;
+        };
+
+        let mut codes = Vec::with_capacity(filters.len());
+        let mut retained_publishes = Vec::new();
+        for filter in filters {
+            if filter.path.starts_with("$share/") {
+                codes.push(SubscribeReasonCode::SharedSubscriptionsNotSupported);
+                continue;
+            }
+            if !valid_filter(&filter.path) {
+                codes.push(SubscribeReasonCode::TopicFilterInvalid);
+                continue;
+            }
+            if !self.may_subscribe(&session.client_id, &filter.path) {
+                warn!(
+                    "Refusing subscription of client '{}' to '{}'",
+                    session.client_id, filter.path
+                );
+                codes.push(SubscribeReasonCode::NotAuthorized);
+                continue;
+            }
+
+            let qos = min_qos(filter.qos, QoS::AtLeastOnce);
+            session.subscriptions.retain(|s| s.filter != filter.path);
+            session.subscriptions.push(Subscription {
+                filter: filter.path.clone(),
+                qos,
+                retain_as_published: filter.preserve_retain,
+            });
+            codes.push(SubscribeReasonCode::Success(qos));
+
+            for (topic, publish) in retained.iter() {
+                if topic_matches(&filter.path, topic) {
+                    let mut out = publish.clone();
+                    out.qos = min_qos(publish.qos, qos);
+                    out.retain = true;
+                    out.pkid = if out.qos == QoS::AtMostOnce {
+                        0
+                    } else {
+                        session.next_pkid()
+                    };
+                    retained_publishes.push(out);
+                }
+            }
+        }
+
+        // Clients expect the SUBACK before the retained messages, the channel keeps the order
+        let _ = session.tx.send(Packet::SubAck(SubAck {
+            pkid,
+            return_codes: codes,
+            properties: None,
+        }));
+        for publish in retained_publishes {
+            let _ = session.tx.send(Packet::Publish(publish));
+        }
+    }
+
+    fn unsubscribe(&self, id: u64, filters: &[String]) -> Vec<UnsubAckReason> {
+        let mut state = lock(&self.state);
+        let Some(session) = state.sessions.get_mut(&id) else {
+            return Vec::new();
+        };
+
+        filters
+            .iter()
+            .map(|filter| {
+                let before = session.subscriptions.len();
+                session.subscriptions.retain(|s| &s.filter != filter);
+                if session.subscriptions.len() < before {
+                    UnsubAckReason::Success
+                } else {
+                    UnsubAckReason::NoSubscriptionExisted
+                }
+            })
+            .collect()
+    }
+
+    /// Accept connections until `shutdown` is cancelled.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener, shutdown: CancellationToken) {
+        loop {
+            let (stream, addr) = select! {
+                res = listener.accept() => match res {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        error!("Failed to accept a connection: {}", e);
+                        continue;
+                    }
+                },
+                _ = shutdown.cancelled() => return,
+            };
+
+            debug!("Accepted connection from {}", addr);
+            let broker = self.clone();
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                if let Err(e) = broker.handle_connection(stream, shutdown).await {
+                    debug!("Connection from {} closed: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    async fn handle_connection(
+        self: Arc<Self>,
+        stream: TcpStream,
+        shutdown: CancellationToken,
+    ) -> Result<(), BrokerError> {
+        let (mut reader, mut writer) = stream.into_split();
+        let mut buf = BytesMut::with_capacity(4096);
+
+        let packet = match timeout(CONNECT_TIMEOUT, read_packet(&mut reader, &mut buf)).await {
+            Ok(Ok(packet)) => packet,
+            Ok(Err(BrokerError::Protocol(mqttbytes::Error::InvalidProtocolLevel(level)))) => {
+                // Refuse in the format older clients understand, the v3.1.1 CONNACK
+                warn!("Refusing client with MQTT protocol level {}", level);
+                writer.write_all(&[0x20, 0x02, 0x00, 0x01]).await?;
+                return Ok(());
+            }
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(BrokerError::Timeout),
+        };
+        let Packet::Connect(connect, will, login) = packet else {
+            return Err(BrokerError::Protocol(
+                mqttbytes::Error::IncorrectPacketFormat,
+            ));
+        };
+
+        if self.credentials.is_some() && self.credentials != login {
+            warn!(
+                "Refusing client '{}' with invalid credentials",
+                connect.client_id
+            );
+            write_packet(
+                &mut writer,
+                &connack(ConnectReturnCode::BadUserNamePassword, None),
+            )
+            .await?;
+            return Ok(());
+        }
+
+        // Clients may leave the ID to the server
+        let assigned_id = connect
+            .client_id
+            .is_empty()
+            .then(|| uuid::Uuid::new_v4().simple().to_string());
+        let client_id = assigned_id.clone().unwrap_or(connect.client_id);
+
+        // The client ID is used as a topic level, and can't be mistaken for the listener status
+        if !is_valid_topic_level(&client_id)
+            || device_id_from_topic(REM_LISTENER_STATUS_TOPIC) == Some(client_id.as_str())
+        {
+            warn!("Refusing client with invalid ID '{}'", client_id);
+            write_packet(
+                &mut writer,
+                &connack(ConnectReturnCode::ClientIdentifierNotValid, None),
+            )
+            .await?;
+            return Ok(());
+        }
+        if let Some(will) = &will {
+            let topic = String::from_utf8_lossy(&will.topic);
+            if !self.may_publish(&client_id, &topic) {
+                warn!(
+                    "Refusing client '{}' with a last will on '{}'",
+                    client_id, topic
+                );
+                write_packet(
+                    &mut writer,
+                    &connack(ConnectReturnCode::NotAuthorized, None),
+                )
+                .await?;
+                return Ok(());
+            }
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let id = self.add_session(&client_id, tx.clone());
+        info!("Client '{}' connected", client_id);
+
+        write_packet(
+            &mut writer,
+            &connack(ConnectReturnCode::Success, assigned_id),
+        )
+        .await?;
+        let writer_handle = tokio::spawn(write_loop(writer, rx));
+
+        let keep_alive = match connect.keep_alive {
+            0 => DEFAULT_KEEP_ALIVE,
+            secs => Duration::from_secs(secs as u64) * 3 / 2,
+        };
+
+        let res = self
+            .read_loop(
+                id,
+                &client_id,
+                &mut reader,
+                &mut buf,
+                &tx,
+                keep_alive,
+                &shutdown,
+            )
+            .await;
+        self.remove_session(id);
+
+        // The last will is published unless the client disconnected cleanly, or we shut down
+        let publish_will = match &res {
+            Ok(reason) => *reason == DisconnectReasonCode::DisconnectWithWillMessage,
+            Err(_) => !shutdown.is_cancelled(),
+        };
+        if publish_will {
+            if let Some(will) = will {
+                self.publish_from_client(publish_from_will(will)).await;
+            }
+        }
+
+        info!("Client '{}' disconnected", client_id);
+        drop(tx);
+        let _ = writer_handle.await;
+        res.map(|_| ())
+    }
+
+    /// Handle the packets of a connected client. Returns the reason of a clean disconnect.
+    #[allow(clippy::too_many_arguments)]
+    async fn read_loop(
+        &self,
+        id: u64,
+        client_id: &str,
+        reader: &mut OwnedReadHalf,
+        buf: &mut BytesMut,
+        tx: &UnboundedSender<Packet>,
+        keep_alive: Duration,
+        shutdown: &CancellationToken,
+    ) -> Result<DisconnectReasonCode, BrokerError> {
+        loop {
+            let packet = select! {
+                res = timeout(keep_alive, read_packet(reader, buf)) => match res {
+                    Ok(packet) => packet?,
+                    Err(_) => {
+                        let _ = tx.send(disconnect(DisconnectReasonCode::KeepAliveTimeout));
+                        return Err(BrokerError::Timeout);
+                    }
+                },
+                _ = shutdown.cancelled() => {
+                    let _ = tx.send(disconnect(DisconnectReasonCode::ServerShuttingDown));
+                    return Ok(DisconnectReasonCode::ServerShuttingDown);
+                }
+            };
+
+            match packet {
+                Packet::Publish(publish) => {
+                    if publish.qos == QoS::ExactlyOnce {
+                        let _ = tx.send(disconnect(DisconnectReasonCode::QoSNotSupported));
+                        return Ok(DisconnectReasonCode::QoSNotSupported);
+                    }
+                    if publish.topic.is_empty()
+                        || publish.topic.iter().any(|b| matches!(b, b'+' | b'#'))
+                    {
+                        let _ = tx.send(disconnect(DisconnectReasonCode::TopicNameInvalid));
+                        return Ok(DisconnectReasonCode::TopicNameInvalid);
+                    }
+
+                    let (qos, pkid) = (publish.qos, publish.pkid);
+                    let topic = String::from_utf8_lossy(&publish.topic).to_string();
+                    let mut ack = PubAck::new(pkid, None);
+                    if self.may_publish(client_id, &topic) {
+                        self.publish_from_client(publish).await;
+                    } else {
+                        warn!("Refusing message of client '{}' on '{}'", client_id, topic);
+                        ack.reason = PubAckReason::NotAuthorized;
+                    }
+                    if qos == QoS::AtLeastOnce {
+                        let _ = tx.send(Packet::PubAck(ack));
+                    }
+                }
+                Packet::Subscribe(subscribe) => {
+                    self.subscribe(id, subscribe.pkid, subscribe.filters);
+                }
+                Packet::Unsubscribe(unsubscribe) => {
+                    let reasons = self.unsubscribe(id, &unsubscribe.filters);
+                    let _ = tx.send(Packet::UnsubAck(UnsubAck {
+                        pkid: unsubscribe.pkid,
+                        reasons,
+                        properties: None,
+                    }));
+                }
+                Packet::PingReq(_) => {
+                    let _ = tx.send(Packet::PingResp(PingResp));
+                }
+                Packet::Disconnect(disconnect) => return Ok(disconnect.reason_code),
+                // Acknowledgements of the messages we sent, nothing is redelivered
+                Packet::PubAck(_) => {}
+                _ => {
+                    let _ = tx.send(disconnect(DisconnectReasonCode::ProtocolError));
+                    return Ok(DisconnectReasonCode::ProtocolError);
+                }
+            }
+        }
+    }
+}
+
+/// Read the next packet from the connection, waiting for more data while it's incomplete.
+async fn read_packet(
+    reader: &mut OwnedReadHalf,
+    buf: &mut BytesMut,
+) -> Result<Packet, BrokerError> {
+    loop {
+        match Packet::read(buf, Some(MAX_PACKET_SIZE)) {
+            Ok(packet) => return Ok(packet),
+            Err(mqttbytes::Error::InsufficientBytes(_)) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        if reader.read_buf(buf).await? == 0 {
+            return Err(BrokerError::Closed);
+        }
+    }
+}
+
+async fn write_packet(writer: &mut OwnedWriteHalf, packet: &Packet) -> Result<(), BrokerError> {
+    let mut buf = BytesMut::new();
+    packet.write(&mut buf)?;
+    writer.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Write the packets queued for a client until the channel closes or we disconnect it.
+async fn write_loop(mut writer: OwnedWriteHalf, mut rx: UnboundedReceiver<Packet>) {
+    while let Some(packet) = rx.recv().await {
+        let is_disconnect = matches!(packet, Packet::Disconnect(_));
+        if let Err(e) = write_packet(&mut writer, &packet).await {
+            debug!("Failed to write to client: {}", e);
+            break;
+        }
+        if is_disconnect {
+            break;
+        }
+    }
+
+    let _ = writer.shutdown().await;
+}
+
+fn connack(code: ConnectReturnCode, assigned_client_id: Option<String>) -> Packet {
+    Packet::ConnAck(ConnAck {
+        session_present: false,
+        code,
+        properties: Some(ConnAckProperties {
+            session_expiry_interval: None,
+            receive_max: None,
+            max_qos: Some(1),
+            retain_available: Some(1),
+            max_packet_size: Some(MAX_PACKET_SIZE as u32),
+            assigned_client_identifier: assigned_client_id,
+            topic_alias_max: None,
+            reason_string: None,
+            user_properties: Vec::new(),
+            wildcard_subscription_available: Some(1),
+            subscription_identifiers_available: Some(0),
+            shared_subscription_available: Some(0),
+            server_keep_alive: None,
+            response_information: None,
+            server_reference: None,
+            authentication_method: None,
+            authentication_data: None,
+        }),
+    })
+}
+
+/// Whether a topic or topic filter is below the `rem/{client_id}/` topics of a device
+fn is_own_topic(client_id: &str, topic: &str) -> bool {
+    topic
+        .strip_prefix("rem/")
+        .and_then(|topic| topic.strip_prefix(client_id))
+        .is_some_and(|rest| rest.starts_with('/'))
+}
+
+fn disconnect(reason: DisconnectReasonCode) -> Packet {
+    Packet::Disconnect(Disconnect::new(reason))
+}
+
+fn min_qos(a: QoS, b: QoS) -> QoS {
+    if (a as u8) <= (b as u8) {
+        a
+    } else {
+        b
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    // The state is never left inconsistent, so a poisoned lock is safe to reuse
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Convert a message of the listener to a packet, keeping the properties the devices use.
+fn publish_from_message(msg: &Message) -> Publish {
+    let props = msg.properties();
+    let response_topic = props.get_string(PropertyCode::ResponseTopic);
+    let correlation_data = props
+        .get_binary(PropertyCode::CorrelationData)
+        .map(Bytes::from);
+
+    let properties =
+        (response_topic.is_some() || correlation_data.is_some()).then(|| PublishProperties {
+            response_topic,
+            correlation_data,
+            ..Default::default()
+        });
+
+    let qos = if msg.qos() == QOS_0 {
+        QoS::AtMostOnce
+    } else {
+        QoS::AtLeastOnce
+    };
+
+    let mut publish = Publish::new(msg.topic(), qos, msg.payload().to_vec(), properties);
+    publish.retain = msg.retained();
+    publish
+}
+
+fn publish_from_will(will: LastWill) -> Publish {
+    Publish {
+        dup: false,
+        qos: min_qos(will.qos, QoS::AtLeastOnce),
+        retain: will.retain,
+        topic: will.topic,
+        pkid: 0,
+        payload: will.message,
+        properties: None,
+    }
+}
+
+/// Convert a packet of a client to the message type the handlers work with.
+fn message_from_publish(topic: &str, publish: &Publish) -> Message {
+    let mut props = Properties::new();
+    if let Some(properties) = &publish.properties {
+        if let Some(data) = &properties.correlation_data {
+            let _ = props.push_binary(PropertyCode::CorrelationData, data.to_vec());
+        }
+        if let Some(response_topic) = &properties.response_topic {
+            let _ = props.push_string(PropertyCode::ResponseTopic, response_topic);
+        }
+        for (key, value) in &properties.user_properties {
+            let _ = props.push_string_pair(PropertyCode::UserProperty, key, value);
+        }
+    }
+
+    let qos = match publish.qos {
+        QoS::AtMostOnce => QOS_0,
+        _ => QOS_1,
+    };
+
+    MessageBuilder::new()
+        .topic(topic)
+        .payload(publish.payload.to_vec())
+        .qos(qos)
+        .retained(publish.retain)
+        .properties(props)
+        .finalize()
+}
+
+/// Publish the retained configurations and firmware update notifications stored in the database.
+/// The broker keeps retained messages in memory only, so without this the devices that connect
+/// after a restart wouldn't get them. Returns the number of messages published.
+async fn restore_retained(
+    broker: &Broker,
+    repo: &AsyncMutex<RemRepo>,
+    config: &Settings,
+) -> Result<usize, RemRepoError> {
+    let repo = repo.lock().await;
+    let mut images = HashMap::new();
+    let mut count = 0;
+
+    for tenant in repo.list_tenants().await? {
+        let topic_tenant = config.mqtt_tenant_topics.then_some(tenant.id.as_str());
+
+        for state in repo.list_config_states(&tenant.id).await? {
+            if let Some(desired) = &state.desired {
+                broker.publish_message(&config_message(topic_tenant, &state.device_id, desired));
+                count += 1;
+            }
+        }
+
+        // Updates that are still assigned were never published, assigning them again does that
+        for update in repo.list_firmware_updates(&tenant.id, None).await? {
+            if update.status == FirmwareUpdateStatus::Assigned {
+                continue;
+            }
+            if !images.contains_key(&update.target_version) {
+                let image = repo.get_firmware_image(&update.target_version).await?;
+                images.insert(update.target_version.clone(), image);
+            }
+            let Some(Some(image)) = images.get(&update.target_version) else {
+                continue;
+            };
+
+            let url = download_url(config, &image.version);
+            broker.publish_message(&ota_message(topic_tenant, &update.device_id, image, &url));
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+/// Embedded broker process
+///
+/// Runs the embedded broker in place of the connection to an external one. The messages on the
+/// topics of the registered handlers are journaled and passed to the ingestor, starting with the
+/// `replay` entries left in the inbox by a previous run. The entries that couldn't be stored yet
+/// are retried periodically.
+///
+/// The broker keeps retained messages in memory only, so the desired configurations and firmware
+/// update notifications are published again from the database before clients can connect.
+///
+/// When `shutdown` is cancelled the offline status is published to the connected clients, then
+/// they are disconnected and the messages that were already received are handled.
+pub async fn broker_proc(
+    broker: Arc<Broker>,
+    ingestor: Arc<Ingestor>,
+    repo: Arc<AsyncMutex<RemRepo>>,
+    replay: Vec<InboxEntry>,
+    monitor: Arc<ConnectionMonitor>,
+    config: Arc<Settings>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    if !replay.is_empty() {
+        info!("Replaying {} messages from the inbox", replay.len());
+        for entry in replay {
+            ingestor.process(entry, &shutdown).await;
+        }
+    }
+
+    let (tx, mut rx) = mpsc::channel(MESSAGE_QUEUE_SIZE);
+    broker.set_local_handler(LocalHandler {
+        inbox: ingestor.inbox().clone(),
+        tx,
+    });
+
+    let addr = SocketAddr::new(IpAddr::V4(config.mqtt_broker_host), config.mqtt_broker_port);
+    let listener = TcpListener::bind(addr).await?;
+    info!("Embedded MQTT broker listening on {}", addr);
+
+    match restore_retained(&broker, &repo, &config).await {
+        Ok(count) => info!("Restored {} retained messages from the database", count),
+        Err(e) => error!("Failed to restore the retained messages: {}", e),
+    }
+
+    monitor.transition(ConnectionState::Connected);
+    broker.publish_message(&listener_status_message(
+        REM_LISTENER_STATUS_TOPIC,
+        ListenerState::Online,
+    ));
+
+    // The clients are only disconnected once they were told we're going offline
+    let stop_server = CancellationToken::new();
+    let server = tokio::spawn(broker.clone().serve(listener, stop_server.clone()));
+
+    let mut retry = interval(STALLED_RETRY_INTERVAL);
+    retry.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        select! {
+            Some(entry) = rx.recv() => {
+                ingestor.process(entry, &shutdown).await;
+            }
+            _ = retry.tick() => ingestor.retry_stalled(&shutdown).await,
+            _ = shutdown.cancelled() => break,
+        }
+    }
+
+    broker.publish_message(&listener_status_message(
+        REM_LISTENER_STATUS_TOPIC,
+        ListenerState::Offline,
+    ));
+    stop_server.cancel();
+
+    // Handle whatever was already received, anything that doesn't make it stays in the inbox
+    info!("Shutting down, handling the remaining received messages");
+    let _ = server.await;
+    while let Ok(entry) = rx.try_recv() {
+        ingestor.process(entry, &shutdown).await;
+    }
+
+    monitor.transition(ConnectionState::Disconnected);
+    info!("Embedded MQTT broker stopped");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rumqttc::v5::mqttbytes::v5::{Connect, Subscribe};
+
+    const USERNAME: &str = "devices";
+    const PASSWORD: &str = "secret";
+
+    /// A client of the broker under test
+    struct TestClient {
+        reader: OwnedReadHalf,
+        writer: OwnedWriteHalf,
+        buf: BytesMut,
+    }
+
+    impl TestClient {
+        async fn open(addr: SocketAddr) -> Self {
+            let stream = TcpStream::connect(addr)
+                .await
+                .expect("connect to the broker");
+            let (reader, writer) = stream.into_split();
+            TestClient {
+                reader,
+                writer,
+                buf: BytesMut::new(),
+            }
+        }
+
+        /// Connect and return the CONNACK
+        async fn connect(
+            addr: SocketAddr,
+            client_id: &str,
+            login: Option<Login>,
+            will: Option<LastWill>,
+        ) -> (Self, ConnAck) {
+            let mut client = TestClient::open(addr).await;
+            let connect = Connect {
+                keep_alive: 30,
+                client_id: client_id.to_string(),
+                clean_start: true,
+                properties: None,
+            };
+            client.send(Packet::Connect(connect, will, login)).await;
+            match client.recv().await {
+                Packet::ConnAck(connack) => (client, connack),
+                packet => panic!("Expected a CONNACK, got {:?}", packet),
+            }
+        }
+
+        async fn send(&mut self, packet: Packet) {
+            write_packet(&mut self.writer, &packet)
+                .await
+                .expect("send a packet");
+        }
+
+        async fn recv(&mut self) -> Packet {
+            timeout(
+                Duration::from_secs(5),
+                read_packet(&mut self.reader, &mut self.buf),
+            )
+            .await
+            .expect("a packet within 5 seconds")
+            .expect("read a packet")
+        }
+
+        async fn subscribe(&mut self, filter: &str) -> Vec<SubscribeReasonCode> {
+            let mut subscribe = Subscribe::new(Filter::new(filter, QoS::AtLeastOnce), None);
+            subscribe.pkid = 1;
+            self.send(Packet::Subscribe(subscribe)).await;
+            match self.recv().await {
+                Packet::SubAck(suback) => suback.return_codes,
+                packet => panic!("Expected a SUBACK, got {:?}", packet),
+            }
+        }
+
+        async fn publish(&mut self, topic: &str, payload: &str, pkid: u16) -> PubAck {
+            let mut publish = Publish::new(topic, QoS::AtLeastOnce, payload.to_string(), None);
+            publish.pkid = pkid;
+            self.send(Packet::Publish(publish)).await;
+            match self.recv().await {
+                Packet::PubAck(puback) => puback,
+                packet => panic!("Expected a PUBACK, got {:?}", packet),
+            }
+        }
+    }
+
+    /// Serve the broker on a free local port until the returned token is cancelled
+    async fn serve(broker: Broker) -> (Arc<Broker>, SocketAddr, CancellationToken) {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind a local port");
+        let addr = listener.local_addr().expect("local address");
+        let broker = Arc::new(broker);
+        let shutdown = CancellationToken::new();
+        tokio::spawn(broker.clone().serve(listener, shutdown.clone()));
+        (broker, addr, shutdown)
+    }
+
+    fn login() -> Option<Login> {
+        Some(Login::new(USERNAME, PASSWORD))
+    }
+
+    #[tokio::test]
+    async fn connect_requires_the_credentials() {
+        let (_broker, addr, shutdown) = serve(Broker::new(Vec::new(), login())).await;
+
+        let (_, connack) = TestClient::connect(addr, "dev1", None, None).await;
+        assert_eq!(connack.code, ConnectReturnCode::BadUserNamePassword);
+
+        let wrong = Some(Login::new(USERNAME, "wrong"));
+        let (_, connack) = TestClient::connect(addr, "dev1", wrong, None).await;
+        assert_eq!(connack.code, ConnectReturnCode::BadUserNamePassword);
+
+        let (_, connack) = TestClient::connect(addr, "dev1", login(), None).await;
+        assert_eq!(connack.code, ConnectReturnCode::Success);
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn connect_refuses_ids_and_wills_outside_the_own_topics() {
+        let (_broker, addr, shutdown) = serve(Broker::new(Vec::new(), login())).await;
+
+        let (_, connack) = TestClient::connect(addr, "lwt", login(), None).await;
+        assert_eq!(connack.code, ConnectReturnCode::ClientIdentifierNotValid);
+
+        let (_, connack) = TestClient::connect(addr, "dev/1", login(), None).await;
+        assert_eq!(connack.code, ConnectReturnCode::ClientIdentifierNotValid);
+
+        let will = LastWill::new("rem/dev2/gone", "bye", QoS::AtLeastOnce, false, None);
+        let (_, connack) = TestClient::connect(addr, "dev1", login(), Some(will)).await;
+        assert_eq!(connack.code, ConnectReturnCode::NotAuthorized);
+
+        let will = LastWill::new("rem/dev1/gone", "bye", QoS::AtLeastOnce, false, None);
+        let (_, connack) = TestClient::connect(addr, "dev1", login(), Some(will)).await;
+        assert_eq!(connack.code, ConnectReturnCode::Success);
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn clients_are_restricted_to_their_own_topics() {
+        let (_broker, addr, shutdown) = serve(Broker::new(Vec::new(), login())).await;
+        let (mut client, _) = TestClient::connect(addr, "dev1", login(), None).await;
+
+        // Publish first, the client would get its own messages once subscribed
+        for (topic, reason) in [
+            ("rem/data", PubAckReason::Success),
+            ("rem/status", PubAckReason::Success),
+            ("rem/dev1/cmd/response", PubAckReason::Success),
+            ("rem/dev2/cmd/response", PubAckReason::NotAuthorized),
+            ("rem/lwt", PubAckReason::NotAuthorized),
+            ("other", PubAckReason::NotAuthorized),
+        ] {
+            assert_eq!(
+                client.publish(topic, "{}", 1).await.reason,
+                reason,
+                "{}",
+                topic
+            );
+        }
+
+        let granted = SubscribeReasonCode::Success(QoS::AtLeastOnce);
+        assert_eq!(client.subscribe("rem/dev1/#").await, vec![granted]);
+        assert_eq!(client.subscribe("rem/lwt").await, vec![granted]);
+        assert_eq!(client.subscribe("rem/lwt/+").await, vec![granted]);
+        for filter in ["rem/#", "rem/+/cmd", "rem/dev2/cmd", "rem/dev1", "rem/data"] {
+            let codes = client.subscribe(filter).await;
+            assert_eq!(
+                codes,
+                vec![SubscribeReasonCode::NotAuthorized],
+                "{}",
+                filter
+            );
+        }
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn tenant_topics_need_a_tenant_level() {
+        let broker = Broker::new(Vec::new(), login()).tenant_topics(true);
+        let (_broker, addr, shutdown) = serve(broker).await;
+        let (mut client, _) = TestClient::connect(addr, "dev1", login(), None).await;
+
+        let granted = SubscribeReasonCode::Success(QoS::AtLeastOnce);
+        assert_eq!(client.subscribe("acme/rem/dev1/#").await, vec![granted]);
+        let codes = client.subscribe("+/rem/dev1/#").await;
+        assert_eq!(codes, vec![SubscribeReasonCode::NotAuthorized]);
+        let codes = client.subscribe("rem/dev1/#").await;
+        assert_eq!(codes, vec![SubscribeReasonCode::NotAuthorized]);
+
+        let puback = client.publish("acme/rem/data", "{}", 1).await;
+        assert_eq!(puback.reason, PubAckReason::Success);
+        let puback = client.publish("rem/data", "{}", 2).await;
+        assert_eq!(puback.reason, PubAckReason::NotAuthorized);
+
+        shutdown.cancel();
+    }
+
+    #[test]
+    fn wildcards_match_topic_levels() {
+        assert!(topic_matches("rem/+/cmd", "rem/dev1/cmd"));
+        assert!(!topic_matches("rem/+/cmd", "rem/dev1/cmd/response"));
+        assert!(topic_matches("rem/#", "rem/dev1/cmd/response"));
+        assert!(topic_matches("rem/dev1/#", "rem/dev1"));
+        assert!(!topic_matches("rem/+", "rem"));
+        assert!(!topic_matches("rem/data", "rem/status"));
+
+        assert!(is_own_topic("dev1", "rem/dev1/cmd"));
+        assert!(is_own_topic("dev1", "rem/dev1/#"));
+        assert!(!is_own_topic("dev1", "rem/dev1"));
+        assert!(!is_own_topic("dev1", "rem/dev10/cmd"));
+        assert!(!is_own_topic("dev1", "rem/+/cmd"));
+    }
+
+    #[tokio::test]
+    async fn subscriptions_get_the_messages_of_matching_topics() {
+        let (broker, addr, shutdown) = serve(Broker::new(Vec::new(), login())).await;
+        let (mut client, _) = TestClient::connect(addr, "dev1", login(), None).await;
+        client.subscribe("rem/dev1/+").await;
+
+        broker.publish_message(&Message::new("rem/dev1/cmd/response", "skipped", QOS_1));
+        broker.publish_message(&Message::new("rem/dev2/cmd", "skipped", QOS_1));
+        broker.publish_message(&Message::new("rem/dev1/cmd", "delivered", QOS_1));
+        match client.recv().await {
+            Packet::Publish(publish) => {
+                assert_eq!(publish.topic, "rem/dev1/cmd");
+                assert_eq!(publish.payload, "delivered");
+                assert_eq!(publish.qos, QoS::AtLeastOnce);
+            }
+            packet => panic!("Expected a PUBLISH, got {:?}", packet),
+        }
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn retained_messages_follow_the_suback() {
+        let (broker, addr, shutdown) = serve(Broker::new(Vec::new(), login())).await;
+        broker.publish_message(&Message::new_retained("rem/dev1/config", "old", QOS_1));
+        broker.publish_message(&Message::new_retained("rem/dev1/config", "new", QOS_1));
+        broker.publish_message(&Message::new_retained("rem/dev1/ota", "cleared", QOS_1));
+        broker.publish_message(&Message::new_retained("rem/dev1/ota", "", QOS_1));
+
+        let (mut client, _) = TestClient::connect(addr, "dev1", login(), None).await;
+        let granted = SubscribeReasonCode::Success(QoS::AtLeastOnce);
+        assert_eq!(client.subscribe("rem/dev1/#").await, vec![granted]);
+        match client.recv().await {
+            Packet::Publish(publish) => {
+                assert_eq!(publish.topic, "rem/dev1/config");
+                assert_eq!(publish.payload, "new");
+                assert!(publish.retain);
+            }
+            packet => panic!("Expected the retained PUBLISH, got {:?}", packet),
+        }
+
+        // Nothing else is retained, so the next packet is the answer to the ping
+        client.send(Packet::PingReq(mqttbytes::v5::PingReq)).await;
+        assert!(matches!(client.recv().await, Packet::PingResp(_)));
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn local_messages_are_acknowledged_once_journaled() {
+        let path = std::env::temp_dir().join(format!("broker-test-{}.jsonl", uuid::Uuid::new_v4()));
+        let (inbox, _) = Inbox::open(&path).expect("open the inbox");
+        let inbox = Arc::new(inbox);
+
+        let broker = Broker::new(vec![REM_DATA_TOPIC.to_string()], login());
+        let (tx, mut rx) = mpsc::channel(MESSAGE_QUEUE_SIZE);
+        broker.set_local_handler(LocalHandler {
+            inbox: inbox.clone(),
+            tx,
+        });
+        let (_broker, addr, shutdown) = serve(broker).await;
+        let (mut client, _) = TestClient::connect(addr, "dev1", login(), None).await;
+
+        for pkid in 1..=3 {
+            let puback = client
+                .publish(REM_DATA_TOPIC, &pkid.to_string(), pkid)
+                .await;
+            assert_eq!(puback.pkid, pkid);
+            assert_eq!(puback.reason, PubAckReason::Success);
+
+            // By the time the client gets its PUBACK the message is journaled and queued
+            assert_eq!(inbox.pending(), pkid as usize);
+            let entry = rx.try_recv().expect("a queued entry");
+            assert!(entry.id.is_some());
+            assert_eq!(entry.msg.payload_str(), pkid.to_string());
+        }
+
+        // Messages on other topics aren't handled locally
+        client.publish("rem/dev1/cmd/response", "{}", 4).await;
+        assert!(rx.try_recv().is_err());
+
+        shutdown.cancel();
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,187 @@
+//! Background job to reapply the calibrations of a device to its stored data.
+//!
+//! Calibrations are applied during ingestion, so revising one only affects new data. This job walks
+//! through the stored data of a device in batches ordered by id, recomputing the calibrated values
+//! from the raw values. The repo lock is released between batches so ingestion isn't blocked for
+//! the whole run. Data stored meanwhile is calibrated on ingestion already, so it doesn't matter
+//! whether a batch still sees it.
+//!
+//! Jobs are tracked in memory so callers can poll their progress, the most recent ones are kept
+//! until the listener restarts. A device only has one running job at a time.
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex as StdMutex},
+};
+
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use crate::repo::{RemRepo, RemRepoError};
+
+/// Number of rows recalibrated per transaction
+const RECALIBRATE_BATCH_SIZE: i64 = 500;
+
+/// Number of jobs kept, the oldest finished jobs are forgotten past it
+const MAX_JOBS: usize = 100;
+
+/// Recalibrate all the stored data of a device, returns the number of rows processed.
+/// `on_batch` is called with the number of rows processed so far after every batch.
+pub async fn recalibrate_device(
+    repo: Arc<Mutex<RemRepo>>,
+    tenant: String,
+    device_id: String,
+    on_batch: impl Fn(usize),
+) -> Result<usize, RemRepoError> {
+    let mut total = 0;
+    let mut last = None;
+
+    loop {
+        let (count, batch_last) = repo
+            .lock()
+            .await
+            .recalibrate_rem_data(&tenant, &device_id, last.as_deref(), RECALIBRATE_BATCH_SIZE)
+            .await?;
+
+        total += count;
+        on_batch(total);
+        if (count as i64) < RECALIBRATE_BATCH_SIZE {
+            return Ok(total);
+        }
+        last = batch_last;
+    }
+}
+
+/// State of a recalibration job
+#[derive(Serialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Running,
+    Completed,
+    Failed,
+}
+
+/// A recalibration job of a device and its progress
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct RecalibrationJob {
+    pub id: String,
+    #[serde(rename = "deviceId")]
+    pub device_id: String,
+    pub status: JobStatus,
+    /// Rows recalibrated so far
+    pub rows: usize,
+    /// Why the job failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(rename = "startedAt")]
+    pub started_at: NaiveDateTime,
+    #[serde(rename = "finishedAt")]
+    pub finished_at: Option<NaiveDateTime>,
+    #[serde(skip)]
+    tenant_id: String,
+}
+
+/// The recalibration jobs started since the listener started
+pub struct RecalibrationJobs {
+    repo: Arc<Mutex<RemRepo>>,
+    jobs: StdMutex<VecDeque<RecalibrationJob>>,
+}
+
+impl RecalibrationJobs {
+    pub fn new(repo: Arc<Mutex<RemRepo>>) -> Self {
+        RecalibrationJobs {
+            repo,
+            jobs: StdMutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Start recalibrating the data of a device in a background task, returns the new job. If the
+    /// device is being recalibrated already, the id of the running job is the error instead.
+    pub fn start(
+        self: &Arc<Self>,
+        tenant: String,
+        device_id: String,
+    ) -> Result<RecalibrationJob, String> {
+        let job = RecalibrationJob {
+            id: uuid::Uuid::new_v4().to_string(),
+            device_id: device_id.clone(),
+            status: JobStatus::Running,
+            rows: 0,
+            error: None,
+            started_at: chrono::Utc::now().naive_utc(),
+            finished_at: None,
+            tenant_id: tenant.clone(),
+        };
+        {
+            let mut jobs = self.lock();
+            let running = jobs.iter().find(|j| {
+                j.status == JobStatus::Running && j.tenant_id == tenant && j.device_id == device_id
+            });
+            if let Some(running) = running {
+                return Err(running.id.clone());
+            }
+            if jobs.len() >= MAX_JOBS {
+                if let Some(pos) = jobs.iter().position(|j| j.status != JobStatus::Running) {
+                    jobs.remove(pos);
+                }
+            }
+            jobs.push_back(job.clone());
+        }
+
+        let jobs = self.clone();
+        let id = job.id.clone();
+        tokio::spawn(async move {
+            info!(
+                "Recalibrating the data of device {} (job {})",
+                device_id, id
+            );
+
+            let on_batch = |rows| jobs.update(&id, |job| job.rows = rows);
+            let res = recalibrate_device(jobs.repo.clone(), tenant, device_id.clone(), on_batch);
+            match res.await {
+                Ok(total) => {
+                    info!("Recalibrated {} rows of device {}", total, device_id);
+                    jobs.update(&id, |job| job.status = JobStatus::Completed);
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to recalibrate the data of device {}: {}",
+                        device_id, e
+                    );
+                    jobs.update(&id, |job| {
+                        job.status = JobStatus::Failed;
+                        job.error = Some(e.to_string());
+                    });
+                }
+            }
+            jobs.update(&id, |job| {
+                job.finished_at = Some(chrono::Utc::now().naive_utc())
+            });
+        });
+
+        Ok(job)
+    }
+
+    /// A job of a device of the tenant, if it is still known.
+    pub fn get(&self, tenant: &str, device_id: &str, id: &str) -> Option<RecalibrationJob> {
+        self.lock()
+            .iter()
+            .find(|job| job.id == id && job.tenant_id == tenant && job.device_id == device_id)
+            .cloned()
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut RecalibrationJob)) {
+        if let Some(job) = self.lock().iter_mut().find(|job| job.id == id) {
+            f(job);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<RecalibrationJob>> {
+        // Jobs are only ever replaced field by field, a poisoned lock is safe to reuse
+        self.jobs
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
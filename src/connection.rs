@@ -0,0 +1,159 @@
+//! Connection state and reconnect policy of the MQTT client.
+//!
+//! The initial connection and every reconnection after a lost connection go through the same
+//! [`ReconnectPolicy`], an exponential backoff with jitter. Every state transition is recorded in
+//! the [`ConnectionMonitor`], which the API reads without needing the MQTT client lock, and in the
+//! metrics.
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::Serialize;
+use tokio::sync::watch;
+use utoipa::ToSchema;
+
+use crate::{prometheus, settings::Settings};
+
+/// Exponential backoff with jitter used between attempts to connect to the MQTT server.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    pub jitter: f64,
+
+    /// Number of attempts before giving up, zero to retry forever
+    pub max_attempts: u32,
+}
+
+impl ReconnectPolicy {
+    pub fn from_settings(config: &Settings) -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(config.mqtt_reconnect_initial_delay_ms),
+            max_delay: Duration::from_millis(config.mqtt_reconnect_max_delay_ms),
+            multiplier: config.mqtt_reconnect_multiplier.max(1.0),
+            jitter: config.mqtt_reconnect_jitter.clamp(0.0, 1.0),
+            max_attempts: config.mqtt_reconnect_max_attempts,
+        }
+    }
+
+    /// Check if another attempt is allowed after `attempt` failed attempts.
+    pub fn should_retry(&self, attempt: u32) -> bool {
+        self.max_attempts == 0 || attempt < self.max_attempts
+    }
+
+    /// The delay to wait after the given number of failed attempts, starting at one.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let base = (self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent))
+            .min(self.max_delay.as_secs_f64());
+
+        let jitter = if self.jitter > 0.0 {
+            rand::thread_rng().gen_range(-self.jitter..=self.jitter)
+        } else {
+            0.0
+        };
+
+        Duration::from_secs_f64((base * (1.0 + jitter)).max(0.0))
+    }
+}
+
+/// State of the connection to the MQTT server.
+#[derive(Serialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ConnectionState {
+    /// Establishing the initial connection
+    Connecting,
+    Connected,
+    /// The connection was lost and the client is trying to get it back
+    Reconnecting,
+    /// The client gave up or was disconnected on purpose
+    Disconnected,
+}
+
+/// ConnectionStatus is the current state of the MQTT connection along with its history.
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct ConnectionStatus {
+    pub state: ConnectionState,
+
+    /// When the connection entered the current state
+    pub since: DateTime<Utc>,
+
+    /// Number of failed attempts in the current connecting or reconnecting state
+    pub attempt: u32,
+
+    /// Number of times the connection was lost and reestablished
+    pub reconnects: u64,
+
+    /// Error of the last failed attempt
+    #[serde(rename = "lastError")]
+    pub last_error: Option<String>,
+}
+
+/// Keeps track of the state of the MQTT connection, shared between the MQTT routines and the API.
+pub struct ConnectionMonitor {
+    status: watch::Sender<ConnectionStatus>,
+}
+
+impl Default for ConnectionMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnectionMonitor {
+    pub fn new() -> Self {
+        let (status, _) = watch::channel(ConnectionStatus {
+            state: ConnectionState::Connecting,
+            since: Utc::now(),
+            attempt: 0,
+            reconnects: 0,
+            last_error: None,
+        });
+
+        ConnectionMonitor { status }
+    }
+
+    /// A snapshot of the current status.
+    pub fn status(&self) -> ConnectionStatus {
+        self.status.borrow().clone()
+    }
+
+    /// Check if the client is currently connected.
+    pub fn is_connected(&self) -> bool {
+        self.status.borrow().state == ConnectionState::Connected
+    }
+
+    /// Receiver that is notified of every change of the status.
+    pub fn subscribe(&self) -> watch::Receiver<ConnectionStatus> {
+        self.status.subscribe()
+    }
+
+    /// Move to a new state, resetting the attempt counter.
+    pub fn transition(&self, state: ConnectionState) {
+        self.status.send_modify(|status| {
+            let reconnected = status.state == ConnectionState::Reconnecting
+                && state == ConnectionState::Connected;
+            if reconnected {
+                status.reconnects += 1;
+            }
+            prometheus::record_connection(state, reconnected);
+
+            if status.state != state {
+                status.since = Utc::now();
+            }
+
+            status.state = state;
+            status.attempt = 0;
+        });
+    }
+
+    /// Record a failed attempt to connect in the current state.
+    pub fn attempt_failed(&self, err: String) {
+        prometheus::record_connect_failure();
+        self.status.send_modify(|status| {
+            status.attempt += 1;
+            status.last_error = Some(err);
+        });
+    }
+}
@@ -0,0 +1,254 @@
+//! Derived metrics computed from the raw REM channels.
+//!
+//! These are computed when the data is read rather than stored, so a formula fix applies to the
+//! whole history. Temperatures are in °C and relative humidity in %.
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Magnus formula coefficients over water (Sonntag 1990), valid from -45°C to 60°C
+const MAGNUS_A: f32 = 17.62;
+const MAGNUS_B: f32 = 243.12;
+
+/// DerivedMetrics contains the values computed from the temperature, humidity and PM channels.
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone, Copy, PartialEq)]
+pub struct DerivedMetrics {
+    /// Dew point in °C
+    #[serde(rename = "dewPoint")]
+    pub dew_point: f32,
+
+    /// Absolute humidity in g/m³
+    #[serde(rename = "absoluteHumidity")]
+    pub absolute_humidity: f32,
+
+    /// Heat index (apparent temperature) in °C
+    #[serde(rename = "heatIndex")]
+    pub heat_index: f32,
+
+    /// US EPA air quality index, the maximum of the PM2.5 and PM10 sub indexes
+    pub aqi: u16,
+}
+
+impl DerivedMetrics {
+    pub fn compute(temperature: f32, humidity: f32, pm2_5: f32, pm10: f32) -> Self {
+        DerivedMetrics {
+            dew_point: dew_point(temperature, humidity),
+            absolute_humidity: absolute_humidity(temperature, humidity),
+            heat_index: heat_index(temperature, humidity),
+            aqi: aqi_pm2_5(pm2_5).max(aqi_pm10(pm10)),
+        }
+    }
+}
+
+/// Saturation vapour pressure in hPa at the given temperature, using the Magnus formula.
+fn saturation_vapour_pressure(temperature: f32) -> f32 {
+    6.112 * ((MAGNUS_A * temperature) / (MAGNUS_B + temperature)).exp()
+}
+
+/// Dew point in °C using the Magnus formula.
+pub fn dew_point(temperature: f32, humidity: f32) -> f32 {
+    // ln(0) is undefined, clamp to a tiny humidity instead
+    let humidity = humidity.clamp(0.01, 100.0);
+    let gamma = (humidity / 100.0).ln() + (MAGNUS_A * temperature) / (MAGNUS_B + temperature);
+    (MAGNUS_B * gamma) / (MAGNUS_A - gamma)
+}
+
+/// Absolute humidity in g/m³, derived from the actual vapour pressure and the ideal gas law.
+pub fn absolute_humidity(temperature: f32, humidity: f32) -> f32 {
+    let vapour_pressure = saturation_vapour_pressure(temperature) * humidity.clamp(0.0, 100.0);
+    // 2.1674 = 100 / R_v (461.5 J/(kg·K)) * 1000 g/kg, with the humidity still in %
+    2.1674 * vapour_pressure / (273.15 + temperature)
+}
+
+/// Heat index in °C, using the US National Weather Service algorithm (Steadman's simple formula
+/// below 80°F and the Rothfusz regression with its adjustments above).
+pub fn heat_index(temperature: f32, humidity: f32) -> f32 {
+    let t = temperature * 9.0 / 5.0 + 32.0;
+    let rh = humidity.clamp(0.0, 100.0);
+
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    let hi = if (simple + t) / 2.0 < 80.0 {
+        simple
+    } else {
+        let mut hi = -42.379 + 2.049_015_2 * t + 10.143_332 * rh
+            - 0.224_755_4 * t * rh
+            - 0.006_837_83 * t * t
+            - 0.054_817_17 * rh * rh
+            + 0.001_228_74 * t * t * rh
+            + 0.000_852_82 * t * rh * rh
+            - 0.000_001_99 * t * t * rh * rh;
+
+        if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            hi -= ((13.0 - rh) / 4.0) * ((17.0 - (t - 95.0).abs()) / 17.0).sqrt();
+        } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            hi += ((rh - 85.0) / 10.0) * ((87.0 - t) / 5.0);
+        }
+
+        hi
+    };
+
+    (hi - 32.0) * 5.0 / 9.0
+}
+
+/// A single row of an EPA AQI breakpoint table: concentration low/high and index low/high.
+type Breakpoint = (f32, f32, u16, u16);
+
+/// PM2.5 breakpoints in µg/m³, from the 2024 revision of the EPA AQI.
+const PM2_5_BREAKPOINTS: &[Breakpoint] = &[
+    (0.0, 9.0, 0, 50),
+    (9.1, 35.4, 51, 100),
+    (35.5, 55.4, 101, 150),
+    (55.5, 125.4, 151, 200),
+    (125.5, 225.4, 201, 300),
+    (225.5, 325.4, 301, 500),
+];
+
+/// PM10 breakpoints in µg/m³.
+const PM10_BREAKPOINTS: &[Breakpoint] = &[
+    (0.0, 54.0, 0, 50),
+    (55.0, 154.0, 51, 100),
+    (155.0, 254.0, 101, 150),
+    (255.0, 354.0, 151, 200),
+    (355.0, 424.0, 201, 300),
+    (425.0, 604.0, 301, 500),
+];
+
+/// Linear interpolation of the index within the breakpoint the concentration falls into.
+/// Concentrations above the last breakpoint are reported as the top of the scale.
+fn aqi(concentration: f32, breakpoints: &[Breakpoint]) -> u16 {
+    let concentration = concentration.max(0.0);
+
+    for &(c_low, c_high, i_low, i_high) in breakpoints {
+        if concentration <= c_high {
+            let index = (f32::from(i_high - i_low) / (c_high - c_low))
+                * (concentration.max(c_low) - c_low)
+                + f32::from(i_low);
+            return index.round() as u16;
+        }
+    }
+
+    breakpoints.last().map_or(0, |&(_, _, _, i_high)| i_high)
+}
+
+/// PM2.5 AQI sub index. The concentration is truncated to one decimal as the EPA specifies.
+pub fn aqi_pm2_5(pm2_5: f32) -> u16 {
+    aqi((pm2_5 * 10.0).trunc() / 10.0, PM2_5_BREAKPOINTS)
+}
+
+/// PM10 AQI sub index. The concentration is truncated to an integer as the EPA specifies.
+pub fn aqi_pm10(pm10: f32) -> u16 {
+    aqi(pm10.trunc(), PM10_BREAKPOINTS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn celsius(fahrenheit: f32) -> f32 {
+        (fahrenheit - 32.0) * 5.0 / 9.0
+    }
+
+    fn fahrenheit(celsius: f32) -> f32 {
+        celsius * 9.0 / 5.0 + 32.0
+    }
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "expected {} ± {}, got {}",
+            expected,
+            tolerance,
+            actual
+        );
+    }
+
+    #[test]
+    fn dew_point_matches_reference_values() {
+        assert_close(dew_point(25.0, 60.0), 16.7, 0.05);
+        assert_close(dew_point(0.0, 50.0), -9.2, 0.05);
+        // Saturated air is at its dew point
+        assert_close(dew_point(20.0, 100.0), 20.0, 0.01);
+        // Dry air doesn't produce NaN or infinity
+        assert!(dew_point(20.0, 0.0).is_finite());
+    }
+
+    #[test]
+    fn absolute_humidity_matches_reference_values() {
+        assert_close(absolute_humidity(25.0, 60.0), 13.8, 0.05);
+        assert_close(absolute_humidity(20.0, 100.0), 17.3, 0.1);
+        assert_close(absolute_humidity(20.0, 0.0), 0.0, f32::EPSILON);
+    }
+
+    #[test]
+    fn heat_index_matches_the_nws_table() {
+        // Temperature in °F, relative humidity and heat index in °F from the NWS heat index chart
+        let table = [
+            (80.0, 40.0, 80.0),
+            (90.0, 70.0, 106.0),
+            (96.0, 65.0, 121.0),
+            (100.0, 50.0, 118.0),
+            (86.0, 90.0, 105.0),
+            (104.0, 40.0, 119.0),
+        ];
+        for (t, rh, expected) in table {
+            assert_close(fahrenheit(heat_index(celsius(t), rh)), expected, 1.0);
+        }
+    }
+
+    #[test]
+    fn heat_index_uses_the_simple_formula_when_cool() {
+        // Steadman's formula, 0.5 * (70 + 61 + (70 - 68) * 1.2 + 50 * 0.094)
+        assert_close(fahrenheit(heat_index(celsius(70.0), 50.0)), 69.05, 0.01);
+    }
+
+    #[test]
+    fn heat_index_adjusts_for_dry_and_humid_air() {
+        // The regression gives 90.20°F, less (13 - 10) / 4 * sqrt((17 - |95 - 95|) / 17)
+        assert_close(fahrenheit(heat_index(celsius(95.0), 10.0)), 89.45, 0.05);
+        // The regression gives 98.04°F, plus (90 - 85) / 10 * (87 - 84) / 5
+        assert_close(fahrenheit(heat_index(celsius(84.0), 90.0)), 98.34, 0.05);
+    }
+
+    #[test]
+    fn pm2_5_aqi_matches_the_breakpoint_edges() {
+        assert_eq!(aqi_pm2_5(0.0), 0);
+        assert_eq!(aqi_pm2_5(9.0), 50);
+        assert_eq!(aqi_pm2_5(9.1), 51);
+        // Truncated to one decimal, so still in the first breakpoint
+        assert_eq!(aqi_pm2_5(9.09), 50);
+        assert_eq!(aqi_pm2_5(35.4), 100);
+        assert_eq!(aqi_pm2_5(35.5), 101);
+        assert_eq!(aqi_pm2_5(55.4), 150);
+        assert_eq!(aqi_pm2_5(55.5), 151);
+        assert_eq!(aqi_pm2_5(125.4), 200);
+        assert_eq!(aqi_pm2_5(225.4), 300);
+        assert_eq!(aqi_pm2_5(325.4), 500);
+        assert_eq!(aqi_pm2_5(1000.0), 500);
+        assert_eq!(aqi_pm2_5(-1.0), 0);
+    }
+
+    #[test]
+    fn pm2_5_aqi_interpolates_within_a_breakpoint() {
+        // 51 + (100 - 51) / (35.4 - 9.1) * (20.0 - 9.1) = 71.3
+        assert_eq!(aqi_pm2_5(20.0), 71);
+    }
+
+    #[test]
+    fn pm10_aqi_matches_the_breakpoint_edges() {
+        assert_eq!(aqi_pm10(0.0), 0);
+        assert_eq!(aqi_pm10(54.0), 50);
+        // Truncated to an integer, so still in the first breakpoint
+        assert_eq!(aqi_pm10(54.9), 50);
+        assert_eq!(aqi_pm10(55.0), 51);
+        assert_eq!(aqi_pm10(154.0), 100);
+        assert_eq!(aqi_pm10(155.0), 101);
+        assert_eq!(aqi_pm10(254.0), 150);
+        assert_eq!(aqi_pm10(604.0), 500);
+        assert_eq!(aqi_pm10(1000.0), 500);
+    }
+
+    #[test]
+    fn aqi_is_the_worst_sub_index() {
+        assert_eq!(DerivedMetrics::compute(20.0, 50.0, 9.0, 155.0).aqi, 101);
+        assert_eq!(DerivedMetrics::compute(20.0, 50.0, 35.5, 54.0).aqi, 101);
+    }
+}
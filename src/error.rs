@@ -0,0 +1,219 @@
+//! Errors returned by the API.
+//!
+//! Every failed request is answered with the status code of what went wrong and the same JSON
+//! body: a stable `code` clients can match on, a `message` for humans and the `requestId` of the
+//! request, which is also in the `X-Request-Id` header and the logs. The extractors below replace
+//! the ones of axum so that malformed paths, queries and bodies are reported the same way.
+use axum::{
+    extract::{
+        rejection::{BytesRejection, JsonRejection, PathRejection, QueryRejection},
+        FromRequest, FromRequestParts, Path, Query, Request,
+    },
+    http::{request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::error;
+use utoipa::ToSchema;
+
+use crate::{
+    repo::{is_connection_error, RemRepoError},
+    telemetry::current_request_id,
+};
+
+/// What went wrong, each code always comes with the same status code
+#[derive(Serialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The path, query or body of the request isn't valid (400)
+    InvalidRequest,
+    /// The API key is missing or invalid (401)
+    Unauthorized,
+    /// The API key isn't allowed to use the API, or an ingested item isn't signed properly (403)
+    Forbidden,
+    /// The route or the entity it refers to doesn't exist (404)
+    NotFound,
+    /// The entity already exists or is still referenced (409)
+    Conflict,
+    /// The body is larger than the API accepts (413)
+    PayloadTooLarge,
+    /// The body isn't of a content type the API accepts (415)
+    UnsupportedMediaType,
+    /// The API key, client or device of an ingested item is over its rate limit (429)
+    RateLimited,
+    /// The listener failed to handle the request (500)
+    Internal,
+    /// The database or the MQTT server can't be reached, the request can be retried (503)
+    Unavailable,
+}
+
+impl ErrorCode {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+/// Body of every error response
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    /// ID of the request, to find it in the logs
+    #[serde(rename = "requestId", skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ApiError {
+            code,
+            message: message.into(),
+            request_id: None,
+        }
+    }
+
+    pub fn invalid(message: impl Into<String>) -> Self {
+        ApiError::new(ErrorCode::InvalidRequest, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        ApiError::new(ErrorCode::NotFound, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        ApiError::new(ErrorCode::Internal, message)
+    }
+
+    pub fn unavailable(message: impl Into<String>) -> Self {
+        ApiError::new(ErrorCode::Unavailable, message)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(mut self) -> Response {
+        self.request_id = current_request_id();
+        (self.code.status(), Json(self)).into_response()
+    }
+}
+
+impl From<RemRepoError> for ApiError {
+    fn from(err: RemRepoError) -> Self {
+        let code = match &err {
+            RemRepoError::DataEntryExists(_) => ErrorCode::Conflict,
+            RemRepoError::InvalidMessage => ErrorCode::InvalidRequest,
+            RemRepoError::UnknownCommand(_) | RemRepoError::UnknownDevice(_) => ErrorCode::NotFound,
+            RemRepoError::DatabaseError(DieselError::NotFound) => ErrorCode::NotFound,
+            RemRepoError::DatabaseError(DieselError::DatabaseError(kind, _)) => match kind {
+                DatabaseErrorKind::UniqueViolation | DatabaseErrorKind::ForeignKeyViolation => {
+                    ErrorCode::Conflict
+                }
+                kind if is_connection_error(kind) => ErrorCode::Unavailable,
+                _ => ErrorCode::Internal,
+            },
+            RemRepoError::ConnectionError(_) => ErrorCode::Unavailable,
+            // Stored data that can't be read back is a bug of the listener, not of the request
+            RemRepoError::DatabaseError(_)
+            | RemRepoError::InvalidRawChannels(_)
+            | RemRepoError::UnknownSignatureAlgorithm(_) => ErrorCode::Internal,
+        };
+        if matches!(code, ErrorCode::Internal | ErrorCode::Unavailable) {
+            error!("Failed to {:?}", err.to_string());
+        }
+        ApiError::new(code, err.to_string())
+    }
+}
+
+/// Code of a request that axum failed to extract, by the status axum gives it
+fn rejection_code(status: StatusCode) -> ErrorCode {
+    match status {
+        StatusCode::PAYLOAD_TOO_LARGE => ErrorCode::PayloadTooLarge,
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => ErrorCode::UnsupportedMediaType,
+        s if s.is_server_error() => ErrorCode::Internal,
+        _ => ErrorCode::InvalidRequest,
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        ApiError::new(rejection_code(rejection.status()), rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        ApiError::new(rejection_code(rejection.status()), rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        ApiError::new(rejection_code(rejection.status()), rejection.body_text())
+    }
+}
+
+impl From<BytesRejection> for ApiError {
+    fn from(rejection: BytesRejection) -> Self {
+        ApiError::new(rejection_code(rejection.status()), rejection.body_text())
+    }
+}
+
+/// JSON body, rejected with an [`ApiError`]
+pub struct ApiJson<T>(pub T);
+
+impl<S, T> FromRequest<S> for ApiJson<T>
+where
+    S: Send + Sync,
+    T: DeserializeOwned,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(req, state).await?;
+        Ok(ApiJson(value))
+    }
+}
+
+/// Query parameters, rejected with an [`ApiError`]
+pub struct ApiQuery<T>(pub T);
+
+impl<S, T> FromRequestParts<S> for ApiQuery<T>
+where
+    S: Send + Sync,
+    T: DeserializeOwned,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(value) = Query::<T>::from_request_parts(parts, state).await?;
+        Ok(ApiQuery(value))
+    }
+}
+
+/// Path parameters, rejected with an [`ApiError`]
+pub struct ApiPath<T>(pub T);
+
+impl<S, T> FromRequestParts<S> for ApiPath<T>
+where
+    S: Send + Sync,
+    T: DeserializeOwned + Send,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Path(value) = Path::<T>::from_request_parts(parts, state).await?;
+        Ok(ApiPath(value))
+    }
+}
@@ -0,0 +1,76 @@
+//! Storage of the firmware images served to the REM devices.
+//!
+//! Images are uploaded through the API and written to a directory on local disk, one file per
+//! version. The SHA-256 checksum is computed on upload and stored in the database along with the
+//! size, devices get both in the update notification so they can verify the download.
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs,
+    io::{self, AsyncWriteExt},
+};
+
+use crate::{settings::Settings, topic::is_valid_topic_level};
+
+/// Versions that would be shadowed by other routes of the firmware API
+const RESERVED_VERSIONS: &[&str] = &["updates"];
+
+/// Check if a firmware version can be used as a file name and in the download URL.
+pub fn is_valid_version(version: &str) -> bool {
+    is_valid_topic_level(version)
+        && !version.starts_with('.')
+        && !RESERVED_VERSIONS.contains(&version)
+        && version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '+'))
+}
+
+/// Hex encoded SHA-256 checksum of an image
+pub fn checksum(image: &[u8]) -> String {
+    hex::encode(Sha256::digest(image))
+}
+
+/// URL the devices download the image of a version from.
+pub fn download_url(config: &Settings, version: &str) -> String {
+    let base = match &config.public_url {
+        Some(url) => url.trim_end_matches('/').to_string(),
+        None => format!("http://{}:{}", config.host, config.port),
+    };
+    format!("{}/v1/rem/firmware/{}/download", base, version)
+}
+
+/// Directory of firmware images, keyed by version.
+pub struct FirmwareStore {
+    dir: PathBuf,
+}
+
+impl FirmwareStore {
+    /// Use the given directory for the images, creating it if it doesn't exist.
+    pub async fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).await?;
+        Ok(FirmwareStore { dir })
+    }
+
+    fn path(&self, version: &str) -> PathBuf {
+        self.dir.join(format!("{}.bin", version))
+    }
+
+    /// Write the image of a version, replacing any existing one. The image is written and synced
+    /// to a temporary file first, so a partial upload never ends up being served.
+    pub async fn save(&self, version: &str, image: &[u8]) -> io::Result<()> {
+        let path = self.path(version);
+        let tmp_path = path.with_extension("tmp");
+
+        let mut file = fs::File::create(&tmp_path).await?;
+        file.write_all(image).await?;
+        file.sync_all().await?;
+        fs::rename(&tmp_path, &path).await
+    }
+
+    /// Read the image of a version.
+    pub async fn read(&self, version: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path(version)).await
+    }
+}
@@ -0,0 +1,604 @@
+//! Message handlers for the MQTT listener.
+//!
+//! Every kind of message the listener understands is described by a [`MessageHandler`]. A handler
+//! declares the topic filter it subscribes to, the QoS for that subscription, how to decode the
+//! payload and how to write the decoded value to the database. Handlers are collected in a
+//! [`HandlerRegistry`] which `mqtt_proc` uses both to subscribe and to dispatch incoming messages,
+//! so adding a new message kind doesn't require touching the dispatch loop.
+//!
+//! With tenant topics enabled every topic starts with the ID of a tenant, `<tenant>/rem/data`. The
+//! registry strips that level before passing the message to the handlers, and the devices are
+//! stored for that tenant. Without tenant topics devices are stored for the tenant they were
+//! registered to, or the default tenant for new devices.
+//!
+//! Every message is admitted by the registry after it is decoded, before the handler stores
+//! anything. The signature is checked by the [`Verifier`] first, along with the `sentAt` time of the
+//! messages that may not be replayed, then messages of devices over their [`RateLimits`] are
+//! dropped. The limit is only checked once the device is known to have sent the message, and not
+//! for messages the listener handles again, see [`HandlerRegistry::dispatch`].
+use std::sync::{
+    atomic::{AtomicI64, Ordering},
+    Arc,
+};
+
+use chrono::{DateTime, Utc};
+use futures::future::{BoxFuture, FutureExt};
+use paho_mqtt::{Message, MessageBuilder, PropertyCode, QOS_1};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn, Span};
+
+use crate::{
+    model::{
+        CommandResponse, CommandStatus, FirmwareProgress, FirmwareUpdateStatus, RemData, RemStatus,
+    },
+    mqtt::MQTTClientError,
+    prometheus,
+    ratelimit::{Admission, RateLimits},
+    repo::RemRepo,
+    signing::{Replay, Verifier},
+    topic::{
+        device_id_from_topic, topic_matches, REM_COMMAND_RESPONSE_TOPIC, REM_DATA_TOPIC,
+        REM_OTA_PROGRESS_TOPIC, REM_STATUS_TOPIC,
+    },
+};
+
+/// A handler for a single kind of MQTT message.
+pub trait MessageHandler: Send + Sync {
+    /// The decoded representation of the message payload.
+    type Message: Send;
+
+    /// Topic filter the handler subscribes to. This may contain the MQTT `+` and `#` wildcards.
+    fn topic_filter(&self) -> &str;
+
+    /// QoS level used when subscribing to the topic filter.
+    fn qos(&self) -> i32 {
+        QOS_1
+    }
+
+    /// Decode the raw MQTT message into the handler's message type.
+    fn decode(&self, msg: &Message) -> Result<Self::Message, MQTTClientError>;
+
+    /// The device that sent the decoded message, whose key its signature is verified with.
+    fn device_id<'a>(&self, msg: &'a Self::Message) -> &'a str;
+
+    /// The ID the device gave the decoded message, if it has one.
+    fn message_id<'a>(&self, _msg: &'a Self::Message) -> Option<&'a str> {
+        None
+    }
+
+    /// Whether the decoded message may be replayed. Signed messages that may not are only admitted
+    /// when they were sent no earlier than the last such message of the device.
+    fn replay(&self, _msg: &Self::Message) -> Replay {
+        Replay::Allowed
+    }
+
+    /// Write the decoded message to the repository, for the tenant the message was received for.
+    fn store(
+        &self,
+        tenant: Option<String>,
+        msg: Self::Message,
+    ) -> BoxFuture<'_, Result<(), MQTTClientError>>;
+}
+
+/// Object safe version of [`MessageHandler`] so that handlers with different message types
+/// can live in the same registry.
+trait DynMessageHandler: Send + Sync {
+    fn topic_filter(&self) -> &str;
+    fn qos(&self) -> i32;
+    fn handle<'a>(
+        &'a self,
+        registry: &'a HandlerRegistry,
+        tenant: Option<String>,
+        msg: &'a Message,
+        rate_limited: bool,
+    ) -> BoxFuture<'a, Result<(), MQTTClientError>>;
+}
+
+impl<H: MessageHandler> DynMessageHandler for H {
+    fn topic_filter(&self) -> &str {
+        MessageHandler::topic_filter(self)
+    }
+
+    fn qos(&self) -> i32 {
+        MessageHandler::qos(self)
+    }
+
+    fn handle<'a>(
+        &'a self,
+        registry: &'a HandlerRegistry,
+        tenant: Option<String>,
+        msg: &'a Message,
+        rate_limited: bool,
+    ) -> BoxFuture<'a, Result<(), MQTTClientError>> {
+        async move {
+            let decoded = self.decode(msg)?;
+
+            let span = Span::current();
+            span.record("device_id", self.device_id(&decoded));
+            if let Some(id) = self.message_id(&decoded) {
+                span.record("message_id", id);
+            }
+
+            registry
+                .admit(
+                    tenant.as_deref(),
+                    self.device_id(&decoded),
+                    msg,
+                    self.replay(&decoded),
+                    rate_limited,
+                )
+                .await?;
+            self.store(tenant, decoded).await
+        }
+        .boxed()
+    }
+}
+
+/// Collection of the message handlers known to the listener.
+#[derive(Default)]
+pub struct HandlerRegistry {
+    handlers: Vec<Box<dyn DynMessageHandler>>,
+    tenant_topics: bool,
+    verifier: Option<Verifier>,
+    rate_limits: Option<Arc<RateLimits>>,
+    /// Unix time in milliseconds the last message was stored at, zero if none was
+    last_stored: AtomicI64,
+}
+
+impl HandlerRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expect the tenant as the first level of every topic.
+    pub fn tenant_topics(mut self, enabled: bool) -> Self {
+        self.tenant_topics = enabled;
+        self
+    }
+
+    /// Verify the signatures of the messages before they are stored.
+    pub fn verifier(mut self, verifier: Verifier) -> Self {
+        self.verifier = Some(verifier);
+        self
+    }
+
+    /// Drop the messages of devices that are over their rate limit.
+    pub fn rate_limits(mut self, rate_limits: Arc<RateLimits>) -> Self {
+        self.rate_limits = Some(rate_limits);
+        self
+    }
+
+    /// Add a handler to the registry. Handlers are matched in the order they are registered.
+    pub fn register<H: MessageHandler + 'static>(mut self, handler: H) -> Self {
+        self.handlers.push(Box::new(handler));
+        self
+    }
+
+    /// Topic filters of all the registered handlers, in registration order.
+    pub fn topic_filters(&self) -> Vec<String> {
+        self.handlers
+            .iter()
+            .map(|h| match self.tenant_topics {
+                true => format!("+/{}", h.topic_filter()),
+                false => h.topic_filter().to_string(),
+            })
+            .collect()
+    }
+
+    /// When the last message was stored, if any was since the listener started.
+    pub fn last_stored(&self) -> Option<DateTime<Utc>> {
+        match self.last_stored.load(Ordering::Relaxed) {
+            0 => None,
+            millis => DateTime::from_timestamp_millis(millis),
+        }
+    }
+
+    /// QoS values of all the registered handlers, in the same order as [`Self::topic_filters`].
+    pub fn qos(&self) -> Vec<i32> {
+        self.handlers.iter().map(|h| h.qos()).collect()
+    }
+
+    /// Pass the message to the first handler whose topic filter matches the message topic. With
+    /// tenant topics the first level is taken off as the tenant. Messages that were already
+    /// counted, or that the devices didn't send at once, like a backlog the MQTT server kept while
+    /// the listener was away, aren't `rate_limited`.
+    pub async fn dispatch(&self, msg: &Message, rate_limited: bool) -> Result<(), MQTTClientError> {
+        if !self.tenant_topics {
+            return self.dispatch_for(None, msg, rate_limited).await;
+        }
+
+        let topic = msg.topic();
+        let Some((tenant, rest)) = topic.split_once('/') else {
+            return Err(MQTTClientError::UnsupportedMessage(topic.to_string()));
+        };
+        let msg = MessageBuilder::new()
+            .topic(rest)
+            .payload(msg.payload())
+            .qos(msg.qos())
+            .properties(msg.properties().clone())
+            .finalize();
+
+        self.dispatch_for(Some(tenant.to_string()), &msg, rate_limited)
+            .await
+    }
+
+    /// Pass a message for a known tenant to the handlers, the topic doesn't contain the tenant.
+    pub async fn dispatch_as(&self, tenant: &str, msg: &Message) -> Result<(), MQTTClientError> {
+        self.dispatch_for(Some(tenant.to_string()), msg, true).await
+    }
+
+    async fn dispatch_for(
+        &self,
+        tenant: Option<String>,
+        msg: &Message,
+        rate_limited: bool,
+    ) -> Result<(), MQTTClientError> {
+        let topic = msg.topic();
+        let (topic_filter, res) = match self
+            .handlers
+            .iter()
+            .find(|h| topic_matches(h.topic_filter(), topic))
+        {
+            Some(handler) => (
+                handler.topic_filter(),
+                handler.handle(self, tenant, msg, rate_limited).await,
+            ),
+            None => (
+                "unknown",
+                Err(MQTTClientError::UnsupportedMessage(topic.to_string())),
+            ),
+        };
+        prometheus::record_ingest(topic_filter, &res);
+        if res.is_ok() {
+            self.last_stored
+                .store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+        }
+        res
+    }
+
+    /// Check that a decoded message of a device may be stored.
+    async fn admit(
+        &self,
+        tenant: Option<&str>,
+        device_id: &str,
+        msg: &Message,
+        replay: Replay,
+        rate_limited: bool,
+    ) -> Result<(), MQTTClientError> {
+        if let Some(verifier) = &self.verifier {
+            verifier.check(tenant, device_id, msg, replay).await?;
+        }
+
+        match &self.rate_limits {
+            Some(rate_limits)
+                if rate_limited && rate_limits.admit_device(device_id) == Admission::Dropped =>
+            {
+                Err(MQTTClientError::RateLimited(device_id.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Decode a JSON payload into `T`, mapping any failure to [`MQTTClientError::InvalidMessage`].
+pub fn decode_json<T: DeserializeOwned>(msg: &Message) -> Result<T, MQTTClientError> {
+    serde_json::from_slice(msg.payload()).map_err(|e| {
+        error!(
+            "Error parsing message on topic '{}': {:?}, payload: {:?}",
+            msg.topic(),
+            e,
+            msg.payload()
+        );
+        MQTTClientError::InvalidMessage
+    })
+}
+
+/// Handler for the telemetry published by the REM devices on [`REM_DATA_TOPIC`].
+pub struct RemDataHandler {
+    repo: Arc<Mutex<RemRepo>>,
+}
+
+impl RemDataHandler {
+    pub fn new(repo: Arc<Mutex<RemRepo>>) -> Self {
+        RemDataHandler { repo }
+    }
+}
+
+impl MessageHandler for RemDataHandler {
+    type Message = RemData;
+
+    fn topic_filter(&self) -> &str {
+        REM_DATA_TOPIC
+    }
+
+    fn decode(&self, msg: &Message) -> Result<RemData, MQTTClientError> {
+        decode_json(msg)
+    }
+
+    fn device_id<'a>(&self, data: &'a RemData) -> &'a str {
+        &data.device_id
+    }
+
+    fn message_id<'a>(&self, data: &'a RemData) -> Option<&'a str> {
+        Some(&data.id)
+    }
+
+    fn store(
+        &self,
+        tenant: Option<String>,
+        data: RemData,
+    ) -> BoxFuture<'_, Result<(), MQTTClientError>> {
+        async move {
+            info!("ID: {}, Device ID: {}", data.id, data.device_id);
+
+            let data = self
+                .repo
+                .lock()
+                .await
+                .insert_rem_data(tenant.as_deref(), data)
+                .await?;
+            prometheus::record_latest_data(&data);
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+/// Handler for the heartbeat status published by the REM devices on [`REM_STATUS_TOPIC`].
+pub struct RemStatusHandler {
+    repo: Arc<Mutex<RemRepo>>,
+}
+
+impl RemStatusHandler {
+    pub fn new(repo: Arc<Mutex<RemRepo>>) -> Self {
+        RemStatusHandler { repo }
+    }
+}
+
+impl MessageHandler for RemStatusHandler {
+    type Message = RemStatus;
+
+    fn topic_filter(&self) -> &str {
+        REM_STATUS_TOPIC
+    }
+
+    fn decode(&self, msg: &Message) -> Result<RemStatus, MQTTClientError> {
+        decode_json(msg)
+    }
+
+    fn device_id<'a>(&self, status: &'a RemStatus) -> &'a str {
+        &status.device_id
+    }
+
+    fn message_id<'a>(&self, status: &'a RemStatus) -> Option<&'a str> {
+        Some(&status.id)
+    }
+
+    fn store(
+        &self,
+        tenant: Option<String>,
+        status: RemStatus,
+    ) -> BoxFuture<'_, Result<(), MQTTClientError>> {
+        async move {
+            info!(
+                "ID: {}, Device ID: {}, Uptime: {}",
+                status.id, status.device_id, status.up_time
+            );
+
+            let device_id = status.device_id.clone();
+            self.repo
+                .lock()
+                .await
+                .insert_rem_status(tenant.as_deref(), status)
+                .await?;
+            prometheus::record_seen(&device_id);
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+/// A decoded acknowledgement of a command, see [`CommandResponseHandler`].
+pub struct CommandAck {
+    pub id: String,
+    pub device_id: String,
+    pub status: CommandStatus,
+    pub sent_at: Option<DateTime<Utc>>,
+    pub response: Value,
+}
+
+/// Handler for the acknowledgements the REM devices publish on [`REM_COMMAND_RESPONSE_TOPIC`]. The
+/// command is identified by the MQTT v5 correlation data the listener set when publishing it.
+pub struct CommandResponseHandler {
+    repo: Arc<Mutex<RemRepo>>,
+}
+
+impl CommandResponseHandler {
+    pub fn new(repo: Arc<Mutex<RemRepo>>) -> Self {
+        CommandResponseHandler { repo }
+    }
+}
+
+impl MessageHandler for CommandResponseHandler {
+    type Message = CommandAck;
+
+    fn topic_filter(&self) -> &str {
+        REM_COMMAND_RESPONSE_TOPIC
+    }
+
+    fn decode(&self, msg: &Message) -> Result<CommandAck, MQTTClientError> {
+        let id = msg
+            .properties()
+            .get_binary(PropertyCode::CorrelationData)
+            .and_then(|data| String::from_utf8(data).ok())
+            .ok_or_else(|| {
+                error!(
+                    "Command response on topic '{}' without valid correlation data",
+                    msg.topic()
+                );
+                MQTTClientError::InvalidMessage
+            })?;
+        let device_id = device_id_from_topic(msg.topic())
+            .ok_or(MQTTClientError::InvalidMessage)?
+            .to_string();
+
+        let response: CommandResponse = decode_json(msg)?;
+
+        // Only the listener itself moves a command to these states
+        if matches!(
+            response.status,
+            CommandStatus::Pending | CommandStatus::Sent
+        ) {
+            error!(
+                "Command response with invalid status {:?} for command {}",
+                response.status, id
+            );
+            return Err(MQTTClientError::InvalidMessage);
+        }
+
+        Ok(CommandAck {
+            id,
+            device_id,
+            status: response.status,
+            sent_at: response.sent_at,
+            response: serde_json::to_value(&response).unwrap_or_default(),
+        })
+    }
+
+    fn device_id<'a>(&self, ack: &'a CommandAck) -> &'a str {
+        &ack.device_id
+    }
+
+    fn message_id<'a>(&self, ack: &'a CommandAck) -> Option<&'a str> {
+        Some(&ack.id)
+    }
+
+    fn replay(&self, ack: &CommandAck) -> Replay {
+        Replay::Protected(ack.sent_at)
+    }
+
+    fn store(
+        &self,
+        tenant: Option<String>,
+        ack: CommandAck,
+    ) -> BoxFuture<'_, Result<(), MQTTClientError>> {
+        async move {
+            info!(
+                "Command: {}, Device ID: {}, Status: {:?}",
+                ack.id, ack.device_id, ack.status
+            );
+
+            let updated = self
+                .repo
+                .lock()
+                .await
+                .acknowledge_command(
+                    tenant.as_deref(),
+                    &ack.id,
+                    &ack.device_id,
+                    ack.status,
+                    ack.response,
+                )
+                .await
+                .map_err(MQTTClientError::Repo)?;
+            if !updated {
+                warn!(
+                    "Ignoring {:?} response for command {}, it already finished",
+                    ack.status, ack.id
+                );
+            }
+
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+/// Handler for the firmware update progress the REM devices report on [`REM_OTA_PROGRESS_TOPIC`].
+pub struct FirmwareProgressHandler {
+    repo: Arc<Mutex<RemRepo>>,
+}
+
+impl FirmwareProgressHandler {
+    pub fn new(repo: Arc<Mutex<RemRepo>>) -> Self {
+        FirmwareProgressHandler { repo }
+    }
+}
+
+impl MessageHandler for FirmwareProgressHandler {
+    type Message = (String, FirmwareProgress);
+
+    fn topic_filter(&self) -> &str {
+        REM_OTA_PROGRESS_TOPIC
+    }
+
+    fn decode(&self, msg: &Message) -> Result<(String, FirmwareProgress), MQTTClientError> {
+        let device_id = device_id_from_topic(msg.topic())
+            .ok_or(MQTTClientError::InvalidMessage)?
+            .to_string();
+        let progress: FirmwareProgress = decode_json(msg)?;
+
+        // Only the listener itself moves an update to these states
+        if matches!(
+            progress.status,
+            FirmwareUpdateStatus::Assigned
+                | FirmwareUpdateStatus::Notified
+                | FirmwareUpdateStatus::Verified
+        ) {
+            error!(
+                "Firmware progress with invalid status {:?} from {}",
+                progress.status, device_id
+            );
+            return Err(MQTTClientError::InvalidMessage);
+        }
+        if progress.progress.is_some_and(|p| p > 100) {
+            error!(
+                "Firmware progress above 100% ({:?}) from {}",
+                progress.progress, device_id
+            );
+            return Err(MQTTClientError::InvalidMessage);
+        }
+
+        Ok((device_id, progress))
+    }
+
+    fn device_id<'a>(&self, (device_id, _): &'a (String, FirmwareProgress)) -> &'a str {
+        device_id
+    }
+
+    fn replay(&self, (_, progress): &(String, FirmwareProgress)) -> Replay {
+        Replay::Protected(progress.sent_at)
+    }
+
+    fn store(
+        &self,
+        tenant: Option<String>,
+        (device_id, progress): (String, FirmwareProgress),
+    ) -> BoxFuture<'_, Result<(), MQTTClientError>> {
+        async move {
+            info!(
+                "Device ID: {}, Firmware: {}, Status: {:?}, Progress: {:?}",
+                device_id, progress.version, progress.status, progress.progress
+            );
+
+            let updated = self
+                .repo
+                .lock()
+                .await
+                .report_firmware_progress(tenant.as_deref(), &device_id, &progress)
+                .await
+                .map_err(MQTTClientError::Repo)?;
+            if !updated {
+                warn!(
+                    "Ignoring progress of {} for firmware {}, it isn't the pending update",
+                    device_id, progress.version
+                );
+            }
+
+            Ok(())
+        }
+        .boxed()
+    }
+}
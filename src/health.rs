@@ -0,0 +1,260 @@
+//! Liveness and readiness of the listener.
+//!
+//! The liveness check only tells whether the listener can still serve requests, orchestrators
+//! restart it when that fails. The readiness check reports every component the listener depends on,
+//! and fails when one of them is down. The database is checked on a connection of its own, so the
+//! check isn't stuck behind slow queries of the API or the ingestion.
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use diesel::{sql_query, Connection, PgConnection, RunQueryDsl};
+use serde::Serialize;
+use tokio::{sync::Mutex, task::spawn_blocking, time::timeout};
+use utoipa::ToSchema;
+
+use crate::{
+    connection::{ConnectionMonitor, ConnectionStatus},
+    ingest::Ingestor,
+    settings::Settings,
+};
+
+/// Time the database has to answer before it is considered down
+const DB_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Latency above which the database is considered degraded
+const DB_SLOW_LATENCY: Duration = Duration::from_millis(500);
+
+/// Number of messages waiting in the inbox above which ingestion is considered degraded
+const INBOX_BACKLOG_DEGRADED: usize = 1000;
+
+/// Status of the listener or one of its components
+#[derive(Serialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub enum HealthStatus {
+    /// Working as expected
+    Up,
+    /// Working, but slow or behind
+    Degraded,
+    /// Not working, the listener isn't ready
+    Down,
+}
+
+/// Response of the liveness check
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct Liveness {
+    pub status: HealthStatus,
+    #[serde(rename = "uptimeSecs")]
+    pub uptime_secs: u64,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct MqttHealth {
+    pub status: HealthStatus,
+    #[serde(flatten)]
+    pub connection: ConnectionStatus,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct DatabaseHealth {
+    pub status: HealthStatus,
+    /// Round trip of a trivial query
+    #[serde(rename = "latencyMs")]
+    pub latency_ms: Option<f64>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct IngestHealth {
+    pub status: HealthStatus,
+    /// When the last message was stored, since the listener started
+    #[serde(rename = "lastMessageAt")]
+    pub last_message_at: Option<DateTime<Utc>>,
+    #[serde(rename = "secondsSinceLastMessage")]
+    pub seconds_since_last_message: Option<i64>,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct InboxHealth {
+    pub status: HealthStatus,
+    /// Received messages that aren't stored yet
+    pub pending: usize,
+    /// Received messages that couldn't be written to the inbox since the listener started
+    #[serde(rename = "writeFailures")]
+    pub write_failures: u64,
+    /// Why the latest message couldn't be written, while writing to the inbox fails
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct Components {
+    pub mqtt: MqttHealth,
+    pub database: DatabaseHealth,
+    pub ingest: IngestHealth,
+    pub inbox: InboxHealth,
+}
+
+/// Response of the readiness check, the status is the worst status of the components
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct Readiness {
+    pub status: HealthStatus,
+    #[serde(rename = "checkedAt")]
+    pub checked_at: DateTime<Utc>,
+    pub components: Components,
+}
+
+pub struct HealthChecker {
+    config: Arc<Settings>,
+    connection: Arc<ConnectionMonitor>,
+    ingestor: Arc<Ingestor>,
+    started: Instant,
+    /// Connection used only by the checks, opened on the first check and after failures
+    db: Mutex<Option<PgConnection>>,
+}
+
+impl HealthChecker {
+    pub fn new(
+        config: Arc<Settings>,
+        connection: Arc<ConnectionMonitor>,
+        ingestor: Arc<Ingestor>,
+    ) -> Self {
+        HealthChecker {
+            config,
+            connection,
+            ingestor,
+            started: Instant::now(),
+            db: Mutex::new(None),
+        }
+    }
+
+    pub fn live(&self) -> Liveness {
+        Liveness {
+            status: HealthStatus::Up,
+            uptime_secs: self.started.elapsed().as_secs(),
+        }
+    }
+
+    pub async fn ready(&self) -> Readiness {
+        let components = Components {
+            mqtt: self.check_mqtt(),
+            database: self.check_database().await,
+            ingest: self.check_ingest(),
+            inbox: self.check_inbox(),
+        };
+        let status = [
+            components.mqtt.status,
+            components.database.status,
+            components.ingest.status,
+            components.inbox.status,
+        ]
+        .into_iter()
+        .max()
+        .unwrap_or(HealthStatus::Up);
+
+        Readiness {
+            status,
+            checked_at: Utc::now(),
+            components,
+        }
+    }
+
+    fn check_mqtt(&self) -> MqttHealth {
+        let status = match self.connection.is_connected() {
+            true => HealthStatus::Up,
+            false => HealthStatus::Down,
+        };
+        MqttHealth {
+            status,
+            connection: self.connection.status(),
+        }
+    }
+
+    async fn check_database(&self) -> DatabaseHealth {
+        let mut db = self.db.lock().await;
+        let conn = db.take();
+        let url = self.config.database_url.clone();
+
+        // Connecting and querying block, and may take a while when the database is unreachable
+        let check = spawn_blocking(move || -> Result<(PgConnection, Duration), String> {
+            let mut conn = match conn {
+                Some(conn) => conn,
+                None => PgConnection::establish(&url).map_err(|e| e.to_string())?,
+            };
+            let start = Instant::now();
+            sql_query("SELECT 1")
+                .execute(&mut conn)
+                .map_err(|e| e.to_string())?;
+            Ok((conn, start.elapsed()))
+        });
+
+        let res = match timeout(DB_TIMEOUT, check).await {
+            Ok(Ok(res)) => res,
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err(format!("No answer within {:?}", DB_TIMEOUT)),
+        };
+
+        match res {
+            Ok((conn, latency)) => {
+                *db = Some(conn);
+                let status = match latency > DB_SLOW_LATENCY {
+                    true => HealthStatus::Degraded,
+                    false => HealthStatus::Up,
+                };
+                DatabaseHealth {
+                    status,
+                    latency_ms: Some(latency.as_secs_f64() * 1000.0),
+                    error: None,
+                }
+            }
+            Err(e) => DatabaseHealth {
+                status: HealthStatus::Down,
+                latency_ms: None,
+                error: Some(e),
+            },
+        }
+    }
+
+    fn check_ingest(&self) -> IngestHealth {
+        let last = self.ingestor.registry().last_stored();
+        let age = last.map(|at| (Utc::now() - at).num_seconds());
+
+        let max_age = self.config.health_max_ingest_age_secs;
+        let stale = match age {
+            Some(age) => age > max_age,
+            None => self.started.elapsed().as_secs() as i64 > max_age,
+        };
+        let status = match max_age > 0 && stale {
+            true => HealthStatus::Degraded,
+            false => HealthStatus::Up,
+        };
+
+        IngestHealth {
+            status,
+            last_message_at: last,
+            seconds_since_last_message: age,
+        }
+    }
+
+    fn check_inbox(&self) -> InboxHealth {
+        let inbox = self.ingestor.inbox();
+        let pending = inbox.pending();
+        let failures = inbox.failures();
+
+        // Messages that can't be journaled are lost if the listener stops before storing them
+        let status = if failures.last_error.is_some() {
+            HealthStatus::Down
+        } else if pending > INBOX_BACKLOG_DEGRADED {
+            HealthStatus::Degraded
+        } else {
+            HealthStatus::Up
+        };
+        InboxHealth {
+            status,
+            pending,
+            write_failures: failures.count,
+            error: failures.last_error,
+        }
+    }
+}
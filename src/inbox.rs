@@ -0,0 +1,494 @@
+//! Durable inbox for incoming MQTT messages.
+//!
+//! The Paho client acknowledges QoS 1 messages as soon as they arrive, before the application has
+//! seen them, and it has no manual acknowledgement. To still get at-least-once delivery end to end
+//! every message is appended to an on-disk journal from the client callback, before it is handed
+//! to the handlers. An entry is only marked done once its row is committed or dead-lettered, so
+//! entries that are still pending when the listener stops are replayed on the next start.
+//!
+//! The journal is a file of JSON lines, one per received message and one per completed message.
+//! It is compacted on startup and whenever it grows past [`COMPACT_THRESHOLD`], and truncated
+//! whenever there are no pending entries left.
+//!
+//! A message that can't be journaled, say because the disk is full, is still handed to the
+//! handlers since the client already acknowledged it. It won't be replayed if the listener stops
+//! before it is stored though, so the failures are counted and reported by the health check.
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use paho_mqtt::{Message, MessageBuilder, Properties, PropertyCode};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::prometheus;
+
+/// Size of the journal in bytes past which it is rewritten with only the pending entries
+pub const COMPACT_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+/// A line of the journal file
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Record {
+    /// A message was received
+    Received {
+        id: u64,
+        topic: String,
+        /// Hex encoded payload
+        payload: String,
+        qos: i32,
+        /// Hex encoded MQTT v5 correlation data, used to match command responses
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        correlation: Option<String>,
+        /// The MQTT v5 user properties, like the signature and the `traceparent` of the sender
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        user_properties: Vec<(String, String)>,
+    },
+    /// A message was stored or dead-lettered
+    Done { id: u64 },
+}
+
+impl Record {
+    fn received(id: u64, msg: &Message) -> Self {
+        Record::Received {
+            id,
+            topic: msg.topic().to_string(),
+            payload: hex::encode(msg.payload()),
+            qos: msg.qos(),
+            correlation: msg
+                .properties()
+                .get_binary(PropertyCode::CorrelationData)
+                .map(hex::encode),
+            user_properties: msg.properties().user_iter().collect(),
+        }
+    }
+}
+
+/// Rebuild a journaled message
+fn message(
+    topic: String,
+    payload: Vec<u8>,
+    qos: i32,
+    correlation: Option<Vec<u8>>,
+    user_properties: Vec<(String, String)>,
+) -> Message {
+    let mut props = Properties::new();
+    if let Some(correlation) = correlation {
+        if let Err(e) = props.push_binary(PropertyCode::CorrelationData, correlation) {
+            warn!("Failed to restore the correlation data of a message: {}", e);
+        }
+    }
+    for (name, value) in user_properties {
+        if let Err(e) = props.push_string_pair(PropertyCode::UserProperty, &name, &value) {
+            warn!(
+                "Failed to restore the user property {} of a message: {}",
+                name, e
+            );
+        }
+    }
+
+    MessageBuilder::new()
+        .topic(topic)
+        .payload(payload)
+        .qos(qos)
+        .properties(props)
+        .finalize()
+}
+
+/// A message read from the inbox. The id is `None` if the message couldn't be journaled.
+#[derive(Debug)]
+pub struct InboxEntry {
+    pub id: Option<u64>,
+    pub msg: Message,
+    /// The message was received a while ago, by a previous run or while the listener couldn't
+    /// handle it, so it doesn't count against the rate limit of its device
+    pub backlog: bool,
+}
+
+struct Journal {
+    file: File,
+    /// Bytes written to the file since it was last compacted or truncated
+    size: u64,
+    next_id: u64,
+    /// The journal lines of the pending entries, by id, to compact the journal without reading it
+    pending: BTreeMap<u64, String>,
+    /// Messages that couldn't be journaled since the listener started
+    failures: u64,
+    /// Why the latest message couldn't be journaled, cleared once a message is journaled again
+    last_error: Option<String>,
+}
+
+impl Journal {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+}
+
+/// Append only journal of the messages that were received but not yet processed.
+pub struct Inbox {
+    path: PathBuf,
+    journal: Mutex<Journal>,
+}
+
+/// Failures to journal received messages
+#[derive(Debug, Clone)]
+pub struct InboxFailures {
+    /// Messages that couldn't be journaled since the listener started
+    pub count: u64,
+    /// Why the latest message couldn't be journaled, if journaling is still failing
+    pub last_error: Option<String>,
+}
+
+impl Inbox {
+    /// Open the inbox at the given path, creating it if it doesn't exist. Returns the inbox along
+    /// with the entries that were never completed, in the order they were received.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<(Self, Vec<InboxEntry>)> {
+        let path = path.as_ref().to_path_buf();
+
+        // Collect the received messages that don't have a matching done record
+        let mut received = BTreeMap::new();
+        let mut next_id = 1;
+        if path.exists() {
+            for (n, line) in BufReader::new(File::open(&path)?).lines().enumerate() {
+                let line = line?;
+                match serde_json::from_str::<Record>(&line) {
+                    Ok(Record::Received {
+                        id,
+                        topic,
+                        payload,
+                        qos,
+                        correlation,
+                        user_properties,
+                    }) => {
+                        next_id = next_id.max(id + 1);
+                        let correlation = correlation.map(hex::decode).transpose();
+                        match (hex::decode(&payload), correlation) {
+                            (Ok(payload), Ok(correlation)) => {
+                                let msg =
+                                    message(topic, payload, qos, correlation, user_properties);
+                                received.insert(id, msg);
+                            }
+                            _ => warn!("Skipping inbox entry {} with an invalid payload", id),
+                        }
+                    }
+                    Ok(Record::Done { id }) => {
+                        received.remove(&id);
+                    }
+                    // Most likely a partial line written while crashing
+                    Err(e) => warn!("Skipping invalid line {} of the inbox: {}", n + 1, e),
+                }
+            }
+        }
+
+        let pending: BTreeMap<u64, String> = received
+            .iter()
+            .map(|(id, msg)| Ok((*id, serde_json::to_string(&Record::received(*id, msg))?)))
+            .collect::<io::Result<_>>()?;
+        let (file, size) = compact(&path, &pending)?;
+        let entries = received
+            .into_iter()
+            .map(|(id, msg)| InboxEntry {
+                id: Some(id),
+                msg,
+                backlog: true,
+            })
+            .collect();
+
+        let inbox = Inbox {
+            path,
+            journal: Mutex::new(Journal {
+                file,
+                size,
+                next_id,
+                pending,
+                failures: 0,
+                last_error: None,
+            }),
+        };
+
+        Ok((inbox, entries))
+    }
+
+    /// Durably record a received message, returns the id of its entry.
+    pub fn append(&self, msg: &Message) -> io::Result<u64> {
+        let mut journal = self.lock();
+        let id = journal.next_id;
+
+        let line = serde_json::to_string(&Record::received(id, msg))?;
+        journal.write_line(&line)?;
+        journal.file.sync_data()?;
+
+        journal.next_id += 1;
+        journal.pending.insert(id, line);
+        journal.last_error = None;
+        Ok(id)
+    }
+
+    /// Record a received message and wrap it in an entry. A message that couldn't be journaled is
+    /// still processed, it just won't be replayed. The failure is counted and reported by
+    /// [`Inbox::failures`] until a message is journaled again.
+    pub fn entry(&self, msg: Message) -> InboxEntry {
+        let id = match self.append(&msg) {
+            Ok(id) => Some(id),
+            Err(e) => {
+                error!(
+                    "Failed to write message on '{}' to the inbox, it won't be replayed: {}",
+                    msg.topic(),
+                    e
+                );
+                prometheus::record_inbox_write_error();
+                let mut journal = self.lock();
+                journal.failures += 1;
+                journal.last_error = Some(e.to_string());
+                None
+            }
+        };
+        InboxEntry {
+            id,
+            msg,
+            backlog: false,
+        }
+    }
+
+    /// Mark an entry as processed, it won't be replayed anymore.
+    pub fn complete(&self, id: u64) -> io::Result<()> {
+        let mut journal = self.lock();
+        if journal.pending.remove(&id).is_none() {
+            return Ok(());
+        }
+
+        // Nothing left to replay, so start over with an empty journal rather than growing forever
+        if journal.pending.is_empty() {
+            journal.file.set_len(0)?;
+            journal.size = 0;
+            return journal.file.sync_data();
+        }
+
+        // Entries that stay pending for long, like while the database is down, would otherwise
+        // let the journal grow with every message that is completed in the meantime
+        if journal.size > COMPACT_THRESHOLD {
+            let (file, size) = compact(&self.path, &journal.pending)?;
+            journal.file = file;
+            journal.size = size;
+            return Ok(());
+        }
+
+        let line = serde_json::to_string(&Record::Done { id })?;
+        journal.write_line(&line)?;
+        journal.file.sync_data()
+    }
+
+    /// Number of received messages that aren't processed yet
+    pub fn pending(&self) -> usize {
+        self.lock().pending.len()
+    }
+
+    /// Messages that couldn't be journaled
+    pub fn failures(&self) -> InboxFailures {
+        let journal = self.lock();
+        InboxFailures {
+            count: journal.failures,
+            last_error: journal.last_error.clone(),
+        }
+    }
+
+    /// Path of the journal file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Journal> {
+        // The journal is never left in an inconsistent state, so a poisoned lock is safe to reuse
+        self.journal
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Rewrite the journal with only the given lines of the pending entries, returns the file opened
+/// for appending and its size. The lines are written to a temporary file first so a crash while
+/// compacting doesn't lose anything.
+fn compact(path: &Path, pending: &BTreeMap<u64, String>) -> io::Result<(File, u64)> {
+    let tmp_path = path.with_extension("tmp");
+    let mut size = 0;
+    {
+        let mut tmp = File::create(&tmp_path)?;
+        for line in pending.values() {
+            writeln!(tmp, "{}", line)?;
+            size += line.len() as u64 + 1;
+        }
+        tmp.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+
+    let file = OpenOptions::new().append(true).open(path)?;
+    Ok((file, size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A journal path of its own for each test, removed before it starts
+    fn journal_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("inbox-{}-{}.jsonl", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn msg(topic: &str, payload: &[u8]) -> Message {
+        MessageBuilder::new()
+            .topic(topic)
+            .payload(payload)
+            .qos(1)
+            .finalize()
+    }
+
+    fn topics(entries: &[InboxEntry]) -> Vec<String> {
+        entries.iter().map(|e| e.msg.topic().to_string()).collect()
+    }
+
+    #[test]
+    fn replays_pending_entries_after_reopening() {
+        let path = journal_path("replay");
+        let (inbox, replay) = Inbox::open(&path).expect("open");
+        assert!(replay.is_empty());
+        inbox.append(&msg("rem/data/a", b"1")).expect("append");
+        inbox.append(&msg("rem/data/b", b"2")).expect("append");
+        drop(inbox);
+
+        let (inbox, replay) = Inbox::open(&path).expect("reopen");
+        assert_eq!(topics(&replay), ["rem/data/a", "rem/data/b"]);
+        assert_eq!(replay[1].msg.payload(), b"2");
+        assert!(replay.iter().all(|e| e.backlog));
+        assert_eq!(inbox.pending(), 2);
+
+        // New entries don't reuse the ids of the replayed ones
+        let id = inbox.append(&msg("rem/data/c", b"3")).expect("append");
+        assert!(replay.iter().all(|e| e.id < Some(id)));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn does_not_replay_done_entries() {
+        let path = journal_path("done");
+        let (inbox, _) = Inbox::open(&path).expect("open");
+        let first = inbox.append(&msg("rem/data/a", b"1")).expect("append");
+        inbox.append(&msg("rem/data/b", b"2")).expect("append");
+        inbox.complete(first).expect("complete");
+        drop(inbox);
+
+        let (inbox, replay) = Inbox::open(&path).expect("reopen");
+        assert_eq!(topics(&replay), ["rem/data/b"]);
+        assert_eq!(inbox.pending(), 1);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn truncates_the_journal_when_nothing_is_pending() {
+        let path = journal_path("truncate");
+        let (inbox, _) = Inbox::open(&path).expect("open");
+        let first = inbox.append(&msg("rem/data/a", b"1")).expect("append");
+        let second = inbox.append(&msg("rem/data/b", b"2")).expect("append");
+
+        inbox.complete(first).expect("complete");
+        assert!(fs::metadata(&path).expect("metadata").len() > 0);
+        inbox.complete(second).expect("complete");
+        assert_eq!(fs::metadata(&path).expect("metadata").len(), 0);
+        assert_eq!(inbox.pending(), 0);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn compacts_the_journal_past_the_threshold() {
+        let path = journal_path("compact");
+        let (inbox, _) = Inbox::open(&path).expect("open");
+
+        // The payloads are hex encoded, so each line takes about twice the payload size
+        let payload = vec![0xab; 1024 * 1024];
+        let lines = COMPACT_THRESHOLD / (2 * payload.len() as u64) + 1;
+        let ids: Vec<u64> = (0..lines)
+            .map(|_| inbox.append(&msg("rem/data/a", &payload)).expect("append"))
+            .collect();
+        let before = fs::metadata(&path).expect("metadata").len();
+        assert!(before > COMPACT_THRESHOLD);
+
+        // Completing an entry rewrites the journal without it, rather than appending a done line
+        inbox.complete(ids[0]).expect("complete");
+        let after = fs::metadata(&path).expect("metadata").len();
+        assert!(after < before);
+        let journal = fs::read_to_string(&path).expect("read");
+        assert_eq!(journal.lines().count() as u64, lines - 1);
+        assert!(!journal.contains(r#""op":"done""#));
+
+        drop(inbox);
+        let (_, replay) = Inbox::open(&path).expect("reopen");
+        assert_eq!(replay.len() as u64, lines - 1);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn skips_a_partially_written_last_line() {
+        let path = journal_path("partial");
+        let (inbox, _) = Inbox::open(&path).expect("open");
+        inbox.append(&msg("rem/data/a", b"1")).expect("append");
+        drop(inbox);
+
+        let mut file = OpenOptions::new().append(true).open(&path).expect("open");
+        write!(file, r#"{{"op":"received","id":2,"topic":"rem/da"#).expect("write");
+        drop(file);
+
+        let (_, replay) = Inbox::open(&path).expect("reopen");
+        assert_eq!(topics(&replay), ["rem/data/a"]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn keeps_the_correlation_data_and_user_properties() {
+        let path = journal_path("properties");
+        let mut props = Properties::new();
+        props
+            .push_binary(PropertyCode::CorrelationData, vec![1, 2, 3])
+            .expect("correlation data");
+        props
+            .push_string_pair(PropertyCode::UserProperty, "signature", "abc")
+            .expect("user property");
+        props
+            .push_string_pair(PropertyCode::UserProperty, "traceparent", "00-01-02-01")
+            .expect("user property");
+        let sent = MessageBuilder::new()
+            .topic("rem/command/response")
+            .payload("{}")
+            .qos(1)
+            .properties(props)
+            .finalize();
+
+        let (inbox, _) = Inbox::open(&path).expect("open");
+        inbox.append(&sent).expect("append");
+        drop(inbox);
+
+        let (_, replay) = Inbox::open(&path).expect("reopen");
+        let received = &replay[0].msg;
+        assert_eq!(
+            received
+                .properties()
+                .get_binary(PropertyCode::CorrelationData),
+            Some(vec![1, 2, 3])
+        );
+        assert_eq!(
+            received.properties().user_iter().collect::<Vec<_>>(),
+            [
+                ("signature".to_string(), "abc".to_string()),
+                ("traceparent".to_string(), "00-01-02-01".to_string()),
+            ]
+        );
+        let _ = fs::remove_file(&path);
+    }
+}
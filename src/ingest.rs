@@ -0,0 +1,272 @@
+//! Processing of received MQTT messages.
+//!
+//! The [`Ingestor`] passes every message from the [`Inbox`] to the handler registry and decides
+//! what happens to it afterwards. Stored messages and duplicates of messages that were already
+//! stored are done. Messages that can never be stored are written to the dead letter table. Messages
+//! that failed on a transient database error are retried a few times. If that doesn't help they
+//! stay in the inbox and are set aside, to be retried every [`STALLED_RETRY_INTERVAL`] or replayed
+//! on the next start. While messages are set aside the database is assumed to be unavailable, so
+//! new messages that fail the same way are set aside right away instead of being retried first.
+//!
+//! Messages posted to the HTTP ingestion API go through [`Ingestor::submit`] instead. Those aren't
+//! journaled or dead-lettered, the client gets the result right away and retries failures itself.
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use paho_mqtt::Message;
+use tokio::{select, sync::Mutex, time::sleep};
+use tokio_util::sync::CancellationToken;
+use tracing::{
+    error,
+    field::{debug, display},
+    info, warn, Instrument, Span,
+};
+
+use crate::{
+    handler::HandlerRegistry,
+    inbox::{Inbox, InboxEntry},
+    mqtt::MQTTClientError,
+    repo::{RemRepo, RemRepoError},
+    telemetry::message_span,
+};
+
+/// Number of times a message is retried after a transient error
+const TRANSIENT_RETRY_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry after a transient error, this doubles with every attempt
+const TRANSIENT_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Interval at which the messages set aside after a transient error are retried
+pub const STALLED_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What happened to a message after it was handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The message was stored
+    Stored,
+    /// The message was stored before, likely a redelivery
+    Duplicate,
+    /// The message can't be stored and was written to the dead letter table
+    DeadLettered,
+    /// The signature of the message is missing or invalid, it was recorded as rejected
+    Rejected,
+    /// The device is over its rate limit and the message was dropped
+    Dropped,
+    /// The message couldn't be stored yet and stays in the inbox
+    Pending,
+}
+
+pub struct Ingestor {
+    registry: Arc<HandlerRegistry>,
+    repo: Arc<Mutex<RemRepo>>,
+    inbox: Arc<Inbox>,
+    /// Entries that couldn't be stored yet, oldest first
+    stalled: StdMutex<VecDeque<InboxEntry>>,
+}
+
+impl Ingestor {
+    pub fn new(
+        registry: Arc<HandlerRegistry>,
+        repo: Arc<Mutex<RemRepo>>,
+        inbox: Arc<Inbox>,
+    ) -> Self {
+        Ingestor {
+            registry,
+            repo,
+            inbox,
+            stalled: StdMutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn registry(&self) -> &HandlerRegistry {
+        &self.registry
+    }
+
+    pub fn inbox(&self) -> &Arc<Inbox> {
+        &self.inbox
+    }
+
+    /// Handle a message of a tenant that was received outside of MQTT. Returns whether it was
+    /// stored or a duplicate, or the error of the handler.
+    pub async fn submit(&self, tenant: &str, msg: &Message) -> Result<Outcome, MQTTClientError> {
+        let span = message_span(msg);
+        let res = match self
+            .registry
+            .dispatch_as(tenant, msg)
+            .instrument(span.clone())
+            .await
+        {
+            Ok(()) => Ok(Outcome::Stored),
+            Err(MQTTClientError::DataEntryExists(key))
+            | Err(MQTTClientError::Repo(RemRepoError::DataEntryExists(key))) => {
+                info!(parent: &span, "Message {} was already stored", key);
+                Ok(Outcome::Duplicate)
+            }
+            Err(err) => Err(err),
+        };
+        match &res {
+            Ok(outcome) => span.record("outcome", debug(outcome)),
+            Err(err) => span.record("outcome", display(err)),
+        };
+        res
+    }
+
+    /// Handle an entry of the inbox, and complete it unless it has to be retried later. An entry
+    /// that has to be retried is set aside for [`Ingestor::retry_stalled`].
+    pub async fn process(&self, entry: InboxEntry, shutdown: &CancellationToken) -> Outcome {
+        let span = message_span(&entry.msg);
+        info!(parent: &span, "Received message: {:?}", entry.msg);
+
+        let retries = match self.stalled() {
+            0 => TRANSIENT_RETRY_ATTEMPTS,
+            _ => 0,
+        };
+        match self.try_process(entry, span, retries, shutdown).await {
+            Ok(outcome) => outcome,
+            Err(mut entry) => {
+                // It went through the rate limit already
+                entry.backlog = true;
+                self.lock_stalled().push_back(entry);
+                Outcome::Pending
+            }
+        }
+    }
+
+    /// Retry the entries that were set aside, oldest first. This stops at the first entry that
+    /// still can't be stored, since the others would most likely fail the same way.
+    pub async fn retry_stalled(&self, shutdown: &CancellationToken) {
+        let count = self.stalled();
+        if count == 0 {
+            return;
+        }
+
+        info!("Retrying {} messages that couldn't be stored yet", count);
+        for _ in 0..count {
+            if shutdown.is_cancelled() {
+                return;
+            }
+            let Some(entry) = self.lock_stalled().pop_front() else {
+                return;
+            };
+            let span = message_span(&entry.msg);
+            if let Err(entry) = self.try_process(entry, span, 0, shutdown).await {
+                self.lock_stalled().push_front(entry);
+                return;
+            }
+        }
+    }
+
+    /// Number of entries set aside until the database is available again
+    pub fn stalled(&self) -> usize {
+        self.lock_stalled().len()
+    }
+
+    /// Handle an entry, retrying transient errors up to `retries` times, and complete it. Returns
+    /// the entry back if it has to be retried later.
+    async fn try_process(
+        &self,
+        entry: InboxEntry,
+        span: Span,
+        retries: u32,
+        shutdown: &CancellationToken,
+    ) -> Result<Outcome, InboxEntry> {
+        let outcome = self
+            .handle(&entry, retries, shutdown)
+            .instrument(span.clone())
+            .await;
+        span.record("outcome", debug(outcome));
+        if outcome == Outcome::Pending {
+            return Err(entry);
+        }
+
+        if let Some(id) = entry.id {
+            if let Err(e) = self.inbox.complete(id) {
+                error!("Failed to complete inbox entry {}: {}", id, e);
+            }
+        }
+        Ok(outcome)
+    }
+
+    async fn handle(
+        &self,
+        entry: &InboxEntry,
+        retries: u32,
+        shutdown: &CancellationToken,
+    ) -> Outcome {
+        let mut attempt = 0;
+
+        loop {
+            let rate_limited = !entry.backlog && attempt == 0;
+            let err = match self.registry.dispatch(&entry.msg, rate_limited).await {
+                Ok(()) => return Outcome::Stored,
+                Err(err) => err,
+            };
+
+            match &err {
+                MQTTClientError::DataEntryExists(key)
+                | MQTTClientError::Repo(RemRepoError::DataEntryExists(key)) => {
+                    info!("Message {} was already stored", key);
+                    return Outcome::Duplicate;
+                }
+
+                MQTTClientError::Signature(_) => return Outcome::Rejected,
+                MQTTClientError::RateLimited(_) => return Outcome::Dropped,
+
+                MQTTClientError::Repo(repo_err) if repo_err.is_transient() => {
+                    attempt += 1;
+                    if attempt > retries {
+                        error!(
+                            "Giving up on message after {} attempts, it stays in the inbox: {}",
+                            attempt, err
+                        );
+                        return Outcome::Pending;
+                    }
+
+                    let delay = TRANSIENT_RETRY_DELAY * 2u32.pow(attempt - 1);
+                    warn!(
+                        "Transient error handling message, retrying in {:?}: {}",
+                        delay, err
+                    );
+                    select! {
+                        _ = sleep(delay) => continue,
+                        _ = shutdown.cancelled() => return Outcome::Pending,
+                    }
+                }
+
+                _ => return self.dead_letter(entry, &err).await,
+            }
+        }
+    }
+
+    async fn dead_letter(&self, entry: &InboxEntry, err: &MQTTClientError) -> Outcome {
+        warn!("Dead-lettering message on '{}': {}", entry.msg.topic(), err);
+
+        let res = self
+            .repo
+            .lock()
+            .await
+            .insert_dead_letter(entry.msg.topic(), entry.msg.payload(), &err.to_string())
+            .await;
+
+        match res {
+            Ok(()) => Outcome::DeadLettered,
+            Err(e) => {
+                error!(
+                    "Failed to dead-letter message, it stays in the inbox: {}",
+                    e
+                );
+                Outcome::Pending
+            }
+        }
+    }
+
+    fn lock_stalled(&self) -> std::sync::MutexGuard<'_, VecDeque<InboxEntry>> {
+        // Entries are only ever pushed and popped whole, a poisoned lock is safe to reuse
+        self.stalled
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
@@ -1,39 +1,74 @@
 pub mod api;
+pub mod auth;
+pub mod broker;
+pub mod calibration;
+pub mod connection;
+pub mod derived;
+pub mod error;
+pub mod firmware;
+pub mod handler;
+pub mod health;
+pub mod inbox;
+pub mod ingest;
 pub mod model;
 pub mod mqtt;
+pub mod prometheus;
+pub mod ratelimit;
 pub mod repo;
 pub mod schema;
 pub mod settings;
+pub mod signing;
+pub mod telemetry;
 pub mod topic;
 
 use api::server_proc;
-use mqtt::mqtt_proc;
+use broker::{broker_proc, Broker};
+use connection::ConnectionMonitor;
+use firmware::FirmwareStore;
+use handler::{
+    CommandResponseHandler, FirmwareProgressHandler, HandlerRegistry, RemDataHandler,
+    RemStatusHandler,
+};
+use inbox::Inbox;
+use ingest::Ingestor;
+use mqtt::{
+    client_id, connect_options, listener_status_message, listener_status_topic, mqtt_proc,
+    server_uri, ListenerState, MqttPublisher,
+};
+use ratelimit::{RateLimits, RETAIN_INTERVAL};
 use repo::RemRepo;
-use settings::Settings;
-use topic::REM_LISTENER_DISCONNECT_TOPIC;
+use settings::{MqttMode, Settings};
+use signing::Verifier;
+use telemetry::Telemetry;
 
 use dotenv::dotenv;
 use envconfig::Envconfig;
-use paho_mqtt::{
-    properties, AsyncClient, ConnectOptionsBuilder, CreateOptionsBuilder, PropertyCode,
-    MQTT_VERSION_5,
-};
+use paho_mqtt::{AsyncClient, CreateOptionsBuilder};
+use rumqttc::v5::mqttbytes::v5::Login;
 use std::{
+    future::pending,
+    path::Path,
     process::{self, exit},
     sync::Arc,
     time::Duration,
 };
 
-use tokio::{join, sync::Mutex};
-use tracing::{debug, error, info};
-use tracing_subscriber::{fmt, prelude::*, EnvFilter};
-
-use diesel::prelude::*;
+use tokio::{
+    join, select, signal,
+    sync::Mutex,
+    time::{sleep, timeout},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
 
-const MQTT_CLIENT_ID: &str = "room-environment-client-listener";
 const MQTT_CLIENT_FAILED_CONNECTION_ERR: i32 = 4;
 const MQTT_CLIENT_FAILED_SETUP_ERR: i32 = 3;
 const POSTGRES_CONNECTION_ERR: i32 = 5;
+const SHUTDOWN_TIMEOUT_ERR: i32 = 6;
+const INBOX_ERR: i32 = 7;
+const FIRMWARE_DIR_ERR: i32 = 8;
+const METRICS_ERR: i32 = 9;
+const TELEMETRY_ERR: i32 = 10;
 
 #[tokio::main]
 async fn main() {
@@ -43,112 +78,270 @@ async fn main() {
         eprintln!("Error loading .env file: {}", err);
     }
 
-    // Setup tracing subscriber. This will configure the logger to read th
-    // RUST_LOG environment variable.
-    tracing_subscriber::registry()
-        .with(fmt::layer())
-        .with(EnvFilter::from_default_env())
-        .init();
-
     // Safe to unwrap because we know that the environment settings will exist
     #[allow(clippy::unwrap_used)]
     let config = Arc::new(Settings::init_from_env().unwrap());
-    let host = format!("mqtt://{}:{}", config.mqtt_host, config.mqtt_port);
-
-    info!("Connecting to the MQTT server at '{}'...", host);
-
-    // Create the client. Use an ID for a persistent session.
-    // A real system should try harder to use a unique ID.
-    let create_opts = CreateOptionsBuilder::new()
-        .server_uri(host)
-        .client_id(MQTT_CLIENT_ID)
-        .finalize();
-
-    // Create the client connection
-    let mqtt_client_mutex = Arc::new(Mutex::new(AsyncClient::new(create_opts).unwrap_or_else(
-        |e| {
-            error!("Error creating the client: {:?}", e);
-            process::exit(MQTT_CLIENT_FAILED_SETUP_ERR);
-        },
-    )));
-
-    // Message to return to the client if we suddenly loose connector, or we shut
-    // down the listener
-    let lwt = paho_mqtt::Message::new(
-        REM_LISTENER_DISCONNECT_TOPIC,
-        "REM listener disconnection",
-        paho_mqtt::QOS_1,
-    );
 
-    // Connect with MQTT v5 and a persistent server session (no clean start).
-    // For a persistent v5 session, we must set the Session Expiry Interval
-    // on the server. Here we set that requests will persist for an hour
-    // (3600sec) if the service disconnects or restarts.
-    let conn_opts = ConnectOptionsBuilder::with_mqtt_version(MQTT_VERSION_5)
-        .clean_start(false)
-        .properties(properties![PropertyCode::SessionExpiryInterval => 3600])
-        .will_message(lwt)
-        .finalize();
-
-    // Make the connection to the broker, if we fail keep trying until we connect
-    // theoretically the MQTT service could not have been booted yet. Retry for 10 seconds
-    // before restarting the entire service.
-    let mut count = 0;
-    let mqtt_client_lock = mqtt_client_mutex.lock().await;
-    info!("Connecting to the MQTT server ...");
-    loop {
-        if let Err(e) = mqtt_client_lock.connect(conn_opts.clone()).await {
+    // Setup tracing subscriber. This will configure the logger to read the RUST_LOG environment
+    // variable, and export the spans if there is an OTLP collector.
+    let telemetry = Telemetry::init(&config).unwrap_or_else(|e| {
+        eprintln!("Failed to set up the OTLP exporter: {}", e);
+        exit(TELEMETRY_ERR);
+    });
+
+    // Install the metrics recorder before anything records to it, the histograms are compacted
+    // in the background in case nothing scrapes them
+    if let Err(e) = prometheus::install() {
+        error!("Failed to install the metrics recorder: {}", e);
+        exit(METRICS_ERR);
+    }
+    tokio::spawn(async {
+        loop {
+            sleep(prometheus::UPKEEP_INTERVAL).await;
+            prometheus::run_upkeep();
+        }
+    });
+
+    info!("Creating connection to the postgres client ...");
+
+    // Create the postgres connection, it is reestablished by the repo whenever it is lost
+    let repo = match RemRepo::connect(&config.database_url) {
+        Ok(repo) => Arc::new(Mutex::new(repo)),
+        Err(e) => {
             error!(
-                "Failed to connect to the MQTT client with this error: {}",
+                "Failed to setup the connection to the postgres instance: {}",
                 e
             );
-            if count == 10 {
-                error!("Exiting out, not retrying anymore");
-                exit(MQTT_CLIENT_FAILED_CONNECTION_ERR)
-            }
-
-            debug!("Waiting a second and then retrying, on attempt: {}", count);
-            tokio::time::sleep(Duration::from_millis(1000)).await;
+            exit(POSTGRES_CONNECTION_ERR);
+        }
+    };
 
-            count += 1;
-            continue;
+    // Shared by the ingestion and the API, the idle keys are cleaned up in the background
+    let rate_limits = Arc::new(RateLimits::new(&config));
+    let idle_rate_limits = rate_limits.clone();
+    tokio::spawn(async move {
+        loop {
+            sleep(RETAIN_INTERVAL).await;
+            idle_rate_limits.retain_recent();
         }
+    });
+
+    // Register the handlers for every message kind the listener understands
+    let registry = Arc::new(
+        HandlerRegistry::new()
+            .tenant_topics(config.mqtt_tenant_topics)
+            .rate_limits(rate_limits.clone())
+            .verifier(Verifier::new(repo.clone(), config.require_signed_messages))
+            .register(RemDataHandler::new(repo.clone()))
+            .register(RemStatusHandler::new(repo.clone()))
+            .register(CommandResponseHandler::new(repo.clone()))
+            .register(FirmwareProgressHandler::new(repo.clone())),
+    );
 
-        // Exit if we get no error
-        break;
+    // Open the inbox that keeps received messages until they are stored, along with the
+    // messages a previous run didn't get to. A relative path depends on the working directory,
+    // which in a container is rarely on a volume that survives a restart.
+    if Path::new(&config.mqtt_inbox_path).is_relative() {
+        warn!(
+            "The inbox path '{}' is relative, pending messages are lost if the working directory \
+             doesn't persist across restarts. Set MQTT_INBOX_PATH to an absolute path.",
+            config.mqtt_inbox_path
+        );
     }
+    let (inbox, replay) = Inbox::open(&config.mqtt_inbox_path).unwrap_or_else(|e| {
+        error!(
+            "Failed to open the inbox at '{}': {}",
+            config.mqtt_inbox_path, e
+        );
+        exit(INBOX_ERR);
+    });
+    info!(
+        "Opened the inbox at '{}' with {} pending messages",
+        inbox.path().display(),
+        replay.len()
+    );
+    let ingestor = Arc::new(Ingestor::new(registry, repo.clone(), Arc::new(inbox)));
 
-    // Release the lock now otherwise the other sub process like server and mqtt proc will not be able
-    // to get the lock on the mqtt client
-    drop(mqtt_client_lock);
+    // Directory the firmware images are served from
+    let firmware = FirmwareStore::open(&config.firmware_dir)
+        .await
+        .unwrap_or_else(|e| {
+            error!(
+                "Failed to open the firmware directory '{}': {}",
+                config.firmware_dir, e
+            );
+            exit(FIRMWARE_DIR_ERR);
+        });
 
-    info!("Creating connection to the postgres client ...");
+    // State of the MQTT connection, shared with the API
+    let connection = Arc::new(ConnectionMonitor::new());
+
+    // Cancelled when the listener receives SIGTERM or SIGINT
+    let shutdown = CancellationToken::new();
+
+    // Start routine to handle mqtt messages, either from the external MQTT server or from the
+    // devices connected to the embedded broker, along with the API server.
+    let (mut mqtt_handle, publisher) = match config.mqtt_mode {
+        MqttMode::External => {
+            let host = server_uri(&config).unwrap_or_else(|| {
+                error!("MQTT_HOST and MQTT_PORT are required when MQTT_MODE is external");
+                exit(MQTT_CLIENT_FAILED_SETUP_ERR);
+            });
+            info!("Using the MQTT server at '{}'", host);
+
+            // Create the client. Use an ID for a persistent session, every instance of the
+            // listener needs a unique one.
+            let client_id = client_id(&config);
+            info!("Using MQTT client ID '{}'", client_id);
+            let create_opts = CreateOptionsBuilder::new()
+                .server_uri(host)
+                .client_id(client_id.clone())
+                .finalize();
 
-    // Create the postgres connection
-    let pg_connection = PgConnection::establish(&config.database_url);
-    if let Err(e) = pg_connection {
+            // Create the client connection
+            let mqtt_client_mutex = Arc::new(Mutex::new(
+                AsyncClient::new(create_opts).unwrap_or_else(|e| {
+                    error!("Error creating the client: {:?}", e);
+                    process::exit(MQTT_CLIENT_FAILED_SETUP_ERR);
+                }),
+            ));
+
+            // Retained offline status the broker publishes for us if we suddenly loose
+            // the connection
+            let lwt = listener_status_message(
+                &listener_status_topic(&config, &client_id),
+                ListenerState::Offline,
+            );
+
+            // Connect options, including the TLS configuration and credentials if there are any
+            let conn_opts = connect_options(&config, lwt).unwrap_or_else(|e| {
+                error!("Error creating the connect options: {:?}", e);
+                process::exit(MQTT_CLIENT_FAILED_SETUP_ERR);
+            });
+
+            let handle = tokio::spawn(mqtt_proc(
+                mqtt_client_mutex.clone(),
+                conn_opts,
+                ingestor.clone(),
+                replay,
+                connection.clone(),
+                config.clone(),
+                shutdown.clone(),
+            ));
+            (handle, MqttPublisher::Client(mqtt_client_mutex))
+        }
+        MqttMode::Embedded => {
+            if config.mqtt_tls {
+                warn!("The embedded MQTT broker doesn't support TLS, devices connect without it");
+            }
+
+            // Devices have to use the configured credentials, anyone could connect otherwise
+            let credentials = match (&config.mqtt_username, &config.mqtt_password) {
+                (Some(username), password) => {
+                    Some(Login::new(username, password.clone().unwrap_or_default()))
+                }
+                (None, _) if config.mqtt_broker_allow_anonymous => {
+                    warn!("The embedded MQTT broker accepts devices without credentials");
+                    None
+                }
+                (None, _) => {
+                    error!(
+                        "The embedded MQTT broker needs MQTT_USERNAME and MQTT_PASSWORD, or \
+                         MQTT_BROKER_ALLOW_ANONYMOUS to accept devices without credentials"
+                    );
+                    exit(MQTT_CLIENT_FAILED_SETUP_ERR);
+                }
+            };
+
+            let broker = Arc::new(
+                Broker::new(ingestor.registry().topic_filters(), credentials)
+                    .tenant_topics(config.mqtt_tenant_topics),
+            );
+            let handle = tokio::spawn(broker_proc(
+                broker.clone(),
+                ingestor.clone(),
+                repo.clone(),
+                replay,
+                connection.clone(),
+                config.clone(),
+                shutdown.clone(),
+            ));
+            (handle, MqttPublisher::Broker(broker))
+        }
+    };
+    let server_handle = tokio::spawn(server_proc(
+        config.clone(),
+        Arc::new(firmware),
+        publisher,
+        ingestor,
+        connection,
+        rate_limits,
+        repo,
+        shutdown.clone(),
+    ));
+
+    // The MQTT routine only returns on its own when it gives up on connecting to the broker, or
+    // the embedded broker can't listen, exit so the service gets restarted.
+    select! {
+        res = &mut mqtt_handle => {
+            error!("MQTT routine stopped, exiting: {:?}", res);
+            telemetry.shutdown();
+            exit(MQTT_CLIENT_FAILED_CONNECTION_ERR);
+        }
+        _ = shutdown_signal() => {
+            info!("Received shutdown signal, shutting down");
+        }
+    }
+
+    // Stop accepting HTTP requests, handle the MQTT messages we already received and let the
+    // devices know we are gone. Give up if that takes too long.
+    shutdown.cancel();
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout_secs);
+    if timeout(shutdown_timeout, async {
+        join!(mqtt_handle, server_handle)
+    })
+    .await
+    .is_err()
+    {
         error!(
-            "Failed to setup the connection to the postgres instance: {}",
-            e
+            "Graceful shutdown didn't finish within {:?}, exiting",
+            shutdown_timeout
         );
-        exit(POSTGRES_CONNECTION_ERR);
+        telemetry.shutdown();
+        exit(SHUTDOWN_TIMEOUT_ERR);
     }
 
-    // Safe to unwrap because we previously checked the error
-    #[allow(clippy::unwrap_used)]
-    let pg_connection_mutex: Arc<Mutex<PgConnection>> =
-        Arc::new(Mutex::new(pg_connection.unwrap()));
-
-    let repo = Arc::new(Mutex::new(RemRepo::new(pg_connection_mutex.clone())));
-
-    // Start routine to handle mqtt messages from subscribed topics
-    let _ = join!(
-        tokio::spawn(mqtt_proc(mqtt_client_mutex.clone(), repo.clone())),
-        tokio::spawn(server_proc(
-            config,
-            mqtt_client_mutex.clone(),
-            pg_connection_mutex.clone(),
-            repo
-        ))
-    );
+    info!("Shutdown complete");
+    telemetry.shutdown();
+}
+
+/// Resolves when the process receives SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = signal::ctrl_c().await {
+            error!("Failed to listen for SIGINT: {}", e);
+            pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = pending::<()>();
+
+    select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
 }
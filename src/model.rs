@@ -1,7 +1,15 @@
-use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{de::IgnoredAny, Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::{derived::DerivedMetrics, signing::SIGNATURE_PROPERTY};
 
 /// RemStatus is the structure of the status that we receive from the REM device.
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, ToSchema, Debug)]
 pub struct RemStatus {
     pub id: String,
     #[serde(rename = "deviceId")]
@@ -10,10 +18,22 @@ pub struct RemStatus {
     pub up_time: i32,
     #[serde(default)]
     pub rssi: i32,
+
+    /// Version of the firmware the device is running. Older firmware doesn't report it.
+    #[serde(
+        rename = "firmwareVersion",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub firmware_version: Option<String>,
+
+    /// Configuration the device is currently running with. Older firmware doesn't report it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<DeviceConfig>,
 }
 
 /// RemData is the structure of the data that we receive from the REM device.
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, ToSchema, Debug)]
 pub struct RemData {
     pub id: String,
 
@@ -29,4 +49,750 @@ pub struct RemData {
 
     #[serde(rename = "vocIndex")]
     pub voc_index: f32,
+
+    // Channels only reported by newer hardware revisions. Older devices omit them.
+    /// CO2 concentration in ppm
+    #[serde(default)]
+    pub co2: Option<f32>,
+
+    /// NOx index, on the same scale as the VOC index
+    #[serde(rename = "noxIndex", default)]
+    pub nox_index: Option<f32>,
+
+    /// Illuminance in lux
+    #[serde(default)]
+    pub illuminance: Option<f32>,
+
+    /// Sound level in dBA
+    #[serde(rename = "soundLevel", default)]
+    pub sound_level: Option<f32>,
+
+    /// Any payload fields that aren't known channels. These are stored in the generic readings
+    /// store rather than being dropped, see [`RemData::extra_metrics`].
+    #[serde(flatten, skip_serializing)]
+    pub extra: HashMap<String, Value>,
+
+    /// Metrics derived from the channels above. These are computed when the data is read back, they
+    /// are never part of the device payload.
+    #[serde(
+        default,
+        deserialize_with = "ignore_derived",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(read_only)]
+    pub derived: Option<DerivedMetrics>,
+}
+
+/// Drop derived metrics sent by a device, they are always computed by the listener.
+fn ignore_derived<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<DerivedMetrics>, D::Error> {
+    IgnoredAny::deserialize(deserializer)?;
+    Ok(None)
+}
+
+/// Uncalibrated channel values of a REM data point, keyed by the channel name.
+pub type RawChannels = BTreeMap<String, f32>;
+
+impl RemData {
+    /// Names of the numeric channels of REM data, as used in the device payload.
+    pub const CHANNELS: &'static [&'static str] = &[
+        "pm2_5",
+        "pm1_0",
+        "pm10",
+        "temperature",
+        "humidity",
+        "pressure",
+        "vocIndex",
+        "co2",
+        "noxIndex",
+        "illuminance",
+        "soundLevel",
+    ];
+
+    /// Value of a channel by name. Optional channels the device didn't report return `None`, same as
+    /// unknown channel names.
+    pub fn channel(&self, name: &str) -> Option<f32> {
+        match name {
+            "pm2_5" => Some(self.pm2_5),
+            "pm1_0" => Some(self.pm1_0),
+            "pm10" => Some(self.pm10),
+            "temperature" => Some(self.temperature),
+            "humidity" => Some(self.humidity),
+            "pressure" => Some(self.pressure),
+            "vocIndex" => Some(self.voc_index),
+            "co2" => self.co2,
+            "noxIndex" => self.nox_index,
+            "illuminance" => self.illuminance,
+            "soundLevel" => self.sound_level,
+            _ => None,
+        }
+    }
+
+    /// Mutable reference to a channel value by name, see [`RemData::channel`].
+    fn channel_mut(&mut self, name: &str) -> Option<&mut f32> {
+        match name {
+            "pm2_5" => Some(&mut self.pm2_5),
+            "pm1_0" => Some(&mut self.pm1_0),
+            "pm10" => Some(&mut self.pm10),
+            "temperature" => Some(&mut self.temperature),
+            "humidity" => Some(&mut self.humidity),
+            "pressure" => Some(&mut self.pressure),
+            "vocIndex" => Some(&mut self.voc_index),
+            "co2" => self.co2.as_mut(),
+            "noxIndex" => self.nox_index.as_mut(),
+            "illuminance" => self.illuminance.as_mut(),
+            "soundLevel" => self.sound_level.as_mut(),
+            _ => None,
+        }
+    }
+
+    /// The current value of every channel the device reported.
+    pub fn raw_channels(&self) -> RawChannels {
+        Self::CHANNELS
+            .iter()
+            .filter_map(|name| self.channel(name).map(|value| (name.to_string(), value)))
+            .collect()
+    }
+
+    /// Set the channels to the calibrated version of the raw values. Channels without a
+    /// calibration are reset to their raw value, so this can be reapplied with revised calibrations.
+    pub fn calibrate(&mut self, raw: &RawChannels, calibrations: &[Calibration]) {
+        for (name, raw_value) in raw {
+            let calibrated = calibrations
+                .iter()
+                .find(|c| &c.field == name)
+                .map_or(*raw_value, |c| raw_value * c.scale + c.offset);
+
+            if let Some(value) = self.channel_mut(name) {
+                *value = calibrated;
+            }
+        }
+    }
+
+    /// Convert the unknown payload fields into metric values. A field can either be a plain
+    /// number, or an object with a numeric `value` and an optional `unit`, for example
+    /// `{"radon": {"value": 12.5, "unit": "Bq/m3"}}`. Fields of any other shape are skipped, and so
+    /// is the signature of a signed payload.
+    pub fn extra_metrics(&self) -> Vec<MetricValue> {
+        self.extra
+            .iter()
+            .filter(|(metric, _)| metric.as_str() != SIGNATURE_PROPERTY)
+            .filter_map(|(metric, value)| {
+                let (value, unit) = match value {
+                    Value::Number(n) => (n.as_f64(), None),
+                    Value::Object(obj) => (
+                        obj.get("value").and_then(Value::as_f64),
+                        obj.get("unit").and_then(Value::as_str).map(str::to_string),
+                    ),
+                    _ => (None, None),
+                };
+
+                match value {
+                    Some(value) => Some(MetricValue {
+                        metric: metric.clone(),
+                        value,
+                        unit,
+                    }),
+                    None => {
+                        warn!(
+                            "Skipping field '{}' of data {}, it isn't a numeric value",
+                            metric, self.id
+                        );
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+/// Calibration is the linear correction applied to a single channel of a device,
+/// `calibrated = raw * scale + offset`.
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone)]
+pub struct Calibration {
+    #[serde(rename = "deviceId")]
+    pub device_id: String,
+
+    /// Name of the channel, as used in the device payload
+    pub field: String,
+
+    pub offset: f32,
+    pub scale: f32,
+}
+
+/// MetricValue is a single named value from a REM data payload that isn't one of the known channels.
+#[derive(Debug, PartialEq)]
+pub struct MetricValue {
+    pub metric: String,
+    pub value: f64,
+    pub unit: Option<String>,
+}
+
+/// Reading is a single value of a metric reported by a REM device at a point in time.
+#[derive(Deserialize, Serialize, ToSchema, Debug)]
+pub struct Reading {
+    #[serde(rename = "deviceId")]
+    pub device_id: String,
+    pub metric: String,
+    pub value: f64,
+    pub unit: Option<String>,
+    #[serde(rename = "createdAt")]
+    pub created_at: NaiveDateTime,
+}
+
+/// RemDataAggregate is the average of the REM data channels of a device over a time bucket.
+#[derive(Deserialize, Serialize, ToSchema, Debug)]
+pub struct RemDataAggregate {
+    #[serde(rename = "deviceId")]
+    pub device_id: String,
+
+    /// Start of the time bucket
+    pub bucket: NaiveDateTime,
+
+    /// Number of data points in the bucket
+    pub count: i64,
+
+    pub pm2_5: f32,
+    pub pm1_0: f32,
+    pub pm10: f32,
+    pub temperature: f32,
+    pub humidity: f32,
+    pub pressure: f32,
+
+    #[serde(rename = "vocIndex")]
+    pub voc_index: f32,
+
+    pub co2: Option<f32>,
+
+    #[serde(rename = "noxIndex")]
+    pub nox_index: Option<f32>,
+
+    pub illuminance: Option<f32>,
+
+    #[serde(rename = "soundLevel")]
+    pub sound_level: Option<f32>,
+
+    /// Metrics derived from the averaged channels
+    pub derived: DerivedMetrics,
+}
+
+/// DeviceCommand is a command that can be sent to a REM device, along with its parameters.
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone, PartialEq)]
+#[serde(tag = "command", content = "params", rename_all = "camelCase")]
+pub enum DeviceCommand {
+    /// Change how often the device publishes its data
+    SetReportingInterval {
+        seconds: u32,
+    },
+    Reboot,
+    /// Run the cleaning cycle of the particulate matter sensor
+    SelfClean,
+}
+
+/// Status of a command sent to a REM device.
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CommandStatus {
+    /// Stored, but not yet published
+    Pending,
+    /// Published to the device, which didn't acknowledge it yet
+    Sent,
+    /// The device received the command and is executing it
+    Accepted,
+    Completed,
+    /// The command couldn't be published, or the device failed to execute it
+    Failed,
+    /// The device doesn't support the command or its parameters
+    Rejected,
+}
+
+impl CommandStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommandStatus::Pending => "pending",
+            CommandStatus::Sent => "sent",
+            CommandStatus::Accepted => "accepted",
+            CommandStatus::Completed => "completed",
+            CommandStatus::Failed => "failed",
+            CommandStatus::Rejected => "rejected",
+        }
+    }
+
+    /// Parse the name of a status, as returned by [`CommandStatus::as_str`].
+    pub fn parse(status: &str) -> Option<Self> {
+        match status {
+            "pending" => Some(CommandStatus::Pending),
+            "sent" => Some(CommandStatus::Sent),
+            "accepted" => Some(CommandStatus::Accepted),
+            "completed" => Some(CommandStatus::Completed),
+            "failed" => Some(CommandStatus::Failed),
+            "rejected" => Some(CommandStatus::Rejected),
+            _ => None,
+        }
+    }
+
+    /// Whether the command is done, its status doesn't change anymore after this.
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            CommandStatus::Completed | CommandStatus::Failed | CommandStatus::Rejected
+        )
+    }
+}
+
+/// Command is a command sent to a REM device and the last known state of it.
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone)]
+pub struct Command {
+    pub id: String,
+
+    #[serde(rename = "deviceId")]
+    pub device_id: String,
+
+    /// Name of the command, like `reboot`
+    pub command: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub params: Option<Value>,
+
+    pub status: CommandStatus,
+
+    /// Payload of the last acknowledgement of the device
+    pub response: Option<Value>,
+
+    #[serde(rename = "createdAt")]
+    pub created_at: NaiveDateTime,
+
+    #[serde(rename = "updatedAt")]
+    pub updated_at: NaiveDateTime,
+}
+
+impl Command {
+    /// A new pending command for the device, with a random id.
+    pub fn new(device_id: String, command: &DeviceCommand) -> Self {
+        // The tagged representation is an object with the name and optionally the parameters
+        let mut value = serde_json::to_value(command).unwrap_or_default();
+        let name = value
+            .get("command")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        let params = value.as_object_mut().and_then(|o| o.remove("params"));
+
+        let now = chrono::Utc::now().naive_utc();
+        Command {
+            id: uuid::Uuid::new_v4().to_string(),
+            device_id,
+            command: name,
+            params,
+            status: CommandStatus::Pending,
+            response: None,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+}
+
+/// CommandResponse is the acknowledgement a REM device publishes on the response topic of a
+/// command. The command is identified by the correlation data of the message.
+#[derive(Deserialize, Serialize, ToSchema, Debug)]
+pub struct CommandResponse {
+    /// One of `accepted`, `completed`, `failed` or `rejected`
+    pub status: CommandStatus,
+
+    /// Optional human readable details, like the reason of a failure
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+
+    /// Optional result of the command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+
+    /// When the device sent the acknowledgement, required when the device has a key so that it
+    /// can't be replayed
+    #[serde(rename = "sentAt", default, skip_serializing_if = "Option::is_none")]
+    pub sent_at: Option<DateTime<Utc>>,
+}
+
+/// DeviceConfig is the configuration of a REM device. Settings that are `None` are left at the
+/// device's default, or weren't reported by it.
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone, Default, PartialEq)]
+pub struct DeviceConfig {
+    /// Seconds between two data messages
+    #[serde(rename = "reportingInterval", skip_serializing_if = "Option::is_none")]
+    pub reporting_interval: Option<u32>,
+
+    /// Brightness of the status LED in percent, 0 turns it off
+    #[serde(rename = "ledBrightness", skip_serializing_if = "Option::is_none")]
+    pub led_brightness: Option<u8>,
+
+    /// Signal strength in dBm below which the device looks for a better access point
+    #[serde(rename = "wifiMinRssi", skip_serializing_if = "Option::is_none")]
+    pub wifi_min_rssi: Option<i32>,
+
+    /// Number of failed Wi-Fi reconnects after which the device reboots
+    #[serde(
+        rename = "wifiMaxReconnectAttempts",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub wifi_max_reconnect_attempts: Option<u32>,
+}
+
+impl DeviceConfig {
+    /// Check that the values are in range, returns a description of the first invalid one.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.reporting_interval == Some(0) {
+            return Err("reportingInterval must be at least 1 second".to_string());
+        }
+        if self.led_brightness.is_some_and(|b| b > 100) {
+            return Err("ledBrightness must be between 0 and 100".to_string());
+        }
+        if self.wifi_min_rssi.is_some_and(|rssi| rssi > 0) {
+            return Err("wifiMinRssi must be negative".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// The settings of this desired configuration that the reported configuration doesn't match.
+    /// Settings that aren't part of the desired configuration are never drifted.
+    pub fn drift(&self, reported: Option<&DeviceConfig>) -> Vec<ConfigDrift> {
+        let desired = config_fields(self);
+        let reported = reported.map(config_fields).unwrap_or_default();
+
+        desired
+            .into_iter()
+            .filter_map(|(field, desired)| {
+                let reported = reported.get(&field).cloned();
+                (reported.as_ref() != Some(&desired)).then_some(ConfigDrift {
+                    field,
+                    desired,
+                    reported,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The set settings of a configuration, keyed by their payload name
+fn config_fields(config: &DeviceConfig) -> serde_json::Map<String, Value> {
+    match serde_json::to_value(config) {
+        Ok(Value::Object(fields)) => fields,
+        _ => Default::default(),
+    }
+}
+
+/// ConfigDrift is a setting whose reported value differs from the desired one.
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct ConfigDrift {
+    pub field: String,
+    pub desired: Value,
+
+    /// `None` if the device didn't report the setting
+    pub reported: Option<Value>,
+}
+
+/// DeviceConfigState is the desired and reported configuration of a device, along with the drift
+/// between them.
+#[derive(Deserialize, Serialize, ToSchema, Debug)]
+pub struct DeviceConfigState {
+    #[serde(rename = "deviceId")]
+    pub device_id: String,
+
+    pub desired: Option<DeviceConfig>,
+    #[serde(rename = "desiredAt")]
+    pub desired_at: Option<NaiveDateTime>,
+
+    pub reported: Option<DeviceConfig>,
+    #[serde(rename = "reportedAt")]
+    pub reported_at: Option<NaiveDateTime>,
+
+    pub drift: Vec<ConfigDrift>,
+
+    /// Whether the device reported every desired setting
+    #[serde(rename = "inSync")]
+    pub in_sync: bool,
+}
+
+/// Device is a REM device known to the listener.
+#[derive(Deserialize, Serialize, ToSchema, Debug)]
+pub struct Device {
+    #[serde(rename = "deviceId")]
+    pub device_id: String,
+
+    /// Group used to roll out firmware to several devices at once
+    pub group: Option<String>,
+
+    /// Firmware version the device reported last
+    #[serde(rename = "firmwareVersion")]
+    pub firmware_version: Option<String>,
+
+    #[serde(rename = "updatedAt")]
+    pub updated_at: NaiveDateTime,
+}
+
+/// FirmwareImage is an uploaded firmware image, the image itself is stored on disk.
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone)]
+pub struct FirmwareImage {
+    pub version: String,
+
+    /// Size of the image in bytes
+    pub size: i64,
+
+    /// Hex encoded SHA-256 checksum of the image
+    pub sha256: String,
+
+    #[serde(rename = "createdAt")]
+    pub created_at: NaiveDateTime,
+}
+
+/// Status of a firmware update of a device.
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FirmwareUpdateStatus {
+    /// The update was assigned, but the device wasn't notified yet
+    Assigned,
+    /// The device was notified of the update
+    Notified,
+    Downloading,
+    Verifying,
+    Installing,
+    /// The device installed the update and is about to reboot into it
+    Installed,
+    Failed,
+    /// The device reported the target version in its status after rebooting
+    Verified,
+}
+
+impl FirmwareUpdateStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FirmwareUpdateStatus::Assigned => "assigned",
+            FirmwareUpdateStatus::Notified => "notified",
+            FirmwareUpdateStatus::Downloading => "downloading",
+            FirmwareUpdateStatus::Verifying => "verifying",
+            FirmwareUpdateStatus::Installing => "installing",
+            FirmwareUpdateStatus::Installed => "installed",
+            FirmwareUpdateStatus::Failed => "failed",
+            FirmwareUpdateStatus::Verified => "verified",
+        }
+    }
+
+    /// Parse the name of a status, as returned by [`FirmwareUpdateStatus::as_str`].
+    pub fn parse(status: &str) -> Option<Self> {
+        match status {
+            "assigned" => Some(FirmwareUpdateStatus::Assigned),
+            "notified" => Some(FirmwareUpdateStatus::Notified),
+            "downloading" => Some(FirmwareUpdateStatus::Downloading),
+            "verifying" => Some(FirmwareUpdateStatus::Verifying),
+            "installing" => Some(FirmwareUpdateStatus::Installing),
+            "installed" => Some(FirmwareUpdateStatus::Installed),
+            "failed" => Some(FirmwareUpdateStatus::Failed),
+            "verified" => Some(FirmwareUpdateStatus::Verified),
+            _ => None,
+        }
+    }
+}
+
+/// FirmwareUpdate is the firmware update assigned to a device and its progress.
+#[derive(Deserialize, Serialize, ToSchema, Debug)]
+pub struct FirmwareUpdate {
+    #[serde(rename = "deviceId")]
+    pub device_id: String,
+
+    #[serde(rename = "targetVersion")]
+    pub target_version: String,
+
+    pub status: FirmwareUpdateStatus,
+
+    /// Progress of the current step in percent, if the device reports it
+    pub progress: Option<i16>,
+
+    /// Details the device reported, like the reason of a failure
+    pub message: Option<String>,
+
+    #[serde(rename = "createdAt")]
+    pub created_at: NaiveDateTime,
+
+    #[serde(rename = "updatedAt")]
+    pub updated_at: NaiveDateTime,
+}
+
+/// FirmwareProgress is the progress of a firmware update a REM device reports while updating.
+#[derive(Deserialize, Serialize, ToSchema, Debug)]
+pub struct FirmwareProgress {
+    /// The version being installed
+    pub version: String,
+
+    /// One of `downloading`, `verifying`, `installing`, `installed` or `failed`
+    pub status: FirmwareUpdateStatus,
+
+    /// Percentage of the current step that is done, from 0 to 100
+    #[serde(default)]
+    #[schema(maximum = 100)]
+    pub progress: Option<u8>,
+
+    #[serde(default)]
+    pub message: Option<String>,
+
+    /// When the device sent the progress, required when the device has a key so that it can't be
+    /// replayed
+    #[serde(rename = "sentAt", default)]
+    pub sent_at: Option<DateTime<Utc>>,
+}
+
+/// Tenant of everything that existed before tenants, and of devices that aren't registered
+/// with another tenant. This is also the tenant of the operator of the listener.
+pub const DEFAULT_TENANT: &str = "default";
+
+/// Tenant is a customer sharing the listener. Devices, their data and API keys belong to exactly
+/// one tenant, and API callers only see the data of their own tenant.
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone)]
+pub struct Tenant {
+    pub id: String,
+    pub name: String,
+
+    #[serde(rename = "createdAt")]
+    pub created_at: NaiveDateTime,
+}
+
+/// Permission granted to an API key.
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ApiScope {
+    /// Read the stored data, device state and configuration
+    ReadData,
+    /// Post data to the ingestion API
+    WriteData,
+    /// Send commands, configuration and firmware updates to devices
+    DeviceCommand,
+    /// Manage API keys, calibrations, firmware images and device groups. Grants all other scopes.
+    Admin,
+}
+
+impl ApiScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiScope::ReadData => "read-data",
+            ApiScope::WriteData => "write-data",
+            ApiScope::DeviceCommand => "device-command",
+            ApiScope::Admin => "admin",
+        }
+    }
+
+    /// Parse the name of a scope, as returned by [`ApiScope::as_str`].
+    pub fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "read-data" => Some(ApiScope::ReadData),
+            "write-data" => Some(ApiScope::WriteData),
+            "device-command" => Some(ApiScope::DeviceCommand),
+            "admin" => Some(ApiScope::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// ApiKey is a key used to authenticate with the API. The key itself is never stored.
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+
+    /// The tenant whose data the key gives access to
+    #[serde(rename = "tenantId")]
+    pub tenant_id: String,
+
+    /// Start of the key, to recognize it by
+    pub prefix: String,
+
+    pub scopes: Vec<ApiScope>,
+
+    #[serde(rename = "createdAt")]
+    pub created_at: NaiveDateTime,
+
+    #[serde(rename = "revokedAt")]
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+impl ApiKey {
+    /// Whether the key grants a scope, admin keys grant every scope.
+    pub fn allows(&self, scope: ApiScope) -> bool {
+        self.scopes
+            .iter()
+            .any(|s| *s == scope || *s == ApiScope::Admin)
+    }
+
+    /// Whether this is an admin key of the operator, which manages the tenants and the shared
+    /// firmware images.
+    pub fn is_operator(&self) -> bool {
+        self.tenant_id == DEFAULT_TENANT && self.allows(ApiScope::Admin)
+    }
+}
+
+/// Algorithm a device signs its messages with.
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SignatureAlgorithm {
+    /// HMAC-SHA256 with a secret shared between the device and the listener
+    HmacSha256,
+    /// Ed25519 signature, the listener only knows the public key of the device
+    Ed25519,
+}
+
+impl SignatureAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SignatureAlgorithm::HmacSha256 => "hmac-sha256",
+            SignatureAlgorithm::Ed25519 => "ed25519",
+        }
+    }
+
+    /// Parse the name of an algorithm, as returned by [`SignatureAlgorithm::as_str`].
+    pub fn parse(algorithm: &str) -> Option<Self> {
+        match algorithm {
+            "hmac-sha256" => Some(SignatureAlgorithm::HmacSha256),
+            "ed25519" => Some(SignatureAlgorithm::Ed25519),
+            _ => None,
+        }
+    }
+}
+
+/// DeviceKey is the key the messages of a device are verified with. Once a device has a key its
+/// messages are only stored when they are signed with it. The key itself is never returned.
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct DeviceKey {
+    #[serde(rename = "deviceId")]
+    pub device_id: String,
+
+    pub algorithm: SignatureAlgorithm,
+
+    /// The shared secret or the public key of the device
+    #[serde(skip)]
+    pub key: Vec<u8>,
+
+    #[serde(rename = "createdAt")]
+    pub created_at: NaiveDateTime,
+}
+
+/// RejectedMessage is a message that wasn't stored because it isn't signed, its signature doesn't
+/// match the key of the device or it was sent before the last one of the device.
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct RejectedMessage {
+    pub id: i64,
+
+    #[serde(rename = "deviceId")]
+    pub device_id: String,
+
+    pub topic: String,
+
+    /// `unsigned`, `invalid` or `replayed`
+    pub reason: String,
+
+    pub error: String,
+
+    /// The payload as received, invalid UTF-8 is replaced
+    pub payload: String,
+
+    #[serde(rename = "createdAt")]
+    pub created_at: NaiveDateTime,
 }
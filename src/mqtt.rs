@@ -1,25 +1,41 @@
 use std::sync::Arc;
 
 use anyhow::Result;
-use futures::stream::StreamExt;
 
+use serde::Serialize;
+use serde_json::json;
 use thiserror::Error;
 use tokio::{
-    sync::Mutex,
-    time::{sleep, Duration},
+    select,
+    sync::{
+        mpsc::{self, Receiver},
+        Mutex,
+    },
+    time::{interval, sleep, MissedTickBehavior},
 };
-use tracing::{error, info, warn};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
 
-use paho_mqtt::{AsyncClient, Message, SubscribeOptions};
+use paho_mqtt::{
+    properties, AsyncClient, ConnectOptions, ConnectOptionsBuilder, Message, MessageBuilder,
+    PropertyCode, ServerResponse, SslOptionsBuilder, SubscribeOptions, MQTT_VERSION_5, QOS_1,
+};
 
-use crate::topic::{REM_DATA_TOPIC, REM_STATUS_TOPIC};
 use crate::{
-    model::{RemData, RemStatus},
+    api::VersionResponse,
+    broker::Broker,
+    connection::{ConnectionMonitor, ConnectionState, ReconnectPolicy},
+    handler::HandlerRegistry,
+    inbox::{Inbox, InboxEntry},
+    ingest::{Ingestor, STALLED_RETRY_INTERVAL},
+    model::{Command, DeviceConfig, FirmwareImage},
     repo::RemRepoError,
-};
-use crate::{
-    repo::RemRepo,
-    topic::{QOS, SUBSCRIBED_TOPICS},
+    settings::Settings,
+    signing::SignatureError,
+    topic::{
+        command_response_topic, command_topic, config_topic, ota_topic, replica_status_topic,
+        shared_subscription, tenant_topic, REM_LISTENER_STATUS_TOPIC,
+    },
 };
 
 #[derive(Error, Debug)]
@@ -32,91 +48,454 @@ pub enum MQTTClientError {
     InvalidMessage,
     #[error("Unsupported message type: {}", .0)]
     UnsupportedMessage(String),
+    #[error("{}", .0)]
+    Signature(#[from] SignatureError),
+    #[error("Device {} is over its rate limit", .0)]
+    RateLimited(String),
+}
+
+/// Whether the listener is available to process messages
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerState {
+    Online,
+    Offline,
+}
+
+/// ListenerStatus is the retained message the listener publishes to [`REM_LISTENER_STATUS_TOPIC`].
+/// Devices use it to decide whether to buffer their readings locally.
+#[derive(Serialize, Debug)]
+pub struct ListenerStatus {
+    pub status: ListenerState,
+    #[serde(flatten)]
+    pub version: VersionResponse,
+}
+
+/// Time the offline status of a replica is retained for. Replicas with a random client ID suffix
+/// never come back under the same ID, their statuses shouldn't pile up on the server.
+const REPLICA_OFFLINE_EXPIRY_SECS: i32 = 24 * 60 * 60;
+
+/// The topic the listener publishes its status to. Replicas in a shared subscription group each
+/// publish to their own topic, so the last will of one of them doesn't tell the devices that the
+/// whole listener is offline while the others are still consuming.
+pub fn listener_status_topic(config: &Settings, client_id: &str) -> String {
+    match config.mqtt_shared_group {
+        Some(_) => replica_status_topic(client_id),
+        None => REM_LISTENER_STATUS_TOPIC.to_string(),
+    }
+}
+
+/// The retained status message of the listener in the given state, published to `topic`.
+pub fn listener_status_message(topic: &str, state: ListenerState) -> Message {
+    let status = ListenerStatus {
+        status: state,
+        version: VersionResponse::current(),
+    };
+
+    // Serializing a struct of strings can't fail
+    let payload = serde_json::to_vec(&status).unwrap_or_default();
+    if state == ListenerState::Offline && topic != REM_LISTENER_STATUS_TOPIC {
+        return MessageBuilder::new()
+            .topic(topic)
+            .payload(payload)
+            .qos(QOS_1)
+            .retained(true)
+            .properties(properties![
+                PropertyCode::MessageExpiryInterval => REPLICA_OFFLINE_EXPIRY_SECS
+            ])
+            .finalize();
+    }
+    Message::new_retained(topic, payload, QOS_1)
+}
+
+/// The message that sends a command to its device. The response topic and correlation data tell the
+/// device where to publish its acknowledgements and how to identify the command in them. With a
+/// `tenant` both topics are prefixed with it.
+pub fn command_message(tenant: Option<&str>, command: &Command) -> Message {
+    let mut payload = json!({
+        "id": command.id,
+        "command": command.command,
+    });
+    if let Some(params) = &command.params {
+        payload["params"] = params.clone();
+    }
+
+    let props = properties![
+        PropertyCode::ResponseTopic => tenant_topic(tenant, &command_response_topic(&command.device_id)),
+        PropertyCode::CorrelationData => command.id.as_bytes().to_vec()
+    ];
+
+    MessageBuilder::new()
+        .topic(tenant_topic(tenant, &command_topic(&command.device_id)))
+        .payload(payload.to_string())
+        .qos(QOS_1)
+        .properties(props)
+        .finalize()
+}
+
+/// The retained message with the desired configuration of a device.
+pub fn config_message(tenant: Option<&str>, device_id: &str, config: &DeviceConfig) -> Message {
+    // Serializing a struct of numbers can't fail
+    let payload = serde_json::to_vec(config).unwrap_or_default();
+    Message::new_retained(
+        tenant_topic(tenant, &config_topic(device_id)),
+        payload,
+        QOS_1,
+    )
 }
 
-/// Handle the message from the MQTT server. This function will determine if the message is a status
-/// message or a data message and then create the appropriate table row.
-async fn handle_message(repo: &Arc<Mutex<RemRepo>>, msg: Message) -> Result<(), MQTTClientError> {
-    let topic = msg.topic();
-    match topic {
-        REM_DATA_TOPIC => {
-            let data: RemData = serde_json::from_slice(msg.payload())
-                .map_err(|_| MQTTClientError::InvalidMessage)?;
+/// The retained message that notifies a device of a firmware update. The device downloads the
+/// image from `url` and verifies it against the checksum before installing it.
+pub fn ota_message(
+    tenant: Option<&str>,
+    device_id: &str,
+    image: &FirmwareImage,
+    url: &str,
+) -> Message {
+    let payload = json!({
+        "version": image.version,
+        "url": url,
+        "sha256": image.sha256,
+        "size": image.size,
+    });
+    let topic = tenant_topic(tenant, &ota_topic(device_id));
+    Message::new_retained(topic, payload.to_string(), QOS_1)
+}
 
-            info!("ID: {}, Device ID: {}", data.id, data.device_id);
+/// Publishes the messages of the API, either through the client connected to the external MQTT
+/// server or straight to the clients of the embedded broker.
+#[derive(Clone)]
+pub enum MqttPublisher {
+    Client(Arc<Mutex<AsyncClient>>),
+    Broker(Arc<Broker>),
+}
 
-            repo.lock()
-                .await
-                .insert_rem_data(data)
-                .await
-                .map_err(MQTTClientError::Repo)
+impl MqttPublisher {
+    /// Publish a message, waiting for the MQTT server to acknowledge it.
+    pub async fn publish(&self, msg: Message) -> paho_mqtt::Result<()> {
+        match self {
+            MqttPublisher::Client(cli) => {
+                let token = cli.lock().await.publish(msg);
+                token.await
+            }
+            MqttPublisher::Broker(broker) => {
+                broker.publish_message(&msg);
+                Ok(())
+            }
         }
+    }
+}
 
-        REM_STATUS_TOPIC => {
-            let status: RemStatus = match serde_json::from_slice(msg.payload()) {
-                Ok(s) => s,
-                Err(e) => {
-                    info!("{:?}", msg.payload());
-                    error!("Error parsing status message: {:?}", e);
-                    return Err(MQTTClientError::InvalidMessage);
-                }
-            };
-
-            info!(
-                "ID: {}, Device ID: {}, Uptime: {}",
-                status.id, status.device_id, status.up_time
-            );
-
-            repo.lock()
-                .await
-                .insert_rem_status(status)
-                .await
-                .map_err(MQTTClientError::Repo)
+/// Publish the retained status of the listener.
+async fn publish_status(cli: &Arc<Mutex<AsyncClient>>, topic: &str, state: ListenerState) {
+    let token = cli
+        .lock()
+        .await
+        .publish(listener_status_message(topic, state));
+    if let Err(e) = token.await {
+        error!("Failed to publish the {:?} status: {}", state, e);
+    }
+}
+
+/// Publish the offline status and disconnect from the MQTT server. Since this is a clean disconnect
+/// the server doesn't send the last will, so the status is published explicitly.
+async fn disconnect(
+    cli: &Arc<Mutex<AsyncClient>>,
+    status_topic: &str,
+    monitor: &ConnectionMonitor,
+) {
+    if monitor.is_connected() {
+        publish_status(cli, status_topic, ListenerState::Offline).await;
+    }
+
+    let token = cli.lock().await.disconnect(None);
+    if let Err(e) = token.await {
+        error!("Failed to disconnect from the MQTT server: {}", e);
+    }
+
+    monitor.transition(ConnectionState::Disconnected);
+}
+
+/// Build the URI of the MQTT server from the settings, using the `mqtts://` scheme when TLS is enabled.
+/// Returns `None` if the host or the port isn't set.
+pub fn server_uri(config: &Settings) -> Option<String> {
+    let scheme = if config.mqtt_tls { "mqtts" } else { "mqtt" };
+    let host = config.mqtt_host.as_ref()?;
+    let port = config.mqtt_port?;
+    Some(format!("{}://{}:{}", scheme, host, port))
+}
+
+/// The client ID from the settings, with a random suffix appended when enabled.
+pub fn client_id(config: &Settings) -> String {
+    if config.mqtt_client_id_suffix {
+        let suffix = uuid::Uuid::new_v4().simple().to_string();
+        format!("{}-{}", config.mqtt_client_id, &suffix[..8])
+    } else {
+        config.mqtt_client_id.clone()
+    }
+}
+
+/// Build the options used to connect to the MQTT server, including the TLS configuration and
+/// credentials from the settings. This fails if any of the configured certificate files don't exist.
+pub fn connect_options(config: &Settings, lwt: Message) -> paho_mqtt::Result<ConnectOptions> {
+    // Connect with MQTT v5 and a persistent server session (no clean start).
+    // For a persistent v5 session, we must set the Session Expiry Interval
+    // on the server. Here we set that requests will persist for an hour
+    // (3600sec) if the service disconnects or restarts.
+    let mut builder = ConnectOptionsBuilder::with_mqtt_version(MQTT_VERSION_5);
+    builder
+        .clean_start(false)
+        .properties(properties![PropertyCode::SessionExpiryInterval => 3600])
+        .will_message(lwt);
+
+    if config.mqtt_tls {
+        let mut ssl = SslOptionsBuilder::new();
+        if let Some(ca_file) = &config.mqtt_ca_file {
+            ssl.trust_store(ca_file)?;
+        }
+        if let Some(cert_file) = &config.mqtt_client_cert_file {
+            ssl.key_store(cert_file)?;
         }
+        if let Some(key_file) = &config.mqtt_client_key_file {
+            ssl.private_key(key_file)?;
+        }
+        if let Some(key_password) = &config.mqtt_client_key_password {
+            ssl.private_key_password(key_password);
+        }
+        builder.ssl_options(ssl.finalize());
+    }
 
-        _ => Err(MQTTClientError::UnsupportedMessage(topic.to_string())),
+    if let Some(username) = &config.mqtt_username {
+        builder.user_name(username);
     }
+    if let Some(password) = &config.mqtt_password {
+        builder.password(password);
+    }
+
+    Ok(builder.finalize())
+}
+
+/// Subscribe to the topic filter of every registered handler. With a shared group the broker
+/// only delivers each message to one of the listeners in the group.
+async fn subscribe(
+    cli: &Arc<Mutex<AsyncClient>>,
+    registry: &HandlerRegistry,
+    shared_group: &Option<String>,
+) -> paho_mqtt::Result<()> {
+    let mut topics = registry.topic_filters();
+    if let Some(group) = shared_group {
+        topics = topics
+            .iter()
+            .map(|filter| shared_subscription(group, filter))
+            .collect();
+    }
+
+    info!("Subscribing to topics: {:?}", topics);
+    let sub_opts = vec![SubscribeOptions::with_retain_as_published(); topics.len()];
+    let token =
+        cli.lock()
+            .await
+            .subscribe_many_with_options(&topics, &registry.qos(), &sub_opts, None);
+    token.await?;
+
+    Ok(())
+}
+
+/// Connect to the MQTT server, retrying according to the reconnect policy. The initial connection
+/// passes the connect options, a reconnection reuses the options of the last connection.
+///
+/// The client lock is only held to start an attempt, not while waiting on the server or the
+/// backoff delay, so the rest of the application isn't blocked while the server is unreachable.
+async fn connect_with_retry(
+    cli: &Arc<Mutex<AsyncClient>>,
+    conn_opts: Option<&ConnectOptions>,
+    policy: &ReconnectPolicy,
+    monitor: &ConnectionMonitor,
+) -> paho_mqtt::Result<ServerResponse> {
+    loop {
+        let token = {
+            let cli_lock = cli.lock().await;
+            match conn_opts {
+                Some(opts) => cli_lock.connect(opts.clone()),
+                None => cli_lock.reconnect(),
+            }
+        };
+
+        match token.await {
+            Ok(rsp) => {
+                monitor.transition(ConnectionState::Connected);
+                return Ok(rsp);
+            }
+            Err(e) => {
+                error!(
+                    "Failed to connect to the MQTT server with this error: {}",
+                    e
+                );
+                monitor.attempt_failed(e.to_string());
+
+                let attempt = monitor.status().attempt;
+                if !policy.should_retry(attempt) {
+                    error!("Giving up connecting after {} attempts", attempt);
+                    monitor.transition(ConnectionState::Disconnected);
+                    return Err(e);
+                }
+
+                let delay = policy.delay(attempt);
+                debug!("Retrying in {:?}, after attempt: {}", delay, attempt);
+                sleep(delay).await;
+            }
+        }
+    }
+}
+
+/// Whether the server resumed the session of the listener, along with its subscriptions and the
+/// messages it kept for it.
+fn session_present(rsp: &ServerResponse) -> bool {
+    rsp.connect_response()
+        .is_some_and(|conn| conn.session_present)
+}
+
+/// Number of received messages queued for the ingestor before the client stops reading more
+pub const MESSAGE_QUEUE_SIZE: usize = 25;
+
+/// Hand the received messages to a channel, journaling them in the inbox first. This runs on the
+/// client's callback thread, so a message is on disk before the client moves on to the next one.
+/// While the channel is full the callback blocks, so the client stops reading from the server
+/// until the ingestor catches up. A `None` is sent when the connection is lost.
+fn start_stream(cli: &AsyncClient, inbox: Arc<Inbox>) -> Receiver<Option<InboxEntry>> {
+    let (tx, rx) = mpsc::channel(MESSAGE_QUEUE_SIZE);
+
+    // Installing the connection lost callbacks makes the client pass a `None` to the message
+    // callback when the connection drops
+    cli.set_connection_lost_callback(|_| {});
+    cli.set_disconnected_callback(|_, _, _| {});
+
+    cli.set_message_callback(move |_, msg_opt| {
+        let entry = msg_opt.map(|msg| inbox.entry(msg));
+
+        // The receiver only goes away when the MQTT routine stopped, the message is in the inbox
+        // by then and replayed on the next start
+        let _ = tx.blocking_send(entry);
+    });
+
+    rx
 }
 
-pub async fn mqtt_proc(cli: Arc<Mutex<AsyncClient>>, repo: Arc<Mutex<RemRepo>>) -> Result<()> {
-    let mut cli_lock = cli.lock().await;
+/// MQTT process
+///
+/// Connects to the MQTT server, subscribes to the topics of the registered handlers and passes
+/// every message to the ingestor. A lost connection is reestablished according to the reconnect
+/// policy, this returns an error if the policy gives up on connecting. The `replay` entries left
+/// in the inbox by a previous run are processed before connecting, and the entries that couldn't
+/// be stored yet are retried periodically.
+///
+/// When `shutdown` is cancelled the messages that were already received are handled, then the
+/// offline status is published and the client disconnects.
+pub async fn mqtt_proc(
+    cli: Arc<Mutex<AsyncClient>>,
+    conn_opts: ConnectOptions,
+    ingestor: Arc<Ingestor>,
+    replay: Vec<InboxEntry>,
+    monitor: Arc<ConnectionMonitor>,
+    config: Arc<Settings>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let policy = ReconnectPolicy::from_settings(&config);
+    let registry = ingestor.registry();
+    let status_topic = listener_status_topic(&config, &cli.lock().await.client_id());
+
+    if !replay.is_empty() {
+        info!("Replaying {} messages from the inbox", replay.len());
+        for entry in replay {
+            ingestor.process(entry, &shutdown).await;
+        }
+    }
+
+    // Get message stream before connecting, so no messages of the persistent session are missed.
+    let mut strm = start_stream(&*cli.lock().await, ingestor.inbox().clone());
 
-    // Get message stream before connecting.
-    let strm = &mut cli_lock.get_stream(25);
+    info!("Connecting to the MQTT server ...");
+    monitor.transition(ConnectionState::Connecting);
+    let rsp = select! {
+        res = connect_with_retry(&cli, Some(&conn_opts), &policy, &monitor) => res?,
+        _ = shutdown.cancelled() => {
+            monitor.transition(ConnectionState::Disconnected);
+            return Ok(());
+        }
+    };
+    subscribe(&cli, registry, &config.mqtt_shared_group).await?;
 
-    info!("Subscribing to topics: {:?}", SUBSCRIBED_TOPICS);
-    let sub_opts = vec![SubscribeOptions::with_retain_as_published(); SUBSCRIBED_TOPICS.len()];
-    cli_lock
-        .subscribe_many_with_options(SUBSCRIBED_TOPICS, QOS, &sub_opts, None)
-        .await?;
+    // A resumed session starts with the messages the server kept while the listener was away.
+    // Those are handled as backlog until the queue runs empty, since the devices didn't send them
+    // at once.
+    let mut backlog = session_present(&rsp);
+    publish_status(&cli, &status_topic, ListenerState::Online).await;
 
-    drop(cli_lock);
+    let mut retry = interval(STALLED_RETRY_INTERVAL);
+    retry.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
-    // Note that we're not providing a way to cleanly shut down and
-    // disconnect. Therefore, when you kill this app (with a ^C or
-    // whatever) the broker will get an unexpected drop and then
-    // should emit the LWT message.
     info!("Waiting for messages...");
-    while let Some(msg_opt) = strm.next().await {
-        if let Some(msg) = msg_opt {
-            info!("Received message: {:?}", msg.clone());
-
-            // Just log an errors if we can't handle the message, the only real error error we care about is
-            // a database error, not from a foreign key violation.
-            if let Err(err) = handle_message(&repo, msg).await {
-                warn!("Warning handling message: {:?}", err);
+    loop {
+        let msg_opt = select! {
+            msg_opt = strm.recv() => msg_opt,
+            _ = retry.tick() => {
+                ingestor.retry_stalled(&shutdown).await;
+                continue;
+            }
+            _ = shutdown.cancelled() => break,
+        };
+
+        match msg_opt {
+            Some(Some(mut entry)) => {
+                entry.backlog |= backlog;
+                ingestor.process(entry, &shutdown).await;
+                if backlog && strm.is_empty() {
+                    info!("Caught up with the messages kept by the MQTT server");
+                    backlog = false;
+                }
             }
-        } else {
+
             // A "None" means we were disconnected. Try to reconnect...
-            warn!("Lost connection. Attempting reconnect.");
+            Some(None) => {
+                warn!("Lost connection. Attempting reconnect.");
+                monitor.transition(ConnectionState::Reconnecting);
 
-            let cli_lock = cli.lock().await;
-            while let Err(err) = &cli_lock.reconnect().await {
-                error!("Error reconnecting: {}", err);
-                sleep(Duration::from_millis(1000)).await;
+                let rsp = select! {
+                    res = connect_with_retry(&cli, None, &policy, &monitor) => res?,
+                    _ = shutdown.cancelled() => break,
+                };
+
+                // The server dropped our session, so it no longer knows about our subscriptions
+                backlog = session_present(&rsp);
+                if !backlog {
+                    info!("Session was not resumed, subscribing again");
+                    subscribe(&cli, registry, &config.mqtt_shared_group).await?;
+                }
+
+                // The last will replaced our status while we were gone
+                publish_status(&cli, &status_topic, ListenerState::Online).await;
             }
+
+            // The stream only ends when the client callback is dropped
+            None => break,
+        }
+    }
+
+    // Handle whatever was already received before going offline. Every message is written to
+    // the database before the next one is handled, so there are no pending writes after this.
+    // Anything that doesn't make it, or arrives until we disconnect, stays in the inbox.
+    info!("Shutting down, handling the remaining received messages");
+    while let Ok(entry_opt) = strm.try_recv() {
+        if let Some(entry) = entry_opt {
+            ingestor.process(entry, &shutdown).await;
         }
     }
 
+    // Unblock the client callback if the queue filled up again, so it can't hold up disconnecting
+    drop(strm);
+
+    disconnect(&cli, &status_topic, &monitor).await;
+    info!("Disconnected from the MQTT server");
+
     Ok(())
 }
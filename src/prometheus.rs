@@ -0,0 +1,233 @@
+//! Prometheus metrics.
+//!
+//! The listener records its metrics with the `metrics` crate, and `/metrics` renders them in the
+//! Prometheus text format so Prometheus or Grafana can scrape the listener directly. Topics are
+//! labelled with the topic filter of their handler rather than the topic itself, so the number of
+//! series doesn't grow with the number of devices. Only the latest values are per device.
+use std::{
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use axum::{extract::Request, middleware::Next, response::Response};
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram};
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder, PrometheusHandle};
+
+use crate::{
+    connection::ConnectionState, model::RemData, mqtt::MQTTClientError, repo::RemRepoError,
+    telemetry::matched_route,
+};
+
+/// Interval the histograms are compacted at, whether or not they are scraped
+pub const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Buckets of the latency histograms, in seconds
+const DURATION_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Install the recorder the metrics of the whole process are recorded with.
+pub fn install() -> Result<(), BuildError> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Suffix("_duration_seconds".to_string()),
+            DURATION_BUCKETS,
+        )?
+        .install_recorder()?;
+    let _ = HANDLE.set(handle);
+
+    describe_counter!(
+        "rem_ingest_messages_total",
+        "Messages handled, by the topic filter of their handler and what happened to them"
+    );
+    describe_histogram!(
+        "rem_db_query_duration_seconds",
+        "Time taken by the database to store or look up a message, by operation"
+    );
+    describe_gauge!(
+        "rem_mqtt_connected",
+        "Whether the listener is connected to the MQTT server"
+    );
+    describe_counter!(
+        "rem_mqtt_reconnects_total",
+        "Times the connection to the MQTT server was lost and reestablished"
+    );
+    describe_counter!(
+        "rem_mqtt_connect_failures_total",
+        "Failed attempts to connect to the MQTT server"
+    );
+    describe_counter!(
+        "rem_http_requests_total",
+        "API requests, by method, route and status code"
+    );
+    describe_histogram!(
+        "rem_http_request_duration_seconds",
+        "Time taken to handle API requests, by method and route"
+    );
+    describe_gauge!(
+        "rem_sensor_value",
+        "Latest calibrated value of every sensor channel of a device"
+    );
+    describe_gauge!(
+        "rem_device_last_seen_timestamp_seconds",
+        "Unix time of the latest data or status stored for a device"
+    );
+    describe_counter!(
+        "rem_ingest_rate_limited_total",
+        "Messages over the rate limit of their device, by whether they were dropped or sampled"
+    );
+    describe_counter!(
+        "rem_inbox_write_errors_total",
+        "Received messages that couldn't be written to the inbox and won't be replayed"
+    );
+    describe_counter!(
+        "rem_http_rate_limited_total",
+        "API requests rejected over the rate limit of their API key or client IP"
+    );
+
+    Ok(())
+}
+
+/// The metrics in the Prometheus text format, empty if the recorder isn't installed.
+pub fn render() -> String {
+    HANDLE
+        .get()
+        .map(|handle| handle.render())
+        .unwrap_or_default()
+}
+
+/// Compact the histograms, this has to run periodically.
+pub fn run_upkeep() {
+    if let Some(handle) = HANDLE.get() {
+        handle.run_upkeep();
+    }
+}
+
+/// Label of what happened to a handled message
+fn ingest_outcome(res: &Result<(), MQTTClientError>) -> &'static str {
+    match res {
+        Ok(()) => "stored",
+        Err(MQTTClientError::DataEntryExists(_))
+        | Err(MQTTClientError::Repo(RemRepoError::DataEntryExists(_))) => "duplicate",
+        Err(MQTTClientError::InvalidMessage)
+        | Err(MQTTClientError::Repo(RemRepoError::UnknownDevice(_))) => "invalid",
+        Err(MQTTClientError::UnsupportedMessage(_)) => "unsupported",
+        Err(MQTTClientError::Signature(_)) => "rejected",
+        Err(MQTTClientError::RateLimited(_)) => "dropped",
+        Err(MQTTClientError::Repo(_)) => "error",
+    }
+}
+
+/// Count a message handled by the handler of `topic_filter`. Messages that are retried after a
+/// database error are counted once per attempt.
+pub fn record_ingest(topic_filter: &str, res: &Result<(), MQTTClientError>) {
+    counter!(
+        "rem_ingest_messages_total",
+        "topic" => topic_filter.to_string(),
+        "outcome" => ingest_outcome(res),
+    )
+    .increment(1);
+}
+
+/// Count a received message that couldn't be written to the inbox.
+pub fn record_inbox_write_error() {
+    counter!("rem_inbox_write_errors_total").increment(1);
+}
+
+/// Set the latest values of a device from the data that was stored.
+pub fn record_latest_data(data: &RemData) {
+    for sensor in RemData::CHANNELS {
+        if let Some(value) = data.channel(sensor) {
+            gauge!(
+                "rem_sensor_value",
+                "device_id" => data.device_id.clone(),
+                "sensor" => *sensor,
+            )
+            .set(value);
+        }
+    }
+    record_seen(&data.device_id);
+}
+
+/// Record that a device was heard from.
+pub fn record_seen(device_id: &str) {
+    gauge!(
+        "rem_device_last_seen_timestamp_seconds",
+        "device_id" => device_id.to_string(),
+    )
+    .set(chrono::Utc::now().timestamp() as f64);
+}
+
+/// Record the state of the MQTT connection.
+pub fn record_connection(state: ConnectionState, reconnected: bool) {
+    let connected = state == ConnectionState::Connected;
+    gauge!("rem_mqtt_connected").set(if connected { 1.0 } else { 0.0 });
+    if reconnected {
+        counter!("rem_mqtt_reconnects_total").increment(1);
+    }
+}
+
+/// Count a failed attempt to connect to the MQTT server.
+pub fn record_connect_failure() {
+    counter!("rem_mqtt_connect_failures_total").increment(1);
+}
+
+/// Count a message over the rate limit of its device. The device isn't a label, the devices over
+/// their limit are listed by the rate limits API instead.
+pub fn record_device_rate_limited(action: &'static str) {
+    counter!("rem_ingest_rate_limited_total", "action" => action).increment(1);
+}
+
+/// Count an API request over a rate limit, `limit` is `key` or `ip`.
+pub fn record_http_rate_limited(limit: &'static str) {
+    counter!("rem_http_rate_limited_total", "limit" => limit).increment(1);
+}
+
+/// Records the duration of a database operation when it is dropped.
+pub struct DbTimer {
+    operation: &'static str,
+    start: Instant,
+}
+
+impl DbTimer {
+    pub fn start(operation: &'static str) -> Self {
+        DbTimer {
+            operation,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for DbTimer {
+    fn drop(&mut self) {
+        histogram!("rem_db_query_duration_seconds", "operation" => self.operation)
+            .record(self.start.elapsed().as_secs_f64());
+    }
+}
+
+/// Middleware that counts the API requests and their duration per route.
+pub async fn track_http(req: Request, next: Next) -> Response {
+    let method = req.method().to_string();
+    let route = matched_route(&req);
+    let start = Instant::now();
+
+    let response = next.run(req).await;
+
+    histogram!(
+        "rem_http_request_duration_seconds",
+        "method" => method.clone(),
+        "route" => route.clone(),
+    )
+    .record(start.elapsed().as_secs_f64());
+    counter!(
+        "rem_http_requests_total",
+        "method" => method,
+        "route" => route,
+        "status" => response.status().as_u16().to_string(),
+    )
+    .increment(1);
+
+    response
+}
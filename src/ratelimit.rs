@@ -0,0 +1,246 @@
+//! Rate limits for ingestion and the API.
+//!
+//! Every device gets a bucket of messages, as many as its burst, refilled at its rate per minute.
+//! Messages of a device with an empty bucket are dropped after their signature is verified, so a
+//! device can't use up the budget of a device with a key by sending in its name, and before they
+//! are stored. With `INGEST_SAMPLE_EVERY` one in every N of them is still stored, so a flooding
+//! device stays visible without filling the database. Messages the listener handles again, like the
+//! ones replayed from the inbox, aren't counted twice.
+//!
+//! The API is limited per API key on the authenticated routes and per client IP on all routes, so
+//! clients without a valid key can't hammer the key lookups either. Requests over the limit get a
+//! 429 with a `Retry-After` header.
+//!
+//! Every limit is disabled until its rate is set.
+//!
+//! The number of dropped, sampled and rejected messages and requests are counted and returned by
+//! the rate limits API, and exported as metrics.
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    net::SocketAddr,
+    num::NonZeroU32,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use governor::{
+    clock::{Clock, DefaultClock},
+    DefaultKeyedRateLimiter, Quota, RateLimiter,
+};
+use serde::Serialize;
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::{
+    error::{ApiError, ErrorCode},
+    model::ApiKey,
+    prometheus,
+    settings::Settings,
+};
+
+/// Interval the state of idle keys is cleaned up at
+pub const RETAIN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Number of messages of a device dropped in a row between warnings
+const DROP_WARNING_INTERVAL: u64 = 1000;
+
+type Limiter = DefaultKeyedRateLimiter<String>;
+
+/// A limiter allowing `burst` events per key at once, refilled at `per_minute`, `None` if the rate
+/// is zero.
+fn limiter(per_minute: u32, burst: u32) -> Option<Limiter> {
+    let rate = NonZeroU32::new(per_minute)?;
+    let burst = NonZeroU32::new(burst).unwrap_or(rate);
+    Some(RateLimiter::keyed(
+        Quota::per_minute(rate).allow_burst(burst),
+    ))
+}
+
+/// Whether a message of a device is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    /// The device is within its limit
+    Allowed,
+    /// The device is over its limit, but the message is kept as a sample
+    Sampled,
+    /// The device is over its limit and the message is dropped
+    Dropped,
+}
+
+/// Messages of a device over its limit
+#[derive(Serialize, ToSchema, Debug, Clone, Default)]
+pub struct DeviceLimitStats {
+    #[serde(rename = "deviceId")]
+    pub device_id: String,
+    pub dropped: u64,
+    pub sampled: u64,
+}
+
+/// Counters of the rate limits, see [`RateLimits::stats`]
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct RateLimitStats {
+    /// Messages dropped over the device limits
+    #[serde(rename = "ingestDropped")]
+    pub ingest_dropped: u64,
+    /// Messages over the device limits that were stored as a sample
+    #[serde(rename = "ingestSampled")]
+    pub ingest_sampled: u64,
+    /// Requests rejected over the limit of their API key
+    #[serde(rename = "httpRejectedByKey")]
+    pub http_rejected_by_key: u64,
+    /// Requests rejected over the limit of their client IP
+    #[serde(rename = "httpRejectedByIp")]
+    pub http_rejected_by_ip: u64,
+    /// The devices that went over their limit, most dropped messages first
+    pub devices: Vec<DeviceLimitStats>,
+}
+
+pub struct RateLimits {
+    devices: Option<Limiter>,
+    sample_every: u64,
+    device_stats: Mutex<HashMap<String, DeviceLimitStats>>,
+    keys: Option<Limiter>,
+    ips: Option<Limiter>,
+    rejected_by_key: AtomicU64,
+    rejected_by_ip: AtomicU64,
+}
+
+impl RateLimits {
+    pub fn new(config: &Settings) -> Self {
+        RateLimits {
+            devices: limiter(config.ingest_device_rate, config.ingest_device_burst),
+            sample_every: config.ingest_sample_every,
+            device_stats: Mutex::new(HashMap::new()),
+            keys: limiter(config.http_key_rate, config.http_key_burst),
+            ips: limiter(config.http_ip_rate, config.http_ip_burst),
+            rejected_by_key: AtomicU64::new(0),
+            rejected_by_ip: AtomicU64::new(0),
+        }
+    }
+
+    /// Decide whether a message of a device is stored, counting the ones over the limit.
+    pub fn admit_device(&self, device_id: &str) -> Admission {
+        let Some(limiter) = &self.devices else {
+            return Admission::Allowed;
+        };
+        if limiter.check_key(&device_id.to_string()).is_ok() {
+            return Admission::Allowed;
+        }
+
+        let mut device_stats = self
+            .device_stats
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let stats = device_stats
+            .entry(device_id.to_string())
+            .or_insert_with(|| DeviceLimitStats {
+                device_id: device_id.to_string(),
+                ..Default::default()
+            });
+
+        let over = stats.dropped + stats.sampled + 1;
+        if self.sample_every > 0 && over.is_multiple_of(self.sample_every) {
+            stats.sampled += 1;
+            prometheus::record_device_rate_limited("sampled");
+            return Admission::Sampled;
+        }
+
+        stats.dropped += 1;
+        prometheus::record_device_rate_limited("dropped");
+        if stats.dropped % DROP_WARNING_INTERVAL == 1 {
+            warn!(
+                "Device {} is over its rate limit, {} messages dropped so far",
+                device_id, stats.dropped
+            );
+        }
+        Admission::Dropped
+    }
+
+    /// The counters of all limits
+    pub fn stats(&self) -> RateLimitStats {
+        let mut devices: Vec<DeviceLimitStats> = self
+            .device_stats
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .values()
+            .cloned()
+            .collect();
+        devices.sort_by_key(|d| Reverse(d.dropped));
+
+        RateLimitStats {
+            ingest_dropped: devices.iter().map(|d| d.dropped).sum(),
+            ingest_sampled: devices.iter().map(|d| d.sampled).sum(),
+            http_rejected_by_key: self.rejected_by_key.load(Ordering::Relaxed),
+            http_rejected_by_ip: self.rejected_by_ip.load(Ordering::Relaxed),
+            devices,
+        }
+    }
+
+    /// Forget the keys that are back within their limit, so the limiters don't grow forever.
+    pub fn retain_recent(&self) {
+        for limiter in [&self.devices, &self.keys, &self.ips].into_iter().flatten() {
+            limiter.retain_recent();
+            limiter.shrink_to_fit();
+        }
+    }
+}
+
+/// Check `key` against a limiter, returns the response to reject the request with if it is over.
+fn check(
+    limiter: &Option<Limiter>,
+    key: String,
+    rejected: &AtomicU64,
+    label: &'static str,
+) -> Option<Response> {
+    let not_until = limiter.as_ref()?.check_key(&key).err()?;
+    rejected.fetch_add(1, Ordering::Relaxed);
+    prometheus::record_http_rate_limited(label);
+    let wait = not_until.wait_time_from(DefaultClock::default().now());
+    let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+
+    let message = format!("Too many requests, retry in {} seconds", retry_after);
+    let response = (
+        [(header::RETRY_AFTER, retry_after.to_string())],
+        ApiError::new(ErrorCode::RateLimited, message),
+    );
+    Some(response.into_response())
+}
+
+/// Middleware that limits the requests per client IP.
+pub async fn limit_by_ip(
+    State(limits): State<Arc<RateLimits>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let ip = addr.ip().to_string();
+    if let Some(response) = check(&limits.ips, ip, &limits.rejected_by_ip, "ip") {
+        return response;
+    }
+    next.run(req).await
+}
+
+/// Middleware that limits the requests per API key, this has to run after the key is checked.
+pub async fn limit_by_key(
+    State(limits): State<Arc<RateLimits>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if let Some(api_key) = req.extensions().get::<ApiKey>() {
+        let key = api_key.id.clone();
+        if let Some(response) = check(&limits.keys, key, &limits.rejected_by_key, "key") {
+            return response;
+        }
+    }
+    next.run(req).await
+}
@@ -1,22 +1,111 @@
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 
 use chrono::NaiveDateTime;
-use diesel::{insert_into, pg::PgConnection, prelude::*};
-use tokio::sync::Mutex;
+use diesel::{
+    connection::InstrumentationEvent,
+    delete,
+    dsl::exists,
+    insert_into,
+    pg::PgConnection,
+    prelude::*,
+    select, sql_query,
+    sql_types::{BigInt, Float4, Nullable, Timestamp, Varchar},
+    update,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::{Mutex, MutexGuard};
+use tracing::{info, instrument, warn};
+use utoipa::ToSchema;
 
 use crate::{
-    model::{RemData, RemStatus},
+    derived::DerivedMetrics,
+    model::{
+        ApiKey, ApiScope, Calibration, Command, CommandStatus, Device, DeviceConfig,
+        DeviceConfigState, DeviceKey, FirmwareImage, FirmwareProgress, FirmwareUpdate,
+        FirmwareUpdateStatus, MetricValue, RawChannels, Reading, RejectedMessage, RemData,
+        RemDataAggregate, RemStatus, SignatureAlgorithm, Tenant, DEFAULT_TENANT,
+    },
+    prometheus::DbTimer,
     schema::{
+        api_keys::dsl::{
+            api_keys, id as api_keys_id, key_hash as api_keys_key_hash, name as api_keys_name,
+            prefix as api_keys_prefix, revoked_at as api_keys_revoked_at,
+            scopes as api_keys_scopes, tenant_id as api_keys_tenant_id,
+        },
+        calibrations::dsl::{
+            calibrations, device_id as calibrations_device_id, field as calibrations_field,
+            offset as calibrations_offset, scale as calibrations_scale,
+            tenant_id as calibrations_tenant_id,
+        },
+        commands::dsl::{
+            command as commands_command, commands, created_at as commands_created_at,
+            device_id as commands_device_id, id as commands_id, params as commands_params,
+            response as commands_response, status as commands_status,
+            tenant_id as commands_tenant_id,
+        },
+        dead_letters::dsl::{
+            dead_letters, error as dead_letters_error, payload as dead_letters_payload,
+            topic as dead_letters_topic,
+        },
+        device_configs::dsl::{
+            desired as device_configs_desired, desired_at as device_configs_desired_at,
+            device_configs, device_id as device_configs_device_id,
+            reported as device_configs_reported, reported_at as device_configs_reported_at,
+            tenant_id as device_configs_tenant_id,
+        },
+        device_keys::dsl::{
+            algorithm as device_keys_algorithm, created_at as device_keys_created_at,
+            device_id as device_keys_device_id, device_keys, key as device_keys_key,
+            last_sent_at as device_keys_last_sent_at,
+        },
+        devices::dsl::{
+            device_id as devices_device_id, devices, firmware_version as devices_firmware_version,
+            group_name as devices_group_name, tenant_id as devices_tenant_id,
+        },
+        firmware_images::dsl::{
+            created_at as firmware_images_created_at, firmware_images,
+            sha256 as firmware_images_sha256, size as firmware_images_size,
+            version as firmware_images_version,
+        },
+        firmware_updates::dsl::{
+            created_at as firmware_updates_created_at, device_id as firmware_updates_device_id,
+            firmware_updates, message as firmware_updates_message,
+            progress as firmware_updates_progress, status as firmware_updates_status,
+            target_version as firmware_updates_target_version,
+            tenant_id as firmware_updates_tenant_id,
+        },
+        readings::dsl::{
+            created_at as readings_created_at, data_id as readings_data_id,
+            device_id as readings_device_id, metric as readings_metric, readings,
+            tenant_id as readings_tenant_id, unit as readings_unit, value as readings_value,
+        },
+        rejected_messages::dsl::{
+            created_at as rejected_messages_created_at, device_id as rejected_messages_device_id,
+            error as rejected_messages_error, id as rejected_messages_id,
+            payload as rejected_messages_payload, reason as rejected_messages_reason,
+            rejected_messages, tenant_id as rejected_messages_tenant_id,
+            topic as rejected_messages_topic,
+        },
         rem_data::dsl::{
+            co2 as rem_data_co2, created_at as rem_data_created_at,
             device_id as rem_data_device_id, humidity as rem_data_humidity, id as rem_data_id,
+            illuminance as rem_data_illuminance, nox_index as rem_data_nox_index,
             pm10 as rem_data_pm10, pm1_0 as rem_data_pm1_0, pm2_5 as rem_data_pm2_5,
-            pressure as rem_data_pressure, rem_data, temperature as rem_data_temperature,
-            voc_index as rem_data_voc_index,
+            pressure as rem_data_pressure, raw as rem_data_raw, rem_data,
+            sound_level as rem_data_sound_level, temperature as rem_data_temperature,
+            tenant_id as rem_data_tenant_id, voc_index as rem_data_voc_index,
         },
         rem_status::dsl::{
-            device_id as rem_status_device_id, id as rem_status_id, rem_status,
-            rssi as rem_status_rssi, up_time as rem_status_up_time,
+            created_at as rem_status_created_at, device_id as rem_status_device_id,
+            firmware_version as rem_status_firmware_version, id as rem_status_id, rem_status,
+            rssi as rem_status_rssi, tenant_id as rem_status_tenant_id,
+            up_time as rem_status_up_time,
         },
+        tenants::dsl::{id as tenants_id, name as tenants_name, tenants},
     },
 };
 
@@ -30,8 +119,18 @@ pub enum RemRepoError {
     DataEntryExists(String),
     #[error("Database error: {}", .0)]
     DatabaseError(#[from] diesel::result::Error),
+    #[error("Database connection error: {}", .0)]
+    ConnectionError(#[from] diesel::ConnectionError),
     #[error("Invalid message")]
     InvalidMessage,
+    #[error("Invalid raw channel values for key: {}", .0)]
+    InvalidRawChannels(String),
+    #[error("Unknown command: {}", .0)]
+    UnknownCommand(String),
+    #[error("Unknown device: {}", .0)]
+    UnknownDevice(String),
+    #[error("Unknown signature algorithm: {}", .0)]
+    UnknownSignatureAlgorithm(String),
 }
 
 /// REMStatus is the structure of the status that we receive from the REM device.
@@ -44,6 +143,7 @@ pub struct RemStatusDB {
     pub up_time: i32,
     pub created_at: NaiveDateTime,
     pub rssi: Option<i32>,
+    pub firmware_version: Option<String>,
 }
 
 impl From<RemStatusDB> for RemStatus {
@@ -56,6 +156,8 @@ impl From<RemStatusDB> for RemStatus {
             device_id: val.device_id,
             up_time: val.up_time,
             rssi,
+            firmware_version: val.firmware_version,
+            config: None,
         }
     }
 }
@@ -77,6 +179,11 @@ pub struct RemDataDB {
     pub voc_index: f32,
 
     pub created_at: NaiveDateTime,
+
+    pub co2: Option<f32>,
+    pub nox_index: Option<f32>,
+    pub illuminance: Option<f32>,
+    pub sound_level: Option<f32>,
 }
 
 impl From<RemDataDB> for RemData {
@@ -91,10 +198,473 @@ impl From<RemDataDB> for RemData {
             humidity: val.humidity,
             pressure: val.pressure,
             voc_index: val.voc_index,
+            co2: val.co2,
+            nox_index: val.nox_index,
+            illuminance: val.illuminance,
+            sound_level: val.sound_level,
+            extra: Default::default(),
+            derived: Some(DerivedMetrics::compute(
+                val.temperature,
+                val.humidity,
+                val.pm2_5,
+                val.pm10,
+            )),
+        }
+    }
+}
+
+/// CalibrationDB is the calibration of a single channel of a device.
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = crate::schema::calibrations)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct CalibrationDB {
+    pub device_id: String,
+    pub field: String,
+    pub offset: f32,
+    pub scale: f32,
+    pub updated_at: NaiveDateTime,
+}
+
+impl From<CalibrationDB> for Calibration {
+    fn from(val: CalibrationDB) -> Self {
+        Calibration {
+            device_id: val.device_id,
+            field: val.field,
+            offset: val.offset,
+            scale: val.scale,
+        }
+    }
+}
+
+/// CommandDB is a row of the commands sent to the REM devices.
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = crate::schema::commands)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct CommandDB {
+    pub id: String,
+    pub device_id: String,
+    pub command: String,
+    pub params: Option<Value>,
+    pub status: String,
+    pub response: Option<Value>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl From<CommandDB> for Command {
+    fn from(val: CommandDB) -> Self {
+        // The column is constrained to the known statuses
+        let status = CommandStatus::parse(&val.status).unwrap_or_else(|| {
+            warn!("Unknown status '{}' of command {}", val.status, val.id);
+            CommandStatus::Failed
+        });
+
+        Command {
+            id: val.id,
+            device_id: val.device_id,
+            command: val.command,
+            params: val.params,
+            status,
+            response: val.response,
+            created_at: val.created_at,
+            updated_at: val.updated_at,
+        }
+    }
+}
+
+/// DeviceConfigDB is the desired and reported configuration of a device.
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = crate::schema::device_configs)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct DeviceConfigDB {
+    pub device_id: String,
+    pub desired: Option<Value>,
+    pub desired_at: Option<NaiveDateTime>,
+    pub reported: Option<Value>,
+    pub reported_at: Option<NaiveDateTime>,
+}
+
+impl From<DeviceConfigDB> for DeviceConfigState {
+    fn from(val: DeviceConfigDB) -> Self {
+        let desired: Option<DeviceConfig> =
+            val.desired.and_then(|v| serde_json::from_value(v).ok());
+        let reported: Option<DeviceConfig> =
+            val.reported.and_then(|v| serde_json::from_value(v).ok());
+        let drift = desired
+            .as_ref()
+            .map(|d| d.drift(reported.as_ref()))
+            .unwrap_or_default();
+
+        DeviceConfigState {
+            device_id: val.device_id,
+            in_sync: drift.is_empty(),
+            desired,
+            desired_at: val.desired_at,
+            reported,
+            reported_at: val.reported_at,
+            drift,
+        }
+    }
+}
+
+/// DeviceDB is a REM device known to the listener.
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = crate::schema::devices)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct DeviceDB {
+    pub device_id: String,
+    pub group_name: Option<String>,
+    pub firmware_version: Option<String>,
+    pub updated_at: NaiveDateTime,
+}
+
+impl From<DeviceDB> for Device {
+    fn from(val: DeviceDB) -> Self {
+        Device {
+            device_id: val.device_id,
+            group: val.group_name,
+            firmware_version: val.firmware_version,
+            updated_at: val.updated_at,
+        }
+    }
+}
+
+/// ApiKeyDB is an API key, without the key itself.
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = crate::schema::api_keys)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ApiKeyDB {
+    pub id: String,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub created_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub tenant_id: String,
+}
+
+impl From<ApiKeyDB> for ApiKey {
+    fn from(val: ApiKeyDB) -> Self {
+        ApiKey {
+            id: val.id,
+            name: val.name,
+            tenant_id: val.tenant_id,
+            prefix: val.prefix,
+            // Scopes are validated when the key is created
+            scopes: val
+                .scopes
+                .iter()
+                .filter_map(|s| ApiScope::parse(s))
+                .collect(),
+            created_at: val.created_at,
+            revoked_at: val.revoked_at,
+        }
+    }
+}
+
+/// DeviceKeyDB is the key the messages of a device are verified with.
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = crate::schema::device_keys)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct DeviceKeyDB {
+    pub device_id: String,
+    pub algorithm: String,
+    pub key: Vec<u8>,
+    pub created_at: NaiveDateTime,
+}
+
+impl TryFrom<DeviceKeyDB> for DeviceKey {
+    type Error = RemRepoError;
+
+    fn try_from(val: DeviceKeyDB) -> Result<Self, Self::Error> {
+        // Algorithms are validated when the key is set
+        let algorithm = SignatureAlgorithm::parse(&val.algorithm)
+            .ok_or_else(|| RemRepoError::UnknownSignatureAlgorithm(val.algorithm.clone()))?;
+
+        Ok(DeviceKey {
+            device_id: val.device_id,
+            algorithm,
+            key: val.key,
+            created_at: val.created_at,
+        })
+    }
+}
+
+/// RejectedMessageDB is a message that was rejected for its signature.
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = crate::schema::rejected_messages)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct RejectedMessageDB {
+    pub id: i64,
+    pub device_id: String,
+    pub topic: String,
+    pub reason: String,
+    pub error: String,
+    pub payload: Vec<u8>,
+    pub created_at: NaiveDateTime,
+}
+
+impl From<RejectedMessageDB> for RejectedMessage {
+    fn from(val: RejectedMessageDB) -> Self {
+        RejectedMessage {
+            id: val.id,
+            device_id: val.device_id,
+            topic: val.topic,
+            reason: val.reason,
+            error: val.error,
+            payload: String::from_utf8_lossy(&val.payload).into_owned(),
+            created_at: val.created_at,
+        }
+    }
+}
+
+/// TenantDB is a customer sharing the listener.
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = crate::schema::tenants)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct TenantDB {
+    pub id: String,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl From<TenantDB> for Tenant {
+    fn from(val: TenantDB) -> Self {
+        Tenant {
+            id: val.id,
+            name: val.name,
+            created_at: val.created_at,
+        }
+    }
+}
+
+/// FirmwareImageDB is an uploaded firmware image.
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = crate::schema::firmware_images)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct FirmwareImageDB {
+    pub version: String,
+    pub size: i64,
+    pub sha256: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl From<FirmwareImageDB> for FirmwareImage {
+    fn from(val: FirmwareImageDB) -> Self {
+        FirmwareImage {
+            version: val.version,
+            size: val.size,
+            sha256: val.sha256,
+            created_at: val.created_at,
+        }
+    }
+}
+
+/// FirmwareUpdateDB is the firmware update assigned to a device.
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = crate::schema::firmware_updates)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct FirmwareUpdateDB {
+    pub device_id: String,
+    pub target_version: String,
+    pub status: String,
+    pub progress: Option<i16>,
+    pub message: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl From<FirmwareUpdateDB> for FirmwareUpdate {
+    fn from(val: FirmwareUpdateDB) -> Self {
+        // Only the listener writes the status, so it is always known
+        let status = FirmwareUpdateStatus::parse(&val.status).unwrap_or_else(|| {
+            warn!(
+                "Unknown status '{}' of the firmware update of {}",
+                val.status, val.device_id
+            );
+            FirmwareUpdateStatus::Failed
+        });
+
+        FirmwareUpdate {
+            device_id: val.device_id,
+            target_version: val.target_version,
+            status,
+            progress: val.progress,
+            message: val.message,
+            created_at: val.created_at,
+            updated_at: val.updated_at,
+        }
+    }
+}
+
+/// RemDataAggregateDB is a row of the bucketed averages of the `rem_data` table.
+#[derive(QueryableByName, Debug)]
+pub struct RemDataAggregateDB {
+    #[diesel(sql_type = Varchar)]
+    pub device_id: String,
+    #[diesel(sql_type = Timestamp)]
+    pub bucket: NaiveDateTime,
+    #[diesel(sql_type = BigInt)]
+    pub count: i64,
+
+    #[diesel(sql_type = Float4)]
+    pub pm2_5: f32,
+    #[diesel(sql_type = Float4)]
+    pub pm1_0: f32,
+    #[diesel(sql_type = Float4)]
+    pub pm10: f32,
+    #[diesel(sql_type = Float4)]
+    pub temperature: f32,
+    #[diesel(sql_type = Float4)]
+    pub humidity: f32,
+    #[diesel(sql_type = Float4)]
+    pub pressure: f32,
+    #[diesel(sql_type = Float4)]
+    pub voc_index: f32,
+
+    #[diesel(sql_type = Nullable<Float4>)]
+    pub co2: Option<f32>,
+    #[diesel(sql_type = Nullable<Float4>)]
+    pub nox_index: Option<f32>,
+    #[diesel(sql_type = Nullable<Float4>)]
+    pub illuminance: Option<f32>,
+    #[diesel(sql_type = Nullable<Float4>)]
+    pub sound_level: Option<f32>,
+}
+
+impl From<RemDataAggregateDB> for RemDataAggregate {
+    fn from(val: RemDataAggregateDB) -> Self {
+        RemDataAggregate {
+            derived: DerivedMetrics::compute(val.temperature, val.humidity, val.pm2_5, val.pm10),
+            device_id: val.device_id,
+            bucket: val.bucket,
+            count: val.count,
+            pm2_5: val.pm2_5,
+            pm1_0: val.pm1_0,
+            pm10: val.pm10,
+            temperature: val.temperature,
+            humidity: val.humidity,
+            pressure: val.pressure,
+            voc_index: val.voc_index,
+            co2: val.co2,
+            nox_index: val.nox_index,
+            illuminance: val.illuminance,
+            sound_level: val.sound_level,
+        }
+    }
+}
+
+/// Size of the time buckets used to aggregate REM data.
+#[derive(Deserialize, ToSchema, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AggregateBucket {
+    Minute,
+    #[default]
+    Hour,
+    Day,
+    Week,
+    Month,
+}
+
+impl AggregateBucket {
+    /// The field name understood by postgres' `date_trunc`
+    fn as_str(&self) -> &'static str {
+        match self {
+            AggregateBucket::Minute => "minute",
+            AggregateBucket::Hour => "hour",
+            AggregateBucket::Day => "day",
+            AggregateBucket::Week => "week",
+            AggregateBucket::Month => "month",
+        }
+    }
+}
+
+/// Filter used to aggregate the REM data.
+#[derive(Debug)]
+pub struct AggregateFilter {
+    pub bucket: AggregateBucket,
+    pub device_id: Option<String>,
+    pub since: Option<NaiveDateTime>,
+    pub limit: i64,
+}
+
+/// ReadingDB is a row of the generic readings store.
+#[derive(Queryable, QueryableByName, Selectable, Debug)]
+#[diesel(table_name = crate::schema::readings)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ReadingDB {
+    pub device_id: String,
+    pub metric: String,
+    pub value: f64,
+    pub unit: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+impl From<ReadingDB> for Reading {
+    fn from(val: ReadingDB) -> Self {
+        Reading {
+            device_id: val.device_id,
+            metric: val.metric,
+            value: val.value,
+            unit: val.unit,
+            created_at: val.created_at,
+        }
+    }
+}
+
+/// Metrics that have a dedicated column in the `rem_data` table, keyed by the name used in the
+/// device payload, along with the column name and unit.
+const REM_DATA_METRICS: &[(&str, &str, Option<&str>)] = &[
+    ("pm2_5", "pm2_5", Some("µg/m³")),
+    ("pm1_0", "pm1_0", Some("µg/m³")),
+    ("pm10", "pm10", Some("µg/m³")),
+    ("temperature", "temperature", Some("°C")),
+    ("humidity", "humidity", Some("%")),
+    ("pressure", "pressure", None),
+    ("vocIndex", "voc_index", None),
+    ("co2", "co2", Some("ppm")),
+    ("noxIndex", "nox_index", None),
+    ("illuminance", "illuminance", Some("lx")),
+    ("soundLevel", "sound_level", Some("dBA")),
+];
+
+/// Filter used to query the values of a single metric.
+#[derive(Debug)]
+pub struct ReadingsFilter {
+    pub metric: String,
+    pub device_id: Option<String>,
+    pub since: Option<NaiveDateTime>,
+    pub limit: i64,
+}
+
+impl RemRepoError {
+    /// Check if the error is likely to go away when the operation is retried, like a lost
+    /// connection. Other errors, like constraint violations, will fail the same way every time.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            RemRepoError::DatabaseError(diesel::result::Error::DatabaseError(kind, _)) => {
+                is_connection_error(kind)
+                    || matches!(
+                        kind,
+                        diesel::result::DatabaseErrorKind::SerializationFailure
+                    )
+            }
+            RemRepoError::DatabaseError(diesel::result::Error::BrokenTransactionManager) => true,
+            RemRepoError::ConnectionError(_) => true,
+            _ => false,
         }
     }
 }
 
+/// Whether the error means the connection to the database is lost and must be reestablished
+pub fn is_connection_error(kind: &diesel::result::DatabaseErrorKind) -> bool {
+    matches!(
+        kind,
+        diesel::result::DatabaseErrorKind::ClosedConnection
+            | diesel::result::DatabaseErrorKind::UnableToSendCommand
+    )
+}
+
 fn repo_error_from_database(e: diesel::result::Error, key: String) -> RemRepoError {
     // Only error type for a duplicate key violation is violation error
     if matches!(
@@ -107,20 +677,128 @@ fn repo_error_from_database(e: diesel::result::Error, key: String) -> RemRepoErr
     RemRepoError::DatabaseError(e)
 }
 
+/// Connect to the database, flagging `broken` as soon as a query fails on a lost connection
+fn establish(database_url: &str, broken: Arc<AtomicBool>) -> Result<PgConnection, RemRepoError> {
+    let mut conn = PgConnection::establish(database_url)?;
+    conn.set_instrumentation(move |event: InstrumentationEvent<'_>| {
+        if let InstrumentationEvent::FinishQuery {
+            error: Some(diesel::result::Error::DatabaseError(kind, _)),
+            ..
+        } = event
+        {
+            if is_connection_error(kind) {
+                broken.store(true, Ordering::Relaxed);
+            }
+        }
+    });
+    Ok(conn)
+}
+
+/// The tenant of a device, registering the device if it isn't known yet. A device belongs to the
+/// tenant it was first seen with, or the default tenant if none was claimed. Claiming a device of
+/// another tenant fails with [`RemRepoError::UnknownDevice`], so tenants can't write to or even
+/// detect each other's devices. Only the ingestion of device messages registers devices, the API
+/// checks the owner with [`owned_device`] instead.
+fn claim_device(
+    conn: &mut PgConnection,
+    tenant: Option<&str>,
+    device: &str,
+) -> Result<String, RemRepoError> {
+    insert_into(devices)
+        .values((
+            devices_device_id.eq(device),
+            devices_tenant_id.eq(tenant.unwrap_or(DEFAULT_TENANT)),
+        ))
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+
+    let owner = devices
+        .select(devices_tenant_id)
+        .filter(devices_device_id.eq(device))
+        .first::<String>(conn)?;
+
+    match tenant {
+        Some(tenant) if tenant != owner => Err(RemRepoError::UnknownDevice(device.to_string())),
+        _ => Ok(owner),
+    }
+}
+
+/// Check that a device is registered to the tenant. Devices of other tenants fail with
+/// [`RemRepoError::UnknownDevice`] like devices that don't exist, so tenants can't detect each
+/// other's devices.
+fn owned_device(conn: &mut PgConnection, tenant: &str, device: &str) -> Result<(), RemRepoError> {
+    let owned = select(exists(
+        devices
+            .filter(devices_device_id.eq(device))
+            .filter(devices_tenant_id.eq(tenant)),
+    ))
+    .get_result::<bool>(conn)?;
+
+    match owned {
+        true => Ok(()),
+        false => Err(RemRepoError::UnknownDevice(device.to_string())),
+    }
+}
+
 pub struct RemRepo {
     db: Arc<Mutex<PgConnection>>,
+    database_url: String,
+    /// Set when a query failed because the connection was lost, the next query reconnects
+    broken: Arc<AtomicBool>,
 }
 
 impl RemRepo {
-    /// RemRepo constructor, this creates a new instance of the
-    /// struct with the passed db connection instance
-    pub fn new(db: Arc<Mutex<PgConnection>>) -> Self {
-        RemRepo { db }
+    /// RemRepo constructor, this connects to the database at the passed url
+    pub fn connect(database_url: &str) -> Result<Self, RemRepoError> {
+        let broken = Arc::new(AtomicBool::new(false));
+        let conn = establish(database_url, broken.clone())?;
+        Ok(RemRepo {
+            db: Arc::new(Mutex::new(conn)),
+            database_url: database_url.to_string(),
+            broken,
+        })
+    }
+
+    /// The connection to the database, reestablished first if it was lost. Until the database can
+    /// be reached again this fails with a transient error.
+    async fn conn(&self) -> Result<MutexGuard<'_, PgConnection>, RemRepoError> {
+        let mut conn = self.db.lock().await;
+        if self.broken.load(Ordering::Relaxed) {
+            *conn = establish(&self.database_url, self.broken.clone())?;
+            self.broken.store(false, Ordering::Relaxed);
+            info!("Reconnected to the postgres instance");
+        }
+        Ok(conn)
+    }
+
+    /// Check that the database answers a trivial query
+    #[instrument(skip_all)]
+    pub async fn ping(&self) -> Result<(), RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        sql_query("SELECT 1").execute(&mut *mut_conn)?;
+        Ok(())
     }
 
-    pub async fn list_data(&self) -> Result<Vec<RemData>, RemRepoError> {
-        let mut mut_conn = self.db.lock().await;
-        let dbs = rem_data.load::<RemDataDB>(&mut *mut_conn)?;
+    #[instrument(skip_all)]
+    pub async fn list_data(
+        &self,
+        tenant: &str,
+        device: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<RemData>, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        let mut query = rem_data
+            .select(RemDataDB::as_select())
+            .filter(rem_data_tenant_id.eq(tenant))
+            .into_boxed();
+        if let Some(device) = device {
+            query = query.filter(rem_data_device_id.eq(device));
+        }
+
+        let dbs = query
+            .order((rem_data_created_at.desc(), rem_data_id.desc()))
+            .limit(limit)
+            .load::<RemDataDB>(&mut *mut_conn)?;
 
         // Map the diesel definition into the global message definition
         let data = dbs.into_iter().map(|d| d.into()).collect();
@@ -128,9 +806,26 @@ impl RemRepo {
         Ok(data)
     }
 
-    pub async fn list_status(&self) -> Result<Vec<RemStatus>, RemRepoError> {
-        let mut mut_conn = self.db.lock().await;
-        let dbs = rem_status.load::<RemStatusDB>(&mut *mut_conn)?;
+    #[instrument(skip_all)]
+    pub async fn list_status(
+        &self,
+        tenant: &str,
+        device: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<RemStatus>, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        let mut query = rem_status
+            .select(RemStatusDB::as_select())
+            .filter(rem_status_tenant_id.eq(tenant))
+            .into_boxed();
+        if let Some(device) = device {
+            query = query.filter(rem_status_device_id.eq(device));
+        }
+
+        let dbs = query
+            .order((rem_status_created_at.desc(), rem_status_id.desc()))
+            .limit(limit)
+            .load::<RemStatusDB>(&mut *mut_conn)?;
 
         // Map the diesel definition into the global message definition
         let data = dbs.into_iter().map(|d| d.into()).collect();
@@ -138,48 +833,1108 @@ impl RemRepo {
         Ok(data)
     }
 
+    /// Return the averages of the REM data channels per device and time bucket, newest bucket first.
+    #[instrument(skip_all)]
+    pub async fn aggregate_data(
+        &self,
+        tenant: &str,
+        filter: AggregateFilter,
+    ) -> Result<Vec<RemDataAggregate>, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+
+        let dbs = sql_query(
+            "SELECT device_id, date_trunc($1, created_at) AS bucket, COUNT(*) AS count, \
+                AVG(pm2_5)::float4 AS pm2_5, AVG(pm1_0)::float4 AS pm1_0, AVG(pm10)::float4 AS pm10, \
+                AVG(temperature)::float4 AS temperature, AVG(humidity)::float4 AS humidity, \
+                AVG(pressure)::float4 AS pressure, AVG(voc_index)::float4 AS voc_index, \
+                AVG(co2)::float4 AS co2, AVG(nox_index)::float4 AS nox_index, \
+                AVG(illuminance)::float4 AS illuminance, AVG(sound_level)::float4 AS sound_level \
+            FROM rem_data \
+            WHERE tenant_id = $5 \
+                AND ($2 IS NULL OR device_id = $2) \
+                AND ($3 IS NULL OR created_at >= $3) \
+            GROUP BY device_id, bucket \
+            ORDER BY bucket DESC, device_id \
+            LIMIT $4",
+        )
+        .bind::<Varchar, _>(filter.bucket.as_str())
+        .bind::<Nullable<Varchar>, _>(filter.device_id)
+        .bind::<Nullable<Timestamp>, _>(filter.since)
+        .bind::<BigInt, _>(filter.limit)
+        .bind::<Varchar, _>(tenant)
+        .load::<RemDataAggregateDB>(&mut *mut_conn)?;
+
+        Ok(dbs.into_iter().map(|d| d.into()).collect())
+    }
+
+    /// Return the most recent values of a metric, newest first. The metric can either be one of
+    /// the known REM data channels or any metric captured in the generic readings store.
+    #[instrument(skip_all)]
+    pub async fn list_readings(
+        &self,
+        tenant: &str,
+        filter: ReadingsFilter,
+    ) -> Result<Vec<Reading>, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+
+        let dbs = match REM_DATA_METRICS
+            .iter()
+            .find(|(name, _, _)| *name == filter.metric)
+        {
+            // The column name comes from the constant list above, so it is safe to format it into
+            // the query. Everything else is passed as a bind parameter.
+            Some((name, column, unit)) => sql_query(format!(
+                "SELECT device_id, '{name}' AS metric, {column}::float8 AS value, \
+                    $1 AS unit, created_at \
+                FROM rem_data \
+                WHERE {column} IS NOT NULL \
+                    AND tenant_id = $5 \
+                    AND ($2 IS NULL OR device_id = $2) \
+                    AND ($3 IS NULL OR created_at >= $3) \
+                ORDER BY created_at DESC \
+                LIMIT $4"
+            ))
+            .bind::<Nullable<Varchar>, _>(*unit)
+            .bind::<Nullable<Varchar>, _>(filter.device_id)
+            .bind::<Nullable<Timestamp>, _>(filter.since)
+            .bind::<BigInt, _>(filter.limit)
+            .bind::<Varchar, _>(tenant)
+            .load::<ReadingDB>(&mut *mut_conn)?,
+
+            None => {
+                let mut query = readings
+                    .select(ReadingDB::as_select())
+                    .filter(readings_tenant_id.eq(tenant))
+                    .filter(readings_metric.eq(filter.metric))
+                    .order(readings_created_at.desc())
+                    .limit(filter.limit)
+                    .into_boxed();
+
+                if let Some(device) = filter.device_id {
+                    query = query.filter(readings_device_id.eq(device));
+                }
+                if let Some(since) = filter.since {
+                    query = query.filter(readings_created_at.ge(since));
+                }
+
+                query.load::<ReadingDB>(&mut *mut_conn)?
+            }
+        };
+
+        Ok(dbs.into_iter().map(|d| d.into()).collect())
+    }
+
+    /// Return the calibrations of every channel of a device
+    #[instrument(skip_all)]
+    pub async fn list_calibrations(
+        &self,
+        tenant: &str,
+        device: &str,
+    ) -> Result<Vec<Calibration>, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        let dbs = calibrations
+            .select(CalibrationDB::as_select())
+            .filter(calibrations_tenant_id.eq(tenant))
+            .filter(calibrations_device_id.eq(device))
+            .order(calibrations_field)
+            .load::<CalibrationDB>(&mut *mut_conn)?;
+
+        Ok(dbs.into_iter().map(|d| d.into()).collect())
+    }
+
+    /// Create or replace the calibration of a channel of a device
+    #[instrument(skip_all)]
+    pub async fn upsert_calibration(
+        &self,
+        tenant: &str,
+        calibration: Calibration,
+    ) -> Result<(), RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        mut_conn.transaction(|conn| {
+            owned_device(conn, tenant, &calibration.device_id)?;
+
+            insert_into(calibrations)
+                .values((
+                    calibrations_device_id.eq(&calibration.device_id),
+                    calibrations_field.eq(&calibration.field),
+                    calibrations_offset.eq(calibration.offset),
+                    calibrations_scale.eq(calibration.scale),
+                    calibrations_tenant_id.eq(tenant),
+                ))
+                .on_conflict((calibrations_device_id, calibrations_field))
+                .do_update()
+                .set((
+                    calibrations_offset.eq(calibration.offset),
+                    calibrations_scale.eq(calibration.scale),
+                ))
+                .execute(conn)?;
+
+            Ok(())
+        })
+    }
+
+    /// Remove the calibration of a channel of a device, returns false if there was none
+    #[instrument(skip_all)]
+    pub async fn delete_calibration(
+        &self,
+        tenant: &str,
+        device: &str,
+        field: &str,
+    ) -> Result<bool, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        let deleted = delete(
+            calibrations
+                .filter(calibrations_tenant_id.eq(tenant))
+                .filter(calibrations_device_id.eq(device))
+                .filter(calibrations_field.eq(field)),
+        )
+        .execute(&mut *mut_conn)?;
+
+        Ok(deleted > 0)
+    }
+
+    /// Reapply the current calibrations of a device to a batch of its stored data, the rows ordered
+    /// by id that come `after` the given id. Rows that were stored before calibrations existed get
+    /// their current values recorded as the raw values. Returns the number of rows in the batch and
+    /// the id of its last row, to continue from.
+    #[instrument(skip_all)]
+    pub async fn recalibrate_rem_data(
+        &self,
+        tenant: &str,
+        device: &str,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<(usize, Option<String>), RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        mut_conn.transaction(|conn| {
+            let cals: Vec<Calibration> = calibrations
+                .select(CalibrationDB::as_select())
+                .filter(calibrations_tenant_id.eq(tenant))
+                .filter(calibrations_device_id.eq(device))
+                .load::<CalibrationDB>(conn)?
+                .into_iter()
+                .map(|d| d.into())
+                .collect();
+
+            // Paging by id rather than by offset, rows stored meanwhile can't shift the batches
+            let mut query = rem_data
+                .select((RemDataDB::as_select(), rem_data_raw))
+                .filter(rem_data_tenant_id.eq(tenant))
+                .filter(rem_data_device_id.eq(device))
+                .into_boxed();
+            if let Some(after) = after {
+                query = query.filter(rem_data_id.gt(after));
+            }
+            let rows = query
+                .order(rem_data_id)
+                .limit(limit)
+                .load::<(RemDataDB, Option<serde_json::Value>)>(conn)?;
+
+            let count = rows.len();
+            let last = rows.last().map(|(row, _)| row.id.clone());
+            for (row, raw_json) in rows {
+                let mut data: RemData = row.into();
+                let raw: RawChannels = match raw_json {
+                    Some(v) => serde_json::from_value(v)
+                        .map_err(|_| RemRepoError::InvalidRawChannels(data.id.clone()))?,
+                    None => data.raw_channels(),
+                };
+
+                data.calibrate(&raw, &cals);
+                update(rem_data.find((tenant, &data.id)))
+                    .set((
+                        rem_data_temperature.eq(data.temperature),
+                        rem_data_pressure.eq(data.pressure),
+                        rem_data_pm2_5.eq(data.pm2_5),
+                        rem_data_pm1_0.eq(data.pm1_0),
+                        rem_data_pm10.eq(data.pm10),
+                        rem_data_humidity.eq(data.humidity),
+                        rem_data_voc_index.eq(data.voc_index),
+                        rem_data_co2.eq(data.co2),
+                        rem_data_nox_index.eq(data.nox_index),
+                        rem_data_illuminance.eq(data.illuminance),
+                        rem_data_sound_level.eq(data.sound_level),
+                        rem_data_raw.eq(serde_json::to_value(&raw).ok()),
+                    ))
+                    .execute(conn)?;
+            }
+
+            Ok((count, last))
+        })
+    }
+
     /// This function is used to insert a new record into the database
     /// it takes a reference to the REM struct and returns a Result
-    /// with the inserted REM struct or an error
-    pub async fn insert_rem_data(&self, data: RemData) -> Result<(), RemRepoError> {
-        let a = (
-            rem_data_id.eq(data.id.clone()),
-            rem_data_device_id.eq(data.device_id),
-            rem_data_temperature.eq(data.temperature),
-            rem_data_pressure.eq(data.pressure),
-            rem_data_pm2_5.eq(data.pm2_5),
-            rem_data_pm1_0.eq(data.pm1_0),
-            rem_data_pm10.eq(data.pm10),
-            rem_data_humidity.eq(data.humidity),
-            rem_data_voc_index.eq(data.voc_index),
-        );
+    /// with the inserted REM struct or an error. The calibrations of the device are
+    /// applied before storing, the uncalibrated values are kept as the raw values. Any
+    /// extra payload fields are written to the generic readings store in the same transaction.
+    /// The data is stored with the tenant of the device, see [`claim_device`].
+    #[instrument(skip_all)]
+    pub async fn insert_rem_data(
+        &self,
+        tenant: Option<&str>,
+        mut data: RemData,
+    ) -> Result<RemData, RemRepoError> {
+        let extra: Vec<MetricValue> = data.extra_metrics();
 
         // Lock on the Database
-        let mut mut_conn = self.db.lock().await;
-        insert_into(rem_data)
-            .values(a)
-            .execute(&mut *mut_conn)
-            .map_err(|e| repo_error_from_database(e, data.id.clone()))?;
+        let mut mut_conn = self.conn().await?;
+        let _timer = DbTimer::start("insert_rem_data");
+
+        mut_conn.transaction(|conn| {
+            let tenant = claim_device(conn, tenant, &data.device_id)?;
+
+            let cals: Vec<Calibration> = calibrations
+                .select(CalibrationDB::as_select())
+                .filter(calibrations_tenant_id.eq(&tenant))
+                .filter(calibrations_device_id.eq(&data.device_id))
+                .load::<CalibrationDB>(conn)?
+                .into_iter()
+                .map(|d| d.into())
+                .collect();
+
+            let raw = data.raw_channels();
+            data.calibrate(&raw, &cals);
+
+            let a = (
+                rem_data_id.eq(&data.id),
+                rem_data_device_id.eq(&data.device_id),
+                rem_data_temperature.eq(data.temperature),
+                rem_data_pressure.eq(data.pressure),
+                rem_data_pm2_5.eq(data.pm2_5),
+                rem_data_pm1_0.eq(data.pm1_0),
+                rem_data_pm10.eq(data.pm10),
+                rem_data_humidity.eq(data.humidity),
+                rem_data_voc_index.eq(data.voc_index),
+                rem_data_co2.eq(data.co2),
+                rem_data_nox_index.eq(data.nox_index),
+                rem_data_illuminance.eq(data.illuminance),
+                rem_data_sound_level.eq(data.sound_level),
+                rem_data_raw.eq(serde_json::to_value(&raw).ok()),
+                rem_data_tenant_id.eq(&tenant),
+            );
+            insert_into(rem_data)
+                .values(a)
+                .execute(conn)
+                .map_err(|e| repo_error_from_database(e, data.id.clone()))?;
+
+            if !extra.is_empty() {
+                let extra_rows: Vec<_> = extra
+                    .into_iter()
+                    .map(|m| {
+                        (
+                            readings_data_id.eq(data.id.clone()),
+                            readings_device_id.eq(data.device_id.clone()),
+                            readings_metric.eq(m.metric),
+                            readings_value.eq(m.value),
+                            readings_unit.eq(m.unit),
+                            readings_tenant_id.eq(tenant.clone()),
+                        )
+                    })
+                    .collect();
+                insert_into(readings).values(&extra_rows).execute(conn)?;
+            }
+
+            Ok::<(), RemRepoError>(())
+        })?;
+
+        Ok(data)
+    }
+
+    /// Store a new command
+    #[instrument(skip_all)]
+    pub async fn insert_command(
+        &self,
+        tenant: &str,
+        command: &Command,
+    ) -> Result<(), RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        mut_conn.transaction(|conn| {
+            owned_device(conn, tenant, &command.device_id)?;
+
+            insert_into(commands)
+                .values((
+                    commands_id.eq(&command.id),
+                    commands_device_id.eq(&command.device_id),
+                    commands_command.eq(&command.command),
+                    commands_params.eq(&command.params),
+                    commands_status.eq(command.status.as_str()),
+                    commands_tenant_id.eq(tenant),
+                ))
+                .execute(conn)
+                .map_err(|e| repo_error_from_database(e, command.id.clone()))?;
+
+            Ok(())
+        })
+    }
+
+    /// Set the status of a command, along with the response if there is one
+    #[instrument(skip_all)]
+    pub async fn set_command_status(
+        &self,
+        tenant: &str,
+        id: &str,
+        status: CommandStatus,
+        response: Option<Value>,
+    ) -> Result<(), RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        update(
+            commands
+                .filter(commands_tenant_id.eq(tenant))
+                .filter(commands_id.eq(id)),
+        )
+        .set((
+            commands_status.eq(status.as_str()),
+            commands_response.eq(response),
+        ))
+        .execute(&mut *mut_conn)?;
+
+        Ok(())
+    }
+
+    /// Record the acknowledgement of a device for one of its commands. Commands that already
+    /// reached a final status are left alone, so a late or redelivered acknowledgement doesn't
+    /// move them back. Returns false in that case, and an error if the device has no such command.
+    /// With a `tenant` only the commands of that tenant are considered.
+    #[instrument(skip_all)]
+    pub async fn acknowledge_command(
+        &self,
+        tenant: Option<&str>,
+        id: &str,
+        device: &str,
+        status: CommandStatus,
+        response: Value,
+    ) -> Result<bool, RemRepoError> {
+        let open_statuses = [
+            CommandStatus::Pending,
+            CommandStatus::Sent,
+            CommandStatus::Accepted,
+        ]
+        .map(|s| s.as_str());
+
+        let mut mut_conn = self.conn().await?;
+        let _timer = DbTimer::start("acknowledge_command");
+        mut_conn.transaction(|conn| {
+            let mut command = commands
+                .filter(commands_id.eq(id))
+                .filter(commands_device_id.eq(device))
+                .into_boxed();
+            if let Some(tenant) = tenant {
+                command = command.filter(commands_tenant_id.eq(tenant));
+            }
+            let command_ids = command.select(commands_id);
+
+            let updated = update(
+                commands
+                    .filter(commands_id.eq_any(command_ids))
+                    .filter(commands_status.eq_any(open_statuses)),
+            )
+            .set((
+                commands_status.eq(status.as_str()),
+                commands_response.eq(&response),
+            ))
+            .execute(conn)?;
+            if updated > 0 {
+                return Ok(true);
+            }
+
+            let mut command = commands
+                .filter(commands_id.eq(id))
+                .filter(commands_device_id.eq(device))
+                .into_boxed();
+            if let Some(tenant) = tenant {
+                command = command.filter(commands_tenant_id.eq(tenant));
+            }
+            let exists = select(exists(command)).get_result::<bool>(conn)?;
+            if exists {
+                Ok(false)
+            } else {
+                Err(RemRepoError::UnknownCommand(id.to_string()))
+            }
+        })
+    }
+
+    /// The most recent commands of a device, newest first
+    #[instrument(skip_all)]
+    pub async fn list_commands(
+        &self,
+        tenant: &str,
+        device: &str,
+        limit: i64,
+    ) -> Result<Vec<Command>, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        let dbs = commands
+            .select(CommandDB::as_select())
+            .filter(commands_tenant_id.eq(tenant))
+            .filter(commands_device_id.eq(device))
+            .order(commands_created_at.desc())
+            .limit(limit)
+            .load::<CommandDB>(&mut *mut_conn)?;
+
+        Ok(dbs.into_iter().map(|d| d.into()).collect())
+    }
+
+    /// A single command of a device, if it exists
+    #[instrument(skip_all)]
+    pub async fn get_command(
+        &self,
+        tenant: &str,
+        device: &str,
+        id: &str,
+    ) -> Result<Option<Command>, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        let db = commands
+            .select(CommandDB::as_select())
+            .filter(commands_tenant_id.eq(tenant))
+            .filter(commands_id.eq(id))
+            .filter(commands_device_id.eq(device))
+            .first::<CommandDB>(&mut *mut_conn)
+            .optional()?;
+
+        Ok(db.map(|d| d.into()))
+    }
+
+    /// Store a message that can't be processed, along with the reason why, so it can be inspected
+    /// and replayed by hand.
+    #[instrument(skip_all)]
+    pub async fn insert_dead_letter(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        error: &str,
+    ) -> Result<(), RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        let _timer = DbTimer::start("insert_dead_letter");
+        insert_into(dead_letters)
+            .values((
+                dead_letters_topic.eq(topic),
+                dead_letters_payload.eq(payload),
+                dead_letters_error.eq(error),
+            ))
+            .execute(&mut *mut_conn)?;
+
+        Ok(())
+    }
+
+    /// The key the messages of a device are verified with, if it has one
+    #[instrument(skip_all)]
+    pub async fn find_device_key(&self, device: &str) -> Result<Option<DeviceKey>, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        let _timer = DbTimer::start("find_device_key");
+        let db = device_keys
+            .select(DeviceKeyDB::as_select())
+            .filter(device_keys_device_id.eq(device))
+            .first::<DeviceKeyDB>(&mut *mut_conn)
+            .optional()?;
+
+        db.map(DeviceKey::try_from).transpose()
+    }
+
+    /// Create or replace the key of a device of a tenant
+    #[instrument(skip_all)]
+    pub async fn set_device_key(
+        &self,
+        tenant: &str,
+        device: &str,
+        algorithm: SignatureAlgorithm,
+        key: &[u8],
+    ) -> Result<DeviceKey, RemRepoError> {
+        let now = chrono::Utc::now().naive_utc();
+
+        let mut mut_conn = self.conn().await?;
+        mut_conn.transaction(|conn| {
+            owned_device(conn, tenant, device)?;
+
+            let db = insert_into(device_keys)
+                .values((
+                    device_keys_device_id.eq(device),
+                    device_keys_algorithm.eq(algorithm.as_str()),
+                    device_keys_key.eq(key),
+                ))
+                .on_conflict(device_keys_device_id)
+                .do_update()
+                .set((
+                    device_keys_algorithm.eq(algorithm.as_str()),
+                    device_keys_key.eq(key),
+                    device_keys_created_at.eq(now),
+                    device_keys_last_sent_at.eq(None::<NaiveDateTime>),
+                ))
+                .returning(DeviceKeyDB::as_returning())
+                .get_result::<DeviceKeyDB>(conn)?;
+
+            DeviceKey::try_from(db)
+        })
+    }
+
+    /// Record when a device sent a message that must not be replayed. Returns false if the device
+    /// already sent one later, or has no key. The same time is accepted again, so that a message
+    /// retried by the listener isn't taken for a replay.
+    #[instrument(skip_all)]
+    pub async fn advance_device_sent_at(
+        &self,
+        device: &str,
+        sent_at: NaiveDateTime,
+    ) -> Result<bool, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        let _timer = DbTimer::start("advance_device_sent_at");
+        let updated = update(
+            device_keys.filter(device_keys_device_id.eq(device)).filter(
+                device_keys_last_sent_at
+                    .is_null()
+                    .or(device_keys_last_sent_at.le(sent_at)),
+            ),
+        )
+        .set(device_keys_last_sent_at.eq(sent_at))
+        .execute(&mut *mut_conn)?;
+
+        Ok(updated > 0)
+    }
+
+    /// Remove the key of a device of a tenant, returns false if it didn't have one
+    #[instrument(skip_all)]
+    pub async fn delete_device_key(
+        &self,
+        tenant: &str,
+        device: &str,
+    ) -> Result<bool, RemRepoError> {
+        let owned = devices
+            .select(devices_device_id)
+            .filter(devices_tenant_id.eq(tenant))
+            .filter(devices_device_id.eq(device));
+
+        let mut mut_conn = self.conn().await?;
+        let deleted = delete(device_keys.filter(device_keys_device_id.eq_any(owned)))
+            .execute(&mut *mut_conn)?;
+
+        Ok(deleted > 0)
+    }
+
+    /// Store a message that was rejected for its signature. Without a `tenant` the message is
+    /// recorded for the tenant of the device, or the default tenant for unknown devices.
+    #[instrument(skip_all)]
+    pub async fn insert_rejected_message(
+        &self,
+        tenant: Option<&str>,
+        device: &str,
+        topic: &str,
+        reason: &str,
+        error: &str,
+        payload: &[u8],
+    ) -> Result<(), RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        let _timer = DbTimer::start("insert_rejected_message");
+        let tenant = match tenant {
+            Some(tenant) => tenant.to_string(),
+            None => devices
+                .select(devices_tenant_id)
+                .filter(devices_device_id.eq(device))
+                .first::<String>(&mut *mut_conn)
+                .optional()?
+                .unwrap_or_else(|| DEFAULT_TENANT.to_string()),
+        };
+
+        insert_into(rejected_messages)
+            .values((
+                rejected_messages_tenant_id.eq(&tenant),
+                rejected_messages_device_id.eq(device),
+                rejected_messages_topic.eq(topic),
+                rejected_messages_reason.eq(reason),
+                rejected_messages_error.eq(error),
+                rejected_messages_payload.eq(payload),
+            ))
+            .execute(&mut *mut_conn)?;
 
         Ok(())
     }
 
+    /// The most recent rejected messages of a tenant, optionally of a single device, newest first
+    #[instrument(skip_all)]
+    pub async fn list_rejected_messages(
+        &self,
+        tenant: &str,
+        device: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<RejectedMessage>, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        let mut query = rejected_messages
+            .select(RejectedMessageDB::as_select())
+            .filter(rejected_messages_tenant_id.eq(tenant))
+            .into_boxed();
+        if let Some(device) = device {
+            query = query.filter(rejected_messages_device_id.eq(device));
+        }
+
+        let dbs = query
+            .order((
+                rejected_messages_created_at.desc(),
+                rejected_messages_id.desc(),
+            ))
+            .limit(limit)
+            .load::<RejectedMessageDB>(&mut *mut_conn)?;
+
+        Ok(dbs.into_iter().map(|d| d.into()).collect())
+    }
+
     /// Handle the status message from the REM device and insert it into the database. If there
-    /// is any issue with insertion it will return a RemRepoError type
-    pub async fn insert_rem_status(&self, status: RemStatus) -> Result<(), RemRepoError> {
-        let r = (
-            rem_status_id.eq(status.id.clone()),
-            rem_status_device_id.eq(status.device_id),
-            rem_status_up_time.eq(status.up_time),
-            rem_status_rssi.eq(status.rssi),
-        );
+    /// is any issue with insertion it will return a RemRepoError type. The status is stored with
+    /// the tenant of the device, see [`claim_device`].
+    #[instrument(skip_all)]
+    pub async fn insert_rem_status(
+        &self,
+        tenant: Option<&str>,
+        status: RemStatus,
+    ) -> Result<(), RemRepoError> {
+        let reported = status
+            .config
+            .as_ref()
+            .and_then(|c| serde_json::to_value(c).ok());
 
         // Lock on the Database
-        let mut mut_conn = self.db.lock().await;
-        insert_into(rem_status)
-            .values(r)
+        let mut mut_conn = self.conn().await?;
+        let _timer = DbTimer::start("insert_rem_status");
+        mut_conn.transaction(|conn| {
+            let tenant = claim_device(conn, tenant, &status.device_id)?;
+
+            insert_into(rem_status)
+                .values((
+                    rem_status_id.eq(&status.id),
+                    rem_status_device_id.eq(&status.device_id),
+                    rem_status_up_time.eq(status.up_time),
+                    rem_status_rssi.eq(status.rssi),
+                    rem_status_firmware_version.eq(&status.firmware_version),
+                    rem_status_tenant_id.eq(&tenant),
+                ))
+                .execute(conn)
+                .map_err(|e| repo_error_from_database(e, status.id.clone()))?;
+
+            // Keep track of the configuration the device reported last
+            if let Some(reported) = reported {
+                let now = chrono::Utc::now().naive_utc();
+                insert_into(device_configs)
+                    .values((
+                        device_configs_device_id.eq(&status.device_id),
+                        device_configs_reported.eq(&reported),
+                        device_configs_reported_at.eq(now),
+                        device_configs_tenant_id.eq(&tenant),
+                    ))
+                    .on_conflict(device_configs_device_id)
+                    .do_update()
+                    .set((
+                        device_configs_reported.eq(&reported),
+                        device_configs_reported_at.eq(now),
+                    ))
+                    .execute(conn)?;
+            }
+
+            // Record the running firmware, and verify the update of the device if it is now
+            // running the target version
+            if let Some(version) = &status.firmware_version {
+                update(devices.filter(devices_device_id.eq(&status.device_id)))
+                    .set(devices_firmware_version.eq(version))
+                    .execute(conn)?;
+
+                update(
+                    firmware_updates
+                        .filter(firmware_updates_device_id.eq(&status.device_id))
+                        .filter(firmware_updates_target_version.eq(version))
+                        .filter(
+                            firmware_updates_status.ne(FirmwareUpdateStatus::Verified.as_str()),
+                        ),
+                )
+                .set((
+                    firmware_updates_status.eq(FirmwareUpdateStatus::Verified.as_str()),
+                    firmware_updates_progress.eq(None::<i16>),
+                ))
+                .execute(conn)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Store the desired configuration of a device, replacing the previous one
+    #[instrument(skip_all)]
+    pub async fn set_desired_config(
+        &self,
+        tenant: &str,
+        device: &str,
+        config: &DeviceConfig,
+    ) -> Result<DeviceConfigState, RemRepoError> {
+        let desired = serde_json::to_value(config).map_err(|_| RemRepoError::InvalidMessage)?;
+        let now = chrono::Utc::now().naive_utc();
+
+        let mut mut_conn = self.conn().await?;
+        mut_conn.transaction(|conn| {
+            owned_device(conn, tenant, device)?;
+
+            let db = insert_into(device_configs)
+                .values((
+                    device_configs_device_id.eq(device),
+                    device_configs_desired.eq(&desired),
+                    device_configs_desired_at.eq(now),
+                    device_configs_tenant_id.eq(tenant),
+                ))
+                .on_conflict(device_configs_device_id)
+                .do_update()
+                .set((
+                    device_configs_desired.eq(&desired),
+                    device_configs_desired_at.eq(now),
+                ))
+                .returning(DeviceConfigDB::as_returning())
+                .get_result::<DeviceConfigDB>(conn)?;
+
+            Ok(db.into())
+        })
+    }
+
+    /// The desired and reported configuration of a device, if either is known
+    #[instrument(skip_all)]
+    pub async fn get_config_state(
+        &self,
+        tenant: &str,
+        device: &str,
+    ) -> Result<Option<DeviceConfigState>, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        let db = device_configs
+            .select(DeviceConfigDB::as_select())
+            .filter(device_configs_tenant_id.eq(tenant))
+            .filter(device_configs_device_id.eq(device))
+            .first::<DeviceConfigDB>(&mut *mut_conn)
+            .optional()?;
+
+        Ok(db.map(|d| d.into()))
+    }
+
+    /// The desired and reported configuration of every known device
+    #[instrument(skip_all)]
+    pub async fn list_config_states(
+        &self,
+        tenant: &str,
+    ) -> Result<Vec<DeviceConfigState>, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        let dbs = device_configs
+            .select(DeviceConfigDB::as_select())
+            .filter(device_configs_tenant_id.eq(tenant))
+            .order(device_configs_device_id)
+            .load::<DeviceConfigDB>(&mut *mut_conn)?;
+
+        Ok(dbs.into_iter().map(|d| d.into()).collect())
+    }
+
+    /// Store the metadata of an uploaded firmware image
+    #[instrument(skip_all)]
+    pub async fn insert_firmware_image(&self, image: &FirmwareImage) -> Result<(), RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        insert_into(firmware_images)
+            .values((
+                firmware_images_version.eq(&image.version),
+                firmware_images_size.eq(image.size),
+                firmware_images_sha256.eq(&image.sha256),
+            ))
+            .execute(&mut *mut_conn)
+            .map_err(|e| repo_error_from_database(e, image.version.clone()))?;
+
+        Ok(())
+    }
+
+    /// Forget the image of a version, when its upload failed
+    #[instrument(skip_all)]
+    pub async fn delete_firmware_image(&self, version: &str) -> Result<(), RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        delete(firmware_images.filter(firmware_images_version.eq(version)))
+            .execute(&mut *mut_conn)?;
+
+        Ok(())
+    }
+
+    /// The firmware image of a version, if it was uploaded
+    #[instrument(skip_all)]
+    pub async fn get_firmware_image(
+        &self,
+        version: &str,
+    ) -> Result<Option<FirmwareImage>, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        let db = firmware_images
+            .select(FirmwareImageDB::as_select())
+            .filter(firmware_images_version.eq(version))
+            .first::<FirmwareImageDB>(&mut *mut_conn)
+            .optional()?;
+
+        Ok(db.map(|d| d.into()))
+    }
+
+    /// All the uploaded firmware images, newest first
+    #[instrument(skip_all)]
+    pub async fn list_firmware_images(&self) -> Result<Vec<FirmwareImage>, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        let dbs = firmware_images
+            .select(FirmwareImageDB::as_select())
+            .order(firmware_images_created_at.desc())
+            .load::<FirmwareImageDB>(&mut *mut_conn)?;
+
+        Ok(dbs.into_iter().map(|d| d.into()).collect())
+    }
+
+    /// Put a device in a group, or remove it from its group
+    #[instrument(skip_all)]
+    pub async fn set_device_group(
+        &self,
+        tenant: &str,
+        device: &str,
+        group: Option<&str>,
+    ) -> Result<Device, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        mut_conn.transaction(|conn| {
+            owned_device(conn, tenant, device)?;
+
+            let db = update(devices.filter(devices_device_id.eq(device)))
+                .set(devices_group_name.eq(group))
+                .returning(DeviceDB::as_returning())
+                .get_result::<DeviceDB>(conn)?;
+
+            Ok(db.into())
+        })
+    }
+
+    /// Register a device to a tenant before it sent any message
+    #[instrument(skip_all)]
+    pub async fn register_device(
+        &self,
+        tenant: &str,
+        device: &str,
+    ) -> Result<Device, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        let db = insert_into(devices)
+            .values((devices_device_id.eq(device), devices_tenant_id.eq(tenant)))
+            .returning(DeviceDB::as_returning())
+            .get_result::<DeviceDB>(&mut *mut_conn)
+            .map_err(|e| repo_error_from_database(e, device.to_string()))?;
+
+        Ok(db.into())
+    }
+
+    /// The known devices, optionally only the ones in a group
+    #[instrument(skip_all)]
+    pub async fn list_devices(
+        &self,
+        tenant: &str,
+        group: Option<&str>,
+    ) -> Result<Vec<Device>, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        let mut query = devices
+            .select(DeviceDB::as_select())
+            .filter(devices_tenant_id.eq(tenant))
+            .order(devices_device_id)
+            .into_boxed();
+        if let Some(group) = group {
+            query = query.filter(devices_group_name.eq(group));
+        }
+
+        let dbs = query.load::<DeviceDB>(&mut *mut_conn)?;
+        Ok(dbs.into_iter().map(|d| d.into()).collect())
+    }
+
+    /// Assign a firmware version to devices, replacing any update they were assigned before
+    #[instrument(skip_all)]
+    pub async fn assign_firmware(
+        &self,
+        tenant: &str,
+        version: &str,
+        device_ids: &[String],
+    ) -> Result<Vec<FirmwareUpdate>, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        mut_conn.transaction(|conn| {
+            let mut updates = Vec::with_capacity(device_ids.len());
+            for device in device_ids {
+                owned_device(conn, tenant, device)?;
+
+                let db = insert_into(firmware_updates)
+                    .values((
+                        firmware_updates_device_id.eq(device),
+                        firmware_updates_target_version.eq(version),
+                        firmware_updates_tenant_id.eq(tenant),
+                    ))
+                    .on_conflict(firmware_updates_device_id)
+                    .do_update()
+                    .set((
+                        firmware_updates_target_version.eq(version),
+                        firmware_updates_status.eq(FirmwareUpdateStatus::Assigned.as_str()),
+                        firmware_updates_progress.eq(None::<i16>),
+                        firmware_updates_message.eq(None::<String>),
+                        firmware_updates_created_at.eq(diesel::dsl::now),
+                    ))
+                    .returning(FirmwareUpdateDB::as_returning())
+                    .get_result::<FirmwareUpdateDB>(conn)?;
+                updates.push(db.into());
+            }
+
+            Ok(updates)
+        })
+    }
+
+    /// Set the status of the firmware update of a device
+    #[instrument(skip_all)]
+    pub async fn set_firmware_update_status(
+        &self,
+        tenant: &str,
+        device: &str,
+        status: FirmwareUpdateStatus,
+    ) -> Result<(), RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        update(
+            firmware_updates
+                .filter(firmware_updates_tenant_id.eq(tenant))
+                .filter(firmware_updates_device_id.eq(device)),
+        )
+        .set(firmware_updates_status.eq(status.as_str()))
+        .execute(&mut *mut_conn)?;
+
+        Ok(())
+    }
+
+    /// Record the progress a device reported for its firmware update. Progress for a version that
+    /// isn't the target of the device, or after the update was verified, is ignored and returns false.
+    /// With a `tenant` only the updates of that tenant are considered.
+    #[instrument(skip_all)]
+    pub async fn report_firmware_progress(
+        &self,
+        tenant: Option<&str>,
+        device: &str,
+        progress: &FirmwareProgress,
+    ) -> Result<bool, RemRepoError> {
+        let mut query = firmware_updates
+            .select(firmware_updates_device_id)
+            .filter(firmware_updates_device_id.eq(device))
+            .into_boxed();
+        if let Some(tenant) = tenant {
+            query = query.filter(firmware_updates_tenant_id.eq(tenant));
+        }
+
+        let mut mut_conn = self.conn().await?;
+        let _timer = DbTimer::start("report_firmware_progress");
+        let updated = update(
+            firmware_updates
+                .filter(firmware_updates_device_id.eq_any(query))
+                .filter(firmware_updates_target_version.eq(&progress.version))
+                .filter(firmware_updates_status.ne(FirmwareUpdateStatus::Verified.as_str())),
+        )
+        .set((
+            firmware_updates_status.eq(progress.status.as_str()),
+            firmware_updates_progress.eq(progress.progress.map(i16::from)),
+            firmware_updates_message.eq(&progress.message),
+        ))
+        .execute(&mut *mut_conn)?;
+
+        Ok(updated > 0)
+    }
+
+    /// The firmware updates of all devices, optionally only the ones to a version
+    #[instrument(skip_all)]
+    pub async fn list_firmware_updates(
+        &self,
+        tenant: &str,
+        version: Option<&str>,
+    ) -> Result<Vec<FirmwareUpdate>, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        let mut query = firmware_updates
+            .select(FirmwareUpdateDB::as_select())
+            .filter(firmware_updates_tenant_id.eq(tenant))
+            .order(firmware_updates_device_id)
+            .into_boxed();
+        if let Some(version) = version {
+            query = query.filter(firmware_updates_target_version.eq(version));
+        }
+
+        let dbs = query.load::<FirmwareUpdateDB>(&mut *mut_conn)?;
+        Ok(dbs.into_iter().map(|d| d.into()).collect())
+    }
+
+    /// Store a new API key along with the hash of the key
+    #[instrument(skip_all)]
+    pub async fn insert_api_key(&self, key: &ApiKey, hash: &str) -> Result<(), RemRepoError> {
+        let scopes: Vec<&str> = key.scopes.iter().map(ApiScope::as_str).collect();
+
+        let mut mut_conn = self.conn().await?;
+        insert_into(api_keys)
+            .values((
+                api_keys_id.eq(&key.id),
+                api_keys_name.eq(&key.name),
+                api_keys_prefix.eq(&key.prefix),
+                api_keys_key_hash.eq(hash),
+                api_keys_scopes.eq(scopes),
+                api_keys_tenant_id.eq(&key.tenant_id),
+            ))
             .execute(&mut *mut_conn)
-            .map(|_| ())
-            .map_err(|e| repo_error_from_database(e, status.id.clone()))
+            .map_err(|e| repo_error_from_database(e, key.id.clone()))?;
+
+        Ok(())
+    }
+
+    /// The API key with the given hash, unless it was revoked
+    #[instrument(skip_all)]
+    pub async fn find_api_key(&self, hash: &str) -> Result<Option<ApiKey>, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        let db = api_keys
+            .select(ApiKeyDB::as_select())
+            .filter(api_keys_key_hash.eq(hash))
+            .filter(api_keys_revoked_at.is_null())
+            .first::<ApiKeyDB>(&mut *mut_conn)
+            .optional()?;
+
+        Ok(db.map(|d| d.into()))
+    }
+
+    /// All the API keys of a tenant, including the revoked ones
+    #[instrument(skip_all)]
+    pub async fn list_api_keys(&self, tenant: &str) -> Result<Vec<ApiKey>, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        let dbs = api_keys
+            .select(ApiKeyDB::as_select())
+            .filter(api_keys_tenant_id.eq(tenant))
+            .order(api_keys_name)
+            .load::<ApiKeyDB>(&mut *mut_conn)?;
+
+        Ok(dbs.into_iter().map(|d| d.into()).collect())
+    }
+
+    /// Revoke an API key of a tenant, returns false if there is no such key or it was revoked before
+    #[instrument(skip_all)]
+    pub async fn revoke_api_key(&self, tenant: &str, id: &str) -> Result<bool, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        let updated = update(
+            api_keys
+                .filter(api_keys_tenant_id.eq(tenant))
+                .filter(api_keys_id.eq(id))
+                .filter(api_keys_revoked_at.is_null()),
+        )
+        .set(api_keys_revoked_at.eq(diesel::dsl::now))
+        .execute(&mut *mut_conn)?;
+
+        Ok(updated > 0)
+    }
+
+    /// Create a tenant
+    #[instrument(skip_all)]
+    pub async fn insert_tenant(&self, id: &str, name: &str) -> Result<Tenant, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        let db = insert_into(tenants)
+            .values((tenants_id.eq(id), tenants_name.eq(name)))
+            .returning(TenantDB::as_returning())
+            .get_result::<TenantDB>(&mut *mut_conn)
+            .map_err(|e| repo_error_from_database(e, id.to_string()))?;
+
+        Ok(db.into())
+    }
+
+    /// All the tenants
+    #[instrument(skip_all)]
+    pub async fn list_tenants(&self) -> Result<Vec<Tenant>, RemRepoError> {
+        let mut mut_conn = self.conn().await?;
+        let dbs = tenants
+            .select(TenantDB::as_select())
+            .order(tenants_id)
+            .load::<TenantDB>(&mut *mut_conn)?;
+
+        Ok(dbs.into_iter().map(|d| d.into()).collect())
     }
 }
@@ -1,7 +1,59 @@
 // @generated automatically by Diesel CLI.
 
 diesel::table! {
-    rem_data (id) {
+    api_keys (id) {
+        id -> Varchar,
+        name -> Varchar,
+        prefix -> Varchar,
+        #[max_length = 64]
+        key_hash -> Varchar,
+        scopes -> Array<Varchar>,
+        created_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+        tenant_id -> Varchar,
+    }
+}
+
+diesel::table! {
+    calibrations (device_id, field) {
+        device_id -> Varchar,
+        field -> Varchar,
+        offset -> Float4,
+        scale -> Float4,
+        updated_at -> Timestamp,
+        tenant_id -> Varchar,
+    }
+}
+
+diesel::table! {
+    commands (id) {
+        #[max_length = 36]
+        id -> Varchar,
+        device_id -> Varchar,
+        command -> Varchar,
+        params -> Nullable<Jsonb>,
+        status -> Varchar,
+        response -> Nullable<Jsonb>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        tenant_id -> Varchar,
+    }
+}
+
+diesel::table! {
+    device_configs (device_id) {
+        device_id -> Varchar,
+        desired -> Nullable<Jsonb>,
+        desired_at -> Nullable<Timestamp>,
+        reported -> Nullable<Jsonb>,
+        reported_at -> Nullable<Timestamp>,
+        updated_at -> Timestamp,
+        tenant_id -> Varchar,
+    }
+}
+
+diesel::table! {
+    rem_data (tenant_id, id) {
         #[max_length = 36]
         id -> Varchar,
         device_id -> Varchar,
@@ -13,18 +65,142 @@ diesel::table! {
         humidity -> Float4,
         voc_index -> Float4,
         created_at -> Timestamp,
+        co2 -> Nullable<Float4>,
+        nox_index -> Nullable<Float4>,
+        illuminance -> Nullable<Float4>,
+        sound_level -> Nullable<Float4>,
+        raw -> Nullable<Jsonb>,
+        tenant_id -> Varchar,
+    }
+}
+
+diesel::table! {
+    dead_letters (id) {
+        id -> Int8,
+        topic -> Varchar,
+        payload -> Bytea,
+        error -> Varchar,
+        created_at -> Timestamp,
     }
 }
 
 diesel::table! {
-    rem_status (id) {
+    device_keys (device_id) {
+        device_id -> Varchar,
+        algorithm -> Varchar,
+        key -> Bytea,
+        created_at -> Timestamp,
+        last_sent_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    devices (device_id) {
+        device_id -> Varchar,
+        group_name -> Nullable<Varchar>,
+        firmware_version -> Nullable<Varchar>,
+        updated_at -> Timestamp,
+        tenant_id -> Varchar,
+    }
+}
+
+diesel::table! {
+    firmware_images (version) {
+        version -> Varchar,
+        size -> Int8,
+        #[max_length = 64]
+        sha256 -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    firmware_updates (device_id) {
+        device_id -> Varchar,
+        target_version -> Varchar,
+        status -> Varchar,
+        progress -> Nullable<Int2>,
+        message -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        tenant_id -> Varchar,
+    }
+}
+
+diesel::table! {
+    readings (id) {
+        id -> Int8,
+        #[max_length = 36]
+        data_id -> Varchar,
+        device_id -> Varchar,
+        metric -> Varchar,
+        value -> Float8,
+        unit -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        tenant_id -> Varchar,
+    }
+}
+
+diesel::table! {
+    rejected_messages (id) {
+        id -> Int8,
+        tenant_id -> Varchar,
+        device_id -> Varchar,
+        topic -> Varchar,
+        reason -> Varchar,
+        error -> Varchar,
+        payload -> Bytea,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    rem_status (tenant_id, id) {
         #[max_length = 36]
         id -> Varchar,
         device_id -> Varchar,
         up_time -> Int4,
         created_at -> Timestamp,
         rssi -> Nullable<Int4>,
+        firmware_version -> Nullable<Varchar>,
+        tenant_id -> Varchar,
+    }
+}
+
+diesel::table! {
+    tenants (id) {
+        id -> Varchar,
+        name -> Varchar,
+        created_at -> Timestamp,
     }
 }
 
-diesel::allow_tables_to_appear_in_same_query!(rem_data, rem_status,);
+diesel::joinable!(api_keys -> tenants (tenant_id));
+diesel::joinable!(calibrations -> tenants (tenant_id));
+diesel::joinable!(commands -> tenants (tenant_id));
+diesel::joinable!(device_configs -> tenants (tenant_id));
+diesel::joinable!(device_keys -> devices (device_id));
+diesel::joinable!(devices -> tenants (tenant_id));
+diesel::joinable!(firmware_updates -> firmware_images (target_version));
+diesel::joinable!(firmware_updates -> tenants (tenant_id));
+diesel::joinable!(readings -> tenants (tenant_id));
+diesel::joinable!(rejected_messages -> tenants (tenant_id));
+diesel::joinable!(rem_data -> tenants (tenant_id));
+diesel::joinable!(rem_status -> tenants (tenant_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    api_keys,
+    calibrations,
+    commands,
+    dead_letters,
+    device_configs,
+    device_keys,
+    devices,
+    firmware_images,
+    firmware_updates,
+    readings,
+    rejected_messages,
+    rem_data,
+    rem_status,
+    tenants,
+);
@@ -0,0 +1,562 @@
+//! Verification of signed device messages.
+//!
+//! Devices can be given a key, either a secret shared with the listener for HMAC-SHA256 or the
+//! public key of an Ed25519 key pair. The messages of a device with a key are only stored when they
+//! carry a valid signature, hex encoded in one of two places:
+//!
+//! - The `signature` MQTT v5 user property, signing the payload exactly as published.
+//! - A `signature` field of the JSON payload, for clients that can't set user properties. This
+//!   signs the payload without that field, serialized as compact JSON with the keys of every object
+//!   sorted by their UTF-8 bytes, e.g. `{"deviceId":"rem-1","id":"...","uptime":5}`. Strings are
+//!   written as UTF-8 with only `"`, `\` and control characters escaped, the latter as `\n` and the
+//!   like or `\u00XX`. Integers are written in decimal, other numbers in the shortest form that
+//!   reads back as the same double, with a `.0` for whole numbers and an exponent from `1e+16` up
+//!   and below `1e-5`: `21.50` is signed as `21.5`, `1E2` as `100.0` and `0.0000001` as `1e-7`.
+//!   Devices that can't produce this form should sign with the user property instead.
+//!
+//! Command acknowledgements and firmware progress change the state of a device, so replaying an
+//! old one could undo a newer one. When the device has a key they must carry a signed `sentAt`
+//! time, and are rejected unless it is no earlier than the one of the last such message of the
+//! device, and not more than [`MAX_CLOCK_SKEW`] ahead of the listener. Setting a new key forgets the
+//! last time, in case the clock of the device went back.
+//!
+//! Messages of devices without a key are stored as before, unless `REQUIRE_SIGNED_MESSAGES` is set.
+//! Messages that fail verification are recorded in the rejected messages table rather than the
+//! dead letter table, since replaying them would never succeed.
+use std::{future::Future, sync::Arc};
+
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+use ed25519_dalek::{Signature, VerifyingKey};
+use hmac::{Hmac, Mac};
+use paho_mqtt::Message;
+use serde_json::Value;
+use sha2::Sha256;
+use thiserror::Error;
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+
+use crate::{
+    model::{DeviceKey, SignatureAlgorithm},
+    mqtt::MQTTClientError,
+    repo::RemRepo,
+};
+
+/// Name of the MQTT v5 user property and of the payload field carrying the signature
+pub const SIGNATURE_PROPERTY: &str = "signature";
+
+/// Shortest shared secret accepted for HMAC-SHA256
+const MIN_HMAC_KEY_LEN: usize = 16;
+
+/// How far ahead of the listener the `sentAt` time of a message may be
+pub const MAX_CLOCK_SKEW: TimeDelta = TimeDelta::minutes(5);
+
+#[derive(Error, Debug)]
+pub enum SignatureError {
+    #[error("Message of device {} isn't signed", .0)]
+    Unsigned(String),
+    #[error("Message of device {} has an invalid signature: {}", .0, .1)]
+    Invalid(String, String),
+    #[error("Message of device {} may be replayed: {}", .0, .1)]
+    Replayed(String, String),
+}
+
+/// Whether a message may be replayed, see [`Verifier::check`]
+pub enum Replay {
+    Allowed,
+    /// The message is only admitted once, along with when the device says it sent it
+    Protected(Option<DateTime<Utc>>),
+}
+
+impl SignatureError {
+    pub fn device_id(&self) -> &str {
+        match self {
+            SignatureError::Unsigned(device_id)
+            | SignatureError::Invalid(device_id, _)
+            | SignatureError::Replayed(device_id, _) => device_id,
+        }
+    }
+
+    /// Short reason the message was rejected, as stored with the rejected message
+    pub fn reason(&self) -> &'static str {
+        match self {
+            SignatureError::Unsigned(_) => "unsigned",
+            SignatureError::Invalid(_, _) => "invalid",
+            SignatureError::Replayed(_, _) => "replayed",
+        }
+    }
+}
+
+/// Check that a key can be used with an algorithm, returns why not otherwise.
+pub fn validate_key(algorithm: SignatureAlgorithm, key: &[u8]) -> Result<(), String> {
+    match algorithm {
+        SignatureAlgorithm::HmacSha256 if key.len() < MIN_HMAC_KEY_LEN => Err(format!(
+            "HMAC keys need at least {} bytes",
+            MIN_HMAC_KEY_LEN
+        )),
+        SignatureAlgorithm::HmacSha256 => Ok(()),
+        SignatureAlgorithm::Ed25519 => ed25519_key(key).map(|_| ()),
+    }
+}
+
+fn ed25519_key(key: &[u8]) -> Result<VerifyingKey, String> {
+    let bytes = key
+        .try_into()
+        .map_err(|_| "Ed25519 public keys are 32 bytes".to_string())?;
+    VerifyingKey::from_bytes(bytes).map_err(|e| format!("Invalid Ed25519 public key: {}", e))
+}
+
+/// The signature of a message along with the bytes it signs, `None` if the message isn't signed.
+fn signed_content(msg: &Message) -> Result<Option<(String, Vec<u8>)>, String> {
+    if let Some(signature) = msg.properties().find_user_property(SIGNATURE_PROPERTY) {
+        return Ok(Some((signature, msg.payload().to_vec())));
+    }
+
+    let Ok(Value::Object(mut payload)) = serde_json::from_slice::<Value>(msg.payload()) else {
+        return Ok(None);
+    };
+    match payload.remove(SIGNATURE_PROPERTY) {
+        Some(Value::String(signature)) => {
+            let mut content = Vec::new();
+            canonical_json(&Value::Object(payload), &mut content).map_err(|e| e.to_string())?;
+            Ok(Some((signature, content)))
+        }
+        Some(_) => Err("Signature field isn't a string".to_string()),
+        None => Ok(None),
+    }
+}
+
+/// Write the canonical form of a JSON value that devices sign, see the module documentation. The
+/// keys are sorted here rather than relying on the order of [`serde_json::Map`], which depends on
+/// the features serde_json is built with.
+fn canonical_json(value: &Value, out: &mut Vec<u8>) -> serde_json::Result<()> {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_unstable_by(|(a, _), (b, _)| a.as_bytes().cmp(b.as_bytes()));
+
+            out.push(b'{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                serde_json::to_writer(&mut *out, key)?;
+                out.push(b':');
+                canonical_json(value, out)?;
+            }
+            out.push(b'}');
+        }
+        Value::Array(values) => {
+            out.push(b'[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                canonical_json(value, out)?;
+            }
+            out.push(b']');
+        }
+        // Scalars have a single serialization, numbers as described in the module documentation
+        scalar => serde_json::to_writer(&mut *out, scalar)?,
+    }
+    Ok(())
+}
+
+/// Verify the signature of a message of a device against the key of the device.
+pub fn verify(key: &DeviceKey, msg: &Message) -> Result<(), SignatureError> {
+    let invalid = |reason: String| SignatureError::Invalid(key.device_id.clone(), reason);
+
+    let (signature, content) = signed_content(msg)
+        .map_err(invalid)?
+        .ok_or_else(|| SignatureError::Unsigned(key.device_id.clone()))?;
+    let signature =
+        hex::decode(signature).map_err(|_| invalid("Signature isn't hex encoded".to_string()))?;
+
+    match key.algorithm {
+        SignatureAlgorithm::HmacSha256 => {
+            let mut mac = Hmac::<Sha256>::new_from_slice(&key.key)
+                .map_err(|e| invalid(format!("Invalid HMAC key: {}", e)))?;
+            mac.update(&content);
+            // Compares in constant time
+            mac.verify_slice(&signature)
+                .map_err(|_| invalid("Signature doesn't match".to_string()))
+        }
+        SignatureAlgorithm::Ed25519 => {
+            let public_key = ed25519_key(&key.key).map_err(invalid)?;
+            let signature = Signature::from_slice(&signature)
+                .map_err(|_| invalid("Ed25519 signatures are 64 bytes".to_string()))?;
+            public_key
+                .verify_strict(&content, &signature)
+                .map_err(|_| invalid("Signature doesn't match".to_string()))
+        }
+    }
+}
+
+/// Verifies the messages of devices before they are stored, and records the ones that fail.
+pub struct Verifier {
+    repo: Arc<Mutex<RemRepo>>,
+    require_signatures: bool,
+}
+
+impl Verifier {
+    pub fn new(repo: Arc<Mutex<RemRepo>>, require_signatures: bool) -> Self {
+        Verifier {
+            repo,
+            require_signatures,
+        }
+    }
+
+    /// Check the signature of a message of a device, and that a message that may not be replayed
+    /// is newer than the last one of the device. A message that fails is recorded as rejected, and
+    /// [`MQTTClientError::Signature`] is returned so it isn't stored.
+    pub async fn check(
+        &self,
+        tenant: Option<&str>,
+        device_id: &str,
+        msg: &Message,
+        replay: Replay,
+    ) -> Result<(), MQTTClientError> {
+        let key = self.repo.lock().await.find_device_key(device_id).await?;
+        let res = match key {
+            Some(key) => match (verify(&key, msg), replay) {
+                (Ok(()), Replay::Protected(sent_at)) => self.fresh(device_id, sent_at).await?,
+                (res, _) => res,
+            },
+            None if self.require_signatures => Err(SignatureError::Unsigned(device_id.to_string())),
+            None => Ok(()),
+        };
+
+        let Err(err) = res else {
+            return Ok(());
+        };
+        warn!("Rejecting message on '{}': {}", msg.topic(), err);
+
+        let recorded = self
+            .repo
+            .lock()
+            .await
+            .insert_rejected_message(
+                tenant,
+                device_id,
+                msg.topic(),
+                err.reason(),
+                &err.to_string(),
+                msg.payload(),
+            )
+            .await;
+        if let Err(e) = recorded {
+            error!("Failed to record rejected message: {}", e);
+        }
+
+        Err(MQTTClientError::Signature(err))
+    }
+
+    /// Check that a signed message sent at `sent_at` isn't older than the last one of the device,
+    /// and record it as the last one.
+    async fn fresh(
+        &self,
+        device_id: &str,
+        sent_at: Option<DateTime<Utc>>,
+    ) -> Result<Result<(), SignatureError>, MQTTClientError> {
+        fresh(device_id, sent_at, Utc::now(), |sent_at| async move {
+            let repo = self.repo.lock().await;
+            Ok(repo.advance_device_sent_at(device_id, sent_at).await?)
+        })
+        .await
+    }
+}
+
+/// Check that a message sent at `sent_at` isn't ahead of `now` by more than the clock skew, and
+/// `advance` the last time of the device to it. `advance` returns false when the device already
+/// sent a later message, which makes this one a replay.
+async fn fresh<F, Fut>(
+    device_id: &str,
+    sent_at: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+    advance: F,
+) -> Result<Result<(), SignatureError>, MQTTClientError>
+where
+    F: FnOnce(NaiveDateTime) -> Fut,
+    Fut: Future<Output = Result<bool, MQTTClientError>>,
+{
+    let replayed = |reason: &str| {
+        Ok(Err(SignatureError::Replayed(
+            device_id.to_string(),
+            reason.to_string(),
+        )))
+    };
+
+    let Some(sent_at) = sent_at else {
+        return replayed("Message doesn't say when it was sent");
+    };
+    if sent_at > now + MAX_CLOCK_SKEW {
+        return replayed("Message was sent in the future");
+    }
+
+    match advance(sent_at.naive_utc()).await? {
+        true => Ok(Ok(())),
+        false => replayed("Message is older than the last one of the device"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Signer, SigningKey};
+    use paho_mqtt::{MessageBuilder, Properties, PropertyCode};
+    use serde_json::json;
+
+    use super::*;
+
+    const HMAC_KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+    const ED25519_SEED: [u8; 32] = [7; 32];
+
+    fn device_key(algorithm: SignatureAlgorithm, key: &[u8]) -> DeviceKey {
+        DeviceKey {
+            device_id: "rem-1".to_string(),
+            algorithm,
+            key: key.to_vec(),
+            created_at: NaiveDateTime::default(),
+        }
+    }
+
+    fn hmac_key() -> DeviceKey {
+        device_key(SignatureAlgorithm::HmacSha256, HMAC_KEY)
+    }
+
+    fn ed25519_key() -> DeviceKey {
+        let public_key = SigningKey::from_bytes(&ED25519_SEED).verifying_key();
+        device_key(SignatureAlgorithm::Ed25519, public_key.as_bytes())
+    }
+
+    fn hmac_sign(key: &[u8], content: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC key");
+        mac.update(content);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn ed25519_sign(content: &[u8]) -> String {
+        hex::encode(
+            SigningKey::from_bytes(&ED25519_SEED)
+                .sign(content)
+                .to_bytes(),
+        )
+    }
+
+    /// A message signed with the user property
+    fn property_signed(payload: &str, signature: &str) -> Message {
+        let mut props = Properties::new();
+        props
+            .push_string_pair(PropertyCode::UserProperty, SIGNATURE_PROPERTY, signature)
+            .expect("user property");
+        MessageBuilder::new()
+            .topic("rem/data")
+            .payload(payload)
+            .properties(props)
+            .finalize()
+    }
+
+    /// A message signed with the payload field, the signature is computed over `payload`
+    fn payload_signed(payload: &Value, sign: impl Fn(&[u8]) -> String) -> Message {
+        let mut content = Vec::new();
+        canonical_json(payload, &mut content).expect("canonical JSON");
+        let mut signed = payload.clone();
+        signed[SIGNATURE_PROPERTY] = Value::String(sign(&content));
+        MessageBuilder::new()
+            .topic("rem/data")
+            .payload(signed.to_string())
+            .finalize()
+    }
+
+    fn canonical(value: &Value) -> String {
+        let mut out = Vec::new();
+        canonical_json(value, &mut out).expect("canonical JSON");
+        String::from_utf8(out).expect("UTF-8")
+    }
+
+    fn is_invalid(res: Result<(), SignatureError>) -> bool {
+        matches!(res, Err(SignatureError::Invalid(_, _)))
+    }
+
+    #[test]
+    fn verifies_hmac_signatures() {
+        let payload = r#"{"deviceId": "rem-1", "temperature": 21.5}"#;
+        let msg = property_signed(payload, &hmac_sign(HMAC_KEY, payload.as_bytes()));
+        assert!(verify(&hmac_key(), &msg).is_ok());
+
+        let tampered = property_signed(
+            r#"{"deviceId": "rem-1", "temperature": 31.5}"#,
+            &hmac_sign(HMAC_KEY, payload.as_bytes()),
+        );
+        assert!(is_invalid(verify(&hmac_key(), &tampered)));
+
+        let other_key = b"fedcba9876543210fedcba9876543210";
+        let msg = property_signed(payload, &hmac_sign(other_key, payload.as_bytes()));
+        assert!(is_invalid(verify(&hmac_key(), &msg)));
+    }
+
+    #[test]
+    fn verifies_ed25519_signatures() {
+        let payload = r#"{"deviceId": "rem-1", "temperature": 21.5}"#;
+        let msg = property_signed(payload, &ed25519_sign(payload.as_bytes()));
+        assert!(verify(&ed25519_key(), &msg).is_ok());
+
+        let tampered = property_signed(
+            r#"{"deviceId": "rem-1", "temperature": 31.5}"#,
+            &ed25519_sign(payload.as_bytes()),
+        );
+        assert!(is_invalid(verify(&ed25519_key(), &tampered)));
+
+        let other_key = SigningKey::from_bytes(&[8; 32]).verifying_key();
+        let other_key = device_key(SignatureAlgorithm::Ed25519, other_key.as_bytes());
+        assert!(is_invalid(verify(&other_key, &msg)));
+    }
+
+    #[test]
+    fn rejects_unsigned_and_malformed_signatures() {
+        let msg = MessageBuilder::new()
+            .topic("rem/data")
+            .payload(r#"{"deviceId": "rem-1"}"#)
+            .finalize();
+        assert!(matches!(
+            verify(&hmac_key(), &msg),
+            Err(SignatureError::Unsigned(_))
+        ));
+
+        let msg = property_signed(r#"{"deviceId": "rem-1"}"#, "not hex");
+        assert!(is_invalid(verify(&hmac_key(), &msg)));
+
+        let msg = MessageBuilder::new()
+            .topic("rem/data")
+            .payload(r#"{"deviceId": "rem-1", "signature": 5}"#)
+            .finalize();
+        assert!(is_invalid(verify(&hmac_key(), &msg)));
+    }
+
+    #[test]
+    fn property_signatures_sign_the_payload_as_published() {
+        // Any whitespace or key order is signed as is
+        let payload = "{ \"temperature\": 21.50,\n  \"deviceId\": \"rem-1\" }";
+        let msg = property_signed(payload, &hmac_sign(HMAC_KEY, payload.as_bytes()));
+        assert!(verify(&hmac_key(), &msg).is_ok());
+
+        // So a signature of the canonical form doesn't match it
+        let canonical = canonical(&serde_json::from_str(payload).expect("JSON"));
+        let msg = property_signed(payload, &hmac_sign(HMAC_KEY, canonical.as_bytes()));
+        assert!(is_invalid(verify(&hmac_key(), &msg)));
+    }
+
+    #[test]
+    fn payload_signatures_sign_the_canonical_payload() {
+        let payload = json!({"deviceId": "rem-1", "temperature": 21.5, "tags": ["a", "b"]});
+        let msg = payload_signed(&payload, |content| hmac_sign(HMAC_KEY, content));
+        assert!(verify(&hmac_key(), &msg).is_ok());
+        let msg = payload_signed(&payload, ed25519_sign);
+        assert!(verify(&ed25519_key(), &msg).is_ok());
+
+        // The field can be anywhere in a payload formatted any way
+        let signature = hmac_sign(HMAC_KEY, canonical(&payload).as_bytes());
+        let reformatted = format!(
+            "{{\"tags\": [\"a\", \"b\"], \"signature\": \"{}\", \"temperature\": 21.50, \"deviceId\": \"rem-1\"}}",
+            signature
+        );
+        let msg = MessageBuilder::new()
+            .topic("rem/data")
+            .payload(reformatted)
+            .finalize();
+        assert!(verify(&hmac_key(), &msg).is_ok());
+
+        let mut tampered: Value = serde_json::from_slice(
+            payload_signed(&payload, |content| hmac_sign(HMAC_KEY, content)).payload(),
+        )
+        .expect("JSON");
+        tampered["temperature"] = json!(31.5);
+        let msg = MessageBuilder::new()
+            .topic("rem/data")
+            .payload(tampered.to_string())
+            .finalize();
+        assert!(is_invalid(verify(&hmac_key(), &msg)));
+    }
+
+    #[test]
+    fn canonical_json_sorts_keys() {
+        let a: Value =
+            serde_json::from_str(r#"{"b": 1, "a": {"d": [1, {"z": 0, "y": 0}], "c": "x"}}"#)
+                .expect("JSON");
+        let b: Value =
+            serde_json::from_str(r#"{"a": {"c": "x", "d": [1, {"y": 0, "z": 0}]}, "b": 1}"#)
+                .expect("JSON");
+        assert_eq!(
+            canonical(&a),
+            r#"{"a":{"c":"x","d":[1,{"y":0,"z":0}]},"b":1}"#
+        );
+        assert_eq!(canonical(&a), canonical(&b));
+
+        // By UTF-8 bytes, so uppercase sorts before lowercase
+        let value = json!({"b": 1, "B": 2, "é": 3, "a": 4});
+        assert_eq!(canonical(&value), r#"{"B":2,"a":4,"b":1,"é":3}"#);
+    }
+
+    #[test]
+    fn canonical_json_formats_numbers_and_strings() {
+        let canonical_str = |json: &str| canonical(&serde_json::from_str(json).expect("JSON"));
+        assert_eq!(canonical_str("21.50"), "21.5");
+        assert_eq!(canonical_str("1E2"), "100.0");
+        assert_eq!(canonical_str("0.0000001"), "1e-7");
+        assert_eq!(canonical_str("0.00001"), "0.00001");
+        assert_eq!(canonical_str("0.000001"), "1e-6");
+        assert_eq!(canonical_str("0.0001"), "0.0001");
+        assert_eq!(canonical_str("1e16"), "1e+16");
+        assert_eq!(canonical_str("1e15"), "1000000000000000.0");
+        assert_eq!(canonical_str("-5"), "-5");
+        assert_eq!(
+            canonical_str("12345678901234567890"),
+            "12345678901234567890"
+        );
+        assert_eq!(
+            canonical_str(r#""line\nbreak \"quoted\" \u00e9 \u0001""#),
+            "\"line\\nbreak \\\"quoted\\\" é \\u0001\""
+        );
+    }
+
+    #[tokio::test]
+    async fn fresh_accepts_messages_after_the_last_one() {
+        let now = Utc::now();
+        let last = (now - TimeDelta::minutes(1)).naive_utc();
+        let advance = |sent_at| async move { Ok(sent_at >= last) };
+
+        let res = fresh("rem-1", Some(now), now, advance)
+            .await
+            .expect("fresh");
+        assert!(res.is_ok());
+
+        // Within the allowed clock skew of the device
+        let ahead = now + MAX_CLOCK_SKEW - TimeDelta::seconds(1);
+        let res = fresh("rem-1", Some(ahead), now, advance)
+            .await
+            .expect("fresh");
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test]
+    async fn fresh_rejects_stale_future_and_missing_times() {
+        let now = Utc::now();
+        let last = (now - TimeDelta::minutes(1)).naive_utc();
+        let advance = |sent_at| async move { Ok(sent_at >= last) };
+        let replayed =
+            |res: Result<(), SignatureError>| matches!(res, Err(SignatureError::Replayed(_, _)));
+
+        let stale = now - TimeDelta::minutes(2);
+        let res = fresh("rem-1", Some(stale), now, advance)
+            .await
+            .expect("fresh");
+        assert!(replayed(res));
+
+        let future = now + MAX_CLOCK_SKEW + TimeDelta::seconds(1);
+        let res = fresh("rem-1", Some(future), now, advance)
+            .await
+            .expect("fresh");
+        assert!(replayed(res));
+
+        let res = fresh("rem-1", None, now, advance).await.expect("fresh");
+        assert!(replayed(res));
+    }
+}
@@ -0,0 +1,199 @@
+//! Logging and tracing.
+//!
+//! Logs are written to stdout as text, or as one JSON object per line for log collectors, filtered
+//! with `RUST_LOG` as before. When `OTEL_EXPORTER_OTLP_ENDPOINT` is set the spans are also exported
+//! to an OpenTelemetry collector over OTLP/HTTP. The exporter reads the endpoint and the other
+//! standard `OTEL_EXPORTER_OTLP_*` variables, like the headers, itself.
+//!
+//! Every MQTT message and HTTP request gets a span, the handlers and the repository add theirs
+//! below it. A message or request with a W3C `traceparent` user property or header continues the
+//! trace of whatever sent it. Every request also gets an ID, taken from its `X-Request-Id` header
+//! when the client sent a sensible one, that is logged with its span and returned in the response
+//! and in error bodies.
+use std::collections::HashMap;
+
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{HeaderMap, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use opentelemetry::{global, propagation::Extractor, trace::TracerProvider, KeyValue};
+use opentelemetry_otlp::{ExporterBuildError, SpanExporter};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource};
+use paho_mqtt::Message;
+use tokio::task_local;
+use tracing::{error, field::Empty, info_span, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+
+use crate::settings::{LogFormat, Settings};
+
+/// Name of the W3C trace context header and user property
+const TRACEPARENT: &str = "traceparent";
+
+/// Header a request ID is read from and returned in
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest request ID accepted from a client
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+task_local! {
+    /// ID of the request being handled
+    static REQUEST_ID: String;
+}
+
+/// The tracing setup of the process, shut it down before exiting so the last spans are exported.
+pub struct Telemetry {
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Telemetry {
+    /// Install the global subscriber, and the OTLP exporter if it is configured.
+    pub fn init(config: &Settings) -> Result<Self, ExporterBuildError> {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let provider = match config.otel_exporter_otlp_endpoint {
+            Some(_) => {
+                let exporter = SpanExporter::builder().with_http().build()?;
+                let resource = Resource::builder()
+                    .with_service_name(config.otel_service_name.clone())
+                    .with_attribute(KeyValue::new("service.version", env!("CARGO_PKG_VERSION")))
+                    .build();
+                Some(
+                    SdkTracerProvider::builder()
+                        .with_batch_exporter(exporter)
+                        .with_resource(resource)
+                        .build(),
+                )
+            }
+            None => None,
+        };
+        let otel = provider.as_ref().map(|provider| {
+            tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+        });
+
+        let (text, json) = match config.log_format {
+            LogFormat::Text => (Some(fmt::layer()), None),
+            LogFormat::Json => (None, Some(fmt::layer().json())),
+        };
+
+        tracing_subscriber::registry()
+            .with(text)
+            .with(json)
+            .with(otel)
+            .with(EnvFilter::from_default_env())
+            .init();
+
+        Ok(Telemetry { provider })
+    }
+
+    /// Export the spans that are still buffered.
+    pub fn shutdown(&self) {
+        if let Some(provider) = &self.provider {
+            if let Err(e) = provider.shutdown() {
+                error!("Failed to shut down the OTLP exporter: {}", e);
+            }
+        }
+    }
+}
+
+/// Reads the trace context from the headers of an HTTP request.
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Make `span` a child of the trace context in `carrier`, if there is one. Without it the span
+/// stays where it is, below the current span or as the root of a new trace.
+fn continue_trace(span: &Span, carrier: &dyn Extractor) {
+    if carrier.get(TRACEPARENT).is_none() {
+        return;
+    }
+    let cx = global::get_text_map_propagator(|propagator| propagator.extract(carrier));
+    // This only fails when the spans aren't exported
+    let _ = span.set_parent(cx);
+}
+
+/// Span that a received message is handled in. The handler records the device and message ID
+/// once the message is decoded.
+pub fn message_span(msg: &Message) -> Span {
+    let span = info_span!(
+        "handle_message",
+        otel.kind = "consumer",
+        messaging.destination.name = msg.topic(),
+        device_id = Empty,
+        message_id = Empty,
+        outcome = Empty,
+    );
+    let carrier: HashMap<String, String> = msg.properties().user_iter().collect();
+    continue_trace(&span, &carrier);
+    span
+}
+
+/// The route template a request matched, `unmatched` if it went to the fallback.
+pub fn matched_route(req: &Request) -> String {
+    req.extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string())
+}
+
+/// ID of the API request being handled, if any.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// The request ID the client sent, unless it is too long or has characters that would mess up
+/// the logs.
+fn client_request_id(headers: &HeaderMap) -> Option<String> {
+    let id = headers.get(REQUEST_ID_HEADER)?.to_str().ok()?;
+    let valid = !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c));
+    valid.then(|| id.to_string())
+}
+
+/// Record the device an API request is about on the span of the request, like the handlers of
+/// messages do once they are decoded.
+pub fn record_device(device_id: &str) {
+    Span::current().record("device_id", device_id);
+}
+
+/// Middleware that handles every API request in a span named after its route.
+pub async fn trace_http(req: Request, next: Next) -> Response {
+    let method = req.method().to_string();
+    let route = matched_route(&req);
+    let request_id = client_request_id(req.headers())
+        .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string());
+    let span = info_span!(
+        "http_request",
+        otel.name = format!("{} {}", method, route),
+        otel.kind = "server",
+        http.request.method = method,
+        http.route = route,
+        http.response.status_code = Empty,
+        request_id = request_id,
+        device_id = Empty,
+    );
+    continue_trace(&span, &HeaderExtractor(req.headers()));
+
+    let mut response = REQUEST_ID
+        .scope(request_id.clone(), next.run(req))
+        .instrument(span.clone())
+        .await;
+    span.record("http.response.status_code", response.status().as_u16());
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
@@ -1,5 +1,3 @@
-use paho_mqtt::QOS_1;
-
 /// The topic we are subscribing to, to get REM data. This includes telemetry data
 /// for the REM devices like temperature, humidity, VOC index, PPM measurements for different
 /// sizes of particles, etc.
@@ -9,12 +7,178 @@ pub const REM_DATA_TOPIC: &str = "rem/data";
 /// status data like a heartbeat.
 pub const REM_STATUS_TOPIC: &str = "rem/status";
 
-/// Topic that the MQTT listener in this project sends a disconnect message too.
-pub const REM_LISTENER_DISCONNECT_TOPIC: &str = "rem/lwt";
+/// Topic that the MQTT listener in this project publishes its retained status to. An online
+/// message is sent on every (re)connect, and an offline message on shutdown or as the last will
+/// when the connection drops unexpectedly.
+pub const REM_LISTENER_STATUS_TOPIC: &str = "rem/lwt";
+
+/// The topic a listener replica publishes its own retained status to when the replicas share their
+/// subscriptions, see [`REM_LISTENER_STATUS_TOPIC`]. Devices subscribe to `rem/lwt/+` and consider
+/// the listener online as long as one of the replicas is.
+pub fn replica_status_topic(client_id: &str) -> String {
+    format!("{}/{}", REM_LISTENER_STATUS_TOPIC, client_id)
+}
+
+/// Topic filter of the acknowledgements the REM devices publish for their commands, see
+/// [`command_response_topic`].
+pub const REM_COMMAND_RESPONSE_TOPIC: &str = "rem/+/cmd/response";
+
+/// The topic a REM device receives its commands on.
+pub fn command_topic(device_id: &str) -> String {
+    format!("rem/{}/cmd", device_id)
+}
+
+/// The topic a REM device publishes the acknowledgements of its commands to. This is passed to the
+/// device as the MQTT v5 response topic of every command.
+pub fn command_response_topic(device_id: &str) -> String {
+    format!("rem/{}/cmd/response", device_id)
+}
+
+/// The topic the desired configuration of a REM device is retained on. Devices subscribe to it, so
+/// they receive the latest configuration whenever they connect.
+pub fn config_topic(device_id: &str) -> String {
+    format!("rem/{}/config", device_id)
+}
+
+/// Topic filter of the firmware update progress the REM devices report, see [`ota_topic`].
+pub const REM_OTA_PROGRESS_TOPIC: &str = "rem/+/ota/progress";
+
+/// The topic a REM device is notified of a firmware update on. The notification is retained, so
+/// devices that are offline get it when they connect.
+pub fn ota_topic(device_id: &str) -> String {
+    format!("rem/{}/ota", device_id)
+}
+
+/// A topic for a tenant, prefixed with the tenant ID if there is one.
+pub fn tenant_topic(tenant: Option<&str>, topic: &str) -> String {
+    match tenant {
+        Some(tenant) => format!("{}/{}", tenant, topic),
+        None => topic.to_string(),
+    }
+}
+
+/// The device ID in a topic of the form `rem/{device_id}/...`.
+pub fn device_id_from_topic(topic: &str) -> Option<&str> {
+    let mut levels = topic.split('/');
+    match (levels.next(), levels.next()) {
+        (Some("rem"), Some(device_id)) if !device_id.is_empty() => Some(device_id),
+        _ => None,
+    }
+}
+
+/// Check if a value can be used as a single topic level, it can't be empty or contain a level
+/// separator or wildcard.
+pub fn is_valid_topic_level(level: &str) -> bool {
+    !level.is_empty() && !level.contains(['/', '+', '#'])
+}
+
+/// Prefix of MQTT v5 shared subscriptions, `$share/<group>/<topic filter>`
+pub const SHARED_SUBSCRIPTION_PREFIX: &str = "$share";
+
+/// The topic filter of a shared subscription, for the given group and topic filter.
+pub fn shared_subscription(group: &str, filter: &str) -> String {
+    format!("{}/{}/{}", SHARED_SUBSCRIPTION_PREFIX, group, filter)
+}
+
+/// Check if a topic matches an MQTT topic filter. The filter may contain the single level `+`
+/// wildcard and a trailing multi level `#` wildcard, and may be a shared subscription. Like the
+/// MQTT server does, wildcards in the first level don't match topics starting with `$`.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let filter = filter
+        .strip_prefix(SHARED_SUBSCRIPTION_PREFIX)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .and_then(|rest| rest.split_once('/'))
+        .map_or(filter, |(_group, filter)| filter);
+    if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => continue,
+            (Some(f), Some(t)) if f == t => continue,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_exact_topics() {
+        assert!(topic_matches(REM_DATA_TOPIC, "rem/data"));
+        assert!(!topic_matches(REM_DATA_TOPIC, "rem/status"));
+        assert!(!topic_matches(REM_DATA_TOPIC, "rem/data/extra"));
+        assert!(!topic_matches(REM_DATA_TOPIC, "rem"));
+    }
+
+    #[test]
+    fn matches_single_level_wildcards() {
+        assert!(topic_matches(
+            REM_COMMAND_RESPONSE_TOPIC,
+            "rem/dev-1/cmd/response"
+        ));
+        assert!(topic_matches(
+            REM_OTA_PROGRESS_TOPIC,
+            "rem/dev-1/ota/progress"
+        ));
+        assert!(topic_matches("rem/+/cmd/response", "rem//cmd/response"));
+        assert!(!topic_matches(REM_COMMAND_RESPONSE_TOPIC, "rem/dev-1/cmd"));
+        assert!(!topic_matches(
+            REM_COMMAND_RESPONSE_TOPIC,
+            "rem/a/b/cmd/response"
+        ));
+        assert!(!topic_matches(REM_OTA_PROGRESS_TOPIC, "rem/dev-1/ota"));
+    }
+
+    #[test]
+    fn matches_multi_level_wildcards() {
+        assert!(topic_matches("rem/#", "rem/data"));
+        assert!(topic_matches("rem/#", "rem/dev-1/cmd/response"));
+        // `#` also matches the parent level
+        assert!(topic_matches("rem/#", "rem"));
+        assert!(topic_matches("#", "rem/data"));
+        assert!(!topic_matches("rem/#", "other/data"));
+    }
+
+    #[test]
+    fn wildcards_do_not_match_system_topics() {
+        assert!(!topic_matches("#", "$SYS/broker/uptime"));
+        assert!(!topic_matches("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(topic_matches("$SYS/#", "$SYS/broker/uptime"));
+    }
+
+    #[test]
+    fn matches_shared_subscriptions() {
+        let filter = shared_subscription("listeners", REM_DATA_TOPIC);
+        assert_eq!(filter, "$share/listeners/rem/data");
+        assert!(topic_matches(&filter, "rem/data"));
+        assert!(!topic_matches(&filter, "rem/status"));
+
+        let filter = shared_subscription("listeners", "+/rem/+/cmd/response");
+        assert!(topic_matches(&filter, "acme/rem/dev-1/cmd/response"));
+        assert!(!topic_matches(&filter, "rem/dev-1/cmd/response"));
+    }
 
-/// Topics that we are going to subscribe too as a listeners.
-pub const SUBSCRIBED_TOPICS: &[&str] = &["rem/data", "rem/status"];
+    #[test]
+    fn matches_tenant_topics() {
+        let filter = format!("+/{}", REM_STATUS_TOPIC);
+        assert!(topic_matches(
+            &filter,
+            &tenant_topic(Some("acme"), REM_STATUS_TOPIC)
+        ));
+        assert!(!topic_matches(&filter, REM_STATUS_TOPIC));
+        assert!(!topic_matches(REM_STATUS_TOPIC, "acme/rem/status"));
 
-/// QOS values for the subscribed topics, note that SUBSCRIBED_TOPICS and QOS
-/// need to be the same length value.
-pub const QOS: &[i32] = &[QOS_1, QOS_1];
+        let filter = format!("+/{}", REM_OTA_PROGRESS_TOPIC);
+        assert!(topic_matches(&filter, "acme/rem/dev-1/ota/progress"));
+        assert!(!topic_matches(&filter, "acme/other/dev-1/ota/progress"));
+    }
+}